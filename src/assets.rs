@@ -0,0 +1,157 @@
+use super::*;
+use sha2::{Digest, Sha256};
+
+/// A content addressed reference to a generated image or audio clip.
+/// Unlike DALL-E urls or object urls it stays valid, so it's what goes into payloads.
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct AssetRef{
+    /// Hex encoded sha256 of the asset bytes
+    pub id:String,
+    pub mime:String,
+    pub size:usize,
+}
+impl AssetRef{
+  /// The stable reference written into payloads, e.g. `asset:sha256:9f86d0...`
+  pub fn uri(&self) -> String {
+    format!("asset:sha256:{}",self.id)
+  }
+  pub fn extension(&self) -> &'static str {
+    match self.mime.as_str() {
+      "image/png" => "png",
+      "image/jpeg" => "jpg",
+      "image/webp" => "webp",
+      "audio/mpeg" => "mp3",
+      "audio/ogg" => "ogg",
+      "audio/aac" => "aac",
+      "audio/flac" => "flac",
+      _ => "bin",
+    }
+  }
+}
+
+pub fn asset_id(bytes:&[u8]) -> String {
+  Sha256::digest(bytes).iter().map(|b| format!("{:02x}",b)).collect()
+}
+
+/// Hashes and stores `bytes`, storing the same content twice is a no-op.
+pub async fn store_asset(bytes:Bytes, mime:&str) -> Result<AssetRef,String> {
+  let asset = AssetRef{
+    id:asset_id(&bytes),
+    mime:mime.to_string(),
+    size:bytes.len(),
+  };
+  backend::put(&asset.id,&bytes).await?;
+  Ok(asset)
+}
+
+pub async fn load_asset(id:&str) -> Result<Option<Bytes>,String> {
+  backend::get(id).await
+}
+
+/// Downloads a short lived url, e.g. a DALL-E result, into the store.
+pub async fn download_asset(url:&str) -> Result<AssetRef,String> {
+  let resp = reqwest::get(url).await.map_err(|e| e.to_string())?;
+  let mime = resp.headers()
+    .get("Content-Type")
+    .and_then(|v| v.to_str().ok())
+    .unwrap_or("application/octet-stream")
+    .to_string();
+  let bytes = resp.bytes().await.map_err(|e| e.to_string())?;
+  store_asset(bytes,&mime).await
+}
+
+/// The IndexedDB database of the web build, shared by the asset store and the response cache.
+#[cfg(target_arch = "wasm32")]
+pub async fn open_db() -> Result<rexie::Rexie,String> {
+  rexie::Rexie::builder("craptent")
+    .version(2)
+    .add_object_store(rexie::ObjectStore::new("assets"))
+    .add_object_store(rexie::ObjectStore::new("responses"))
+    .build()
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/// In the browser assets live in IndexedDB.
+#[cfg(target_arch = "wasm32")]
+mod backend {
+  use bytes::Bytes;
+  use rexie::TransactionMode;
+  use super::open_db;
+
+  const STORE:&str = "assets";
+
+  pub async fn put(id:&str, bytes:&[u8]) -> Result<(),String> {
+    let db = open_db().await?;
+    let tx = db.transaction(&[STORE],TransactionMode::ReadWrite).map_err(|e| e.to_string())?;
+    let store = tx.store(STORE).map_err(|e| e.to_string())?;
+    store.put(&js_sys::Uint8Array::from(bytes).into(),Some(&id.into())).await.map_err(|e| e.to_string())?;
+    tx.done().await.map_err(|e| e.to_string())?;
+    Ok(())
+  }
+
+  pub async fn get(id:&str) -> Result<Option<Bytes>,String> {
+    let db = open_db().await?;
+    let tx = db.transaction(&[STORE],TransactionMode::ReadOnly).map_err(|e| e.to_string())?;
+    let store = tx.store(STORE).map_err(|e| e.to_string())?;
+    let value = store.get(&id.into()).await.map_err(|e| e.to_string())?;
+    if value.is_undefined() {
+      return Ok(None);
+    }
+    Ok(Some(Bytes::from(js_sys::Uint8Array::new(&value).to_vec())))
+  }
+}
+
+/// Natively assets are written to `./assets/<sha256>`.
+#[cfg(not(target_arch = "wasm32"))]
+mod backend {
+  use bytes::Bytes;
+  use std::path::PathBuf;
+
+  fn path(id:&str) -> PathBuf {
+    PathBuf::from("assets").join(id)
+  }
+
+  pub async fn put(id:&str, bytes:&[u8]) -> Result<(),String> {
+    let path = path(id);
+    if path.exists() {
+      return Ok(());
+    }
+    std::fs::create_dir_all("assets").map_err(|e| e.to_string())?;
+    std::fs::write(path,bytes).map_err(|e| e.to_string())
+  }
+
+  pub async fn get(id:&str) -> Result<Option<Bytes>,String> {
+    match std::fs::read(path(id)) {
+      Ok(bytes) => Ok(Some(Bytes::from(bytes))),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e.to_string()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn stored_assets_load_back_by_their_hash() {
+    let bytes = Bytes::from_static(b"round trip of the native asset store");
+    let asset = block_on(store_asset(bytes.clone(),"audio/mpeg")).unwrap();
+    assert_eq!(asset.id,asset_id(&bytes));
+    assert_eq!((asset.size,asset.extension()),(bytes.len(),"mp3"));
+    assert_eq!(block_on(load_asset(&asset.id)).unwrap(),Some(bytes));
+    std::fs::remove_file(std::path::Path::new("assets").join(&asset.id)).unwrap();
+    assert_eq!(block_on(load_asset(&asset.id)).unwrap(),None);
+  }
+
+  #[test]
+  fn the_same_bytes_are_stored_once() {
+    let bytes = Bytes::from_static(b"stored twice by the native asset store");
+    let first = block_on(store_asset(bytes.clone(),"image/png")).unwrap();
+    let second = block_on(store_asset(bytes,"image/png")).unwrap();
+    assert_eq!(first,second);
+    assert_eq!(first.uri(),format!("asset:sha256:{}",first.id));
+    std::fs::remove_file(std::path::Path::new("assets").join(&first.id)).unwrap();
+  }
+}
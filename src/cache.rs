@@ -0,0 +1,174 @@
+use super::*;
+use sha2::{Digest, Sha256};
+
+/// Whether provider responses are reused when a request is repeated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheSettings{
+    pub enabled:bool,
+    /// Entries older than this are ignored and overwritten
+    pub ttl_hours:f64,
+    /// Skip lookups but keep writing, every call goes to the provider and replaces what was cached
+    pub refresh:bool,
+}
+impl Default for CacheSettings{
+  fn default() -> Self {
+    Self{
+      enabled:false,
+      ttl_hours:24. * 7.,
+      refresh:false,
+    }
+  }
+}
+
+/// Hex encoded sha256 of the provider and the request with its object keys sorted,
+/// so the same parameters hit the same entry whatever order they were set in.
+pub fn cache_key(provider:&str, request:&serde_json::Value) -> String {
+  let normalized = format!("{}\n{}",provider,normalize(request));
+  Sha256::digest(normalized.as_bytes()).iter().map(|b| format!("{:02x}",b)).collect()
+}
+
+fn normalize(value:&serde_json::Value) -> serde_json::Value {
+  match value {
+    serde_json::Value::Object(map) => {
+      let mut entries : Vec<(&String,&serde_json::Value)> = map.iter().collect();
+      entries.sort_by(|a,b| a.0.cmp(b.0));
+      serde_json::Value::Object(entries.into_iter().map(|(k,v)| (k.clone(),normalize(v))).collect())
+    },
+    serde_json::Value::Array(values) => serde_json::Value::Array(values.iter().map(normalize).collect()),
+    value => value.clone(),
+  }
+}
+
+/// The cached response for `key`, if caching is on and it hasn't expired.
+pub async fn cache_get(settings:&CacheSettings, key:&str) -> Option<Bytes> {
+  if !settings.enabled || settings.refresh {
+    return None;
+  }
+  let (created,body) = match backend::get(key).await {
+    Ok(entry) => entry?,
+    Err(err) => {
+      log::error!("couldn't read the response cache: {}",err);
+      return None;
+    },
+  };
+  let age_hours = (chrono::Utc::now().timestamp_millis() - created) as f64 / 3_600_000.;
+  (age_hours <= settings.ttl_hours).then_some(body)
+}
+
+pub async fn cache_put(settings:&CacheSettings, key:&str, body:&[u8]) {
+  if !settings.enabled {
+    return;
+  }
+  if let Err(err) = backend::put(key,chrono::Utc::now().timestamp_millis(),body).await {
+    log::error!("couldn't write the response cache: {}",err);
+  }
+}
+
+pub async fn cache_clear() -> Result<(),String> {
+  backend::clear().await
+}
+
+/// In the browser responses live in IndexedDB next to the assets, as `{created, body}` objects.
+#[cfg(target_arch = "wasm32")]
+mod backend {
+  use bytes::Bytes;
+  use rexie::TransactionMode;
+  use crate::open_db;
+
+  const STORE:&str = "responses";
+
+  pub async fn put(key:&str, created:i64, body:&[u8]) -> Result<(),String> {
+    let entry = js_sys::Object::new();
+    js_sys::Reflect::set(&entry,&"created".into(),&js_sys::Number::from(created as f64)).map_err(|e| format!("{:?}",e))?;
+    js_sys::Reflect::set(&entry,&"body".into(),&js_sys::Uint8Array::from(body)).map_err(|e| format!("{:?}",e))?;
+    let db = open_db().await?;
+    let tx = db.transaction(&[STORE],TransactionMode::ReadWrite).map_err(|e| e.to_string())?;
+    let store = tx.store(STORE).map_err(|e| e.to_string())?;
+    store.put(&entry.into(),Some(&key.into())).await.map_err(|e| e.to_string())?;
+    tx.done().await.map_err(|e| e.to_string())?;
+    Ok(())
+  }
+
+  pub async fn get(key:&str) -> Result<Option<(i64,Bytes)>,String> {
+    let db = open_db().await?;
+    let tx = db.transaction(&[STORE],TransactionMode::ReadOnly).map_err(|e| e.to_string())?;
+    let store = tx.store(STORE).map_err(|e| e.to_string())?;
+    let entry = store.get(&key.into()).await.map_err(|e| e.to_string())?;
+    if entry.is_undefined() {
+      return Ok(None);
+    }
+    let created = js_sys::Reflect::get(&entry,&"created".into()).map_err(|e| format!("{:?}",e))?;
+    let body = js_sys::Reflect::get(&entry,&"body".into()).map_err(|e| format!("{:?}",e))?;
+    Ok(Some((
+      created.as_f64().unwrap_or_default() as i64,
+      Bytes::from(js_sys::Uint8Array::new(&body).to_vec()),
+    )))
+  }
+
+  pub async fn clear() -> Result<(),String> {
+    let db = open_db().await?;
+    let tx = db.transaction(&[STORE],TransactionMode::ReadWrite).map_err(|e| e.to_string())?;
+    let store = tx.store(STORE).map_err(|e| e.to_string())?;
+    store.clear().await.map_err(|e| e.to_string())?;
+    tx.done().await.map_err(|e| e.to_string())
+  }
+}
+
+/// Natively responses are written to `./cache/<key>`, the file's modification time is when it was cached.
+#[cfg(not(target_arch = "wasm32"))]
+mod backend {
+  use bytes::Bytes;
+  use std::path::PathBuf;
+
+  const DIR:&str = "cache";
+
+  fn path(key:&str) -> PathBuf {
+    PathBuf::from(DIR).join(key)
+  }
+
+  pub async fn put(key:&str, _created:i64, body:&[u8]) -> Result<(),String> {
+    std::fs::create_dir_all(DIR).map_err(|e| e.to_string())?;
+    std::fs::write(path(key),body).map_err(|e| e.to_string())
+  }
+
+  pub async fn get(key:&str) -> Result<Option<(i64,Bytes)>,String> {
+    let path = path(key);
+    let body = match std::fs::read(&path) {
+      Ok(body) => body,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e.to_string()),
+    };
+    let modified = std::fs::metadata(&path).and_then(|m| m.modified()).map_err(|e| e.to_string())?;
+    let created = chrono::DateTime::<chrono::Utc>::from(modified).timestamp_millis();
+    Ok(Some((created,Bytes::from(body))))
+  }
+
+  pub async fn clear() -> Result<(),String> {
+    match std::fs::remove_dir_all(DIR) {
+      Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.to_string()),
+      _ => Ok(()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn cache_keys_ignore_object_key_order() {
+    let a = json!({"model":"tts-1","input":"hi","voice_settings":{"stability":0.7,"style":0.2}});
+    let b = json!({"voice_settings":{"style":0.2,"stability":0.7},"input":"hi","model":"tts-1"});
+    assert_eq!(cache_key("open_ai_speech",&a),cache_key("open_ai_speech",&b));
+    assert_eq!(cache_key("open_ai_speech",&a).len(),64);
+  }
+
+  #[test]
+  fn cache_keys_tell_providers_values_and_array_order_apart() {
+    let request = json!({"messages":["a","b"]});
+    assert_ne!(cache_key("chat_gpt",&request),cache_key("chat_gpt_judge",&request));
+    assert_ne!(cache_key("chat_gpt",&request),cache_key("chat_gpt",&json!({"messages":["b","a"]})));
+    assert_ne!(cache_key("chat_gpt",&request),cache_key("chat_gpt",&json!({"messages":["a","c"]})));
+  }
+}
@@ -0,0 +1,581 @@
+use super::*;
+use std::collections::BTreeSet;
+
+/// Every row of the uploaded files, kept around so it can be revisited and exported.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Dataset{
+    pub headers:Option<StringRecord>,
+    pub rows:Vec<StringRecord>,
+    /// Where each of `rows` came from
+    pub sources:Vec<RowSource>,
+    /// Rows that couldn't be parsed and were left out
+    pub errors:Vec<RowError>,
+    /// Milliseconds since the epoch, tells a re-upload apart from the dataset it replaced
+    pub uploaded_at:i64,
+}
+
+/// Available to templates as `{_source_file}` and `{_row_number}`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RowSource{
+    pub file:String,
+    /// 1 based index of the row among the rows read from `file`
+    pub row:usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowError{
+    pub file:String,
+    /// 1 based line of the file the row starts on
+    pub line:u64,
+    pub message:String,
+}
+
+/// Rows parsed between giving the browser a chance to render, so large files don't freeze the tab.
+const ROWS_PER_CHUNK : usize = 2000;
+
+/// Natively there's no page to render, the parsing just carries on.
+async fn yield_to_browser() {
+  #[cfg(target_arch = "wasm32")]
+  gloo::timers::future::TimeoutFuture::new(0).await;
+}
+
+/// What part of the dataset is being worked through, runs only visit these rows.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DatasetView{
+    /// Keeps rows with a column containing this, ignoring case
+    pub search:String,
+    pub filters:Vec<ColumnFilter>,
+    /// Indices of rows picked by hand, when any are the view is limited to them
+    pub selected:BTreeSet<usize>,
+}
+impl DatasetView{
+  pub fn matches(&self, headers:Option<&StringRecord>, row:&StringRecord) -> bool {
+    let search = self.search.to_lowercase();
+    (search.is_empty() || row.iter().any(|s| s.to_lowercase().contains(&search)))
+      && self.filters.iter().all(|filter| filter.matches(headers,row))
+  }
+  /// Indices of the rows matching the search and filters, what the browser lists.
+  pub fn rows(&self, dataset:&Dataset) -> Vec<usize> {
+    dataset.rows.iter()
+      .enumerate()
+      .filter(|(_,row)| self.matches(dataset.headers.as_ref(),row))
+      .map(|(i,_)| i)
+      .collect()
+  }
+  /// Indices of the rows a run visits, the selected ones if any are otherwise those matching the search and filters.
+  pub fn selected_or_filtered(&self, dataset:&Dataset) -> Vec<usize> {
+    if !self.selected.is_empty() {
+      return self.selected.iter().copied().filter(|&i| i < dataset.rows.len()).collect();
+    }
+    self.rows(dataset)
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterOp{
+    Equals,
+    NotEquals,
+    Contains,
+}
+
+/// e.g. only rows where `category == "politics"`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnFilter{
+    pub column:String,
+    pub op:FilterOp,
+    pub value:String,
+}
+impl ColumnFilter{
+  fn matches(&self, headers:Option<&StringRecord>, row:&StringRecord) -> bool {
+    let value = headers
+      .and_then(|headers| headers.iter().position(|h| h == self.column))
+      .and_then(|i| row.get(i))
+      .unwrap_or_default();
+    match self.op {
+      FilterOp::Equals => value == self.value,
+      FilterOp::NotEquals => value != self.value,
+      FilterOp::Contains => value.contains(&self.value),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat{
+    /// Delimited text, `,` for CSV, `\t` for TSV or anything else
+    Delimited(u8),
+    /// A JSON array of objects
+    Json,
+    /// One JSON object per line
+    Jsonl,
+    /// An Excel workbook, legacy `.xls` included
+    Xlsx,
+}
+impl InputFormat{
+  /// Guesses the format from the file extension, falling back to CSV.
+  pub fn from_file_name(file_name:&str) -> Self {
+    let extension = file_name.rsplit('.').next().unwrap_or_default().to_lowercase();
+    match extension.as_str() {
+      "tsv" | "tab" => InputFormat::Delimited(b'\t'),
+      "json" => InputFormat::Json,
+      "jsonl" | "ndjson" => InputFormat::Jsonl,
+      "xlsx" | "xlsm" | "xls" => InputFormat::Xlsx,
+      _ => InputFormat::Delimited(b','),
+    }
+  }
+}
+
+/// Which columns are kept when files with different headers are uploaded together.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeaderMerge{
+    /// Every column of every file, empty where a file doesn't have it
+    Union,
+    /// Only the columns all files have
+    Intersection,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextEncoding{
+    /// UTF-8 (with or without a BOM), falling back to Latin-1 when the file isn't valid UTF-8
+    Auto,
+    Utf8,
+    /// Windows-1252, what spreadsheet software on Windows usually exports
+    Latin1,
+}
+
+/// How an uploaded file should be read, `None` fields are guessed from the file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReaderOptions{
+    pub format:Option<InputFormat>,
+    /// Replaces the delimiter of delimited text, whether its format was picked or guessed
+    pub delimiter:Option<u8>,
+    pub quote:u8,
+    pub encoding:TextEncoding,
+    /// Which sheet of a workbook to read, the first one when empty
+    pub sheet:String,
+    /// Load the rows that parsed and report the others, instead of rejecting the file
+    pub skip_bad_rows:bool,
+    pub header_merge:HeaderMerge,
+}
+impl Default for ReaderOptions{
+  fn default() -> Self {
+    Self{
+      format:None,
+      delimiter:None,
+      quote:b'"',
+      encoding:TextEncoding::Auto,
+      sheet:String::new(),
+      skip_bad_rows:false,
+      header_merge:HeaderMerge::Union,
+    }
+  }
+}
+
+/// Reads an uploaded file, `progress` is called with the number of rows read so far.
+/// Unless `skip_bad_rows` is set a file with bad rows is rejected, the error lists them.
+pub async fn read_dataset(
+  file_name:&str,
+  bytes:&[u8],
+  options:&ReaderOptions,
+  progress:impl Fn(usize),
+) -> Result<Dataset,String> {
+  let mut dataset = match options.format.unwrap_or(InputFormat::from_file_name(file_name)) {
+    InputFormat::Delimited(delimiter) => read_delimited(&decode(bytes,options.encoding).await,options.delimiter.unwrap_or(delimiter),options.quote,progress).await?,
+    InputFormat::Json => read_json(&decode(bytes,options.encoding).await,progress).await?,
+    InputFormat::Jsonl => read_jsonl(&decode(bytes,options.encoding).await,progress).await,
+    InputFormat::Xlsx => read_xlsx(bytes,&options.sheet)?,
+  };
+  dataset.headers = dataset.headers.as_ref().map(unique_headers);
+  for error in &mut dataset.errors {
+    error.file = file_name.to_string();
+  }
+  dataset.sources = (1..=dataset.rows.len()).map(|row| RowSource{ file:file_name.to_string(), row }).collect();
+  if !options.skip_bad_rows && !dataset.errors.is_empty() {
+    return Err(dataset.errors.iter()
+      .map(|e| format!("line {}: {}",e.line,e.message))
+      .collect::<Vec<String>>()
+      .join("\n"));
+  }
+  Ok(dataset)
+}
+
+/// Columns are looked up by name, in templates, merges and exports, so a repeated
+/// header gets a `_2`, `_3`... suffix rather than shadowing the first one.
+pub fn unique_headers(headers:&StringRecord) -> StringRecord {
+  let mut unique : Vec<String> = vec![];
+  for header in headers.iter() {
+    let mut name = header.to_string();
+    let mut n = 1;
+    while unique.contains(&name) {
+      n += 1;
+      name = format!("{}_{}",header,n);
+    }
+    unique.push(name);
+  }
+  unique.iter().collect()
+}
+
+/// Parses a delimiter or quote typed by the user, `\t` stands for a tab. The csv reader works on bytes,
+/// so anything but a single ASCII character is rejected rather than cut down to its first byte.
+pub fn parse_separator(input:&str) -> Result<Option<u8>,String> {
+  match input {
+    "" => Ok(None),
+    "\\t" => Ok(Some(b'\t')),
+    _ if input.len() == 1 => Ok(Some(input.as_bytes()[0])),
+    _ => Err(format!("{:?} isn't a single ASCII character",input)),
+  }
+}
+
+/// Merges datasets read from several files into one, reconciling their headers by name.
+pub fn merge_datasets(datasets:Vec<Dataset>, header_merge:HeaderMerge) -> Dataset {
+  if datasets.len() == 1 {
+    return datasets.into_iter().next().unwrap_or_default();
+  }
+  let file_headers : Vec<Vec<String>> = datasets.iter()
+    .map(|d| d.headers.iter().flat_map(|h| h.iter().map(|s| s.to_string())).collect())
+    .collect();
+  let mut headers : Vec<String> = vec![];
+  for header in file_headers.iter().flatten() {
+    if !headers.contains(header) {
+      headers.push(header.clone());
+    }
+  }
+  if header_merge == HeaderMerge::Intersection {
+    headers.retain(|header| file_headers.iter().all(|h| h.contains(header)));
+  }
+  let mut merged = Dataset{
+    headers:Some(headers.iter().collect()),
+    ..Default::default()
+  };
+  for (dataset,file_headers) in datasets.into_iter().zip(file_headers) {
+    let columns : Vec<Option<usize>> = headers.iter()
+      .map(|header| file_headers.iter().position(|h| h == header))
+      .collect();
+    for row in dataset.rows {
+      merged.rows.push(columns.iter()
+        .map(|column| column.and_then(|i| row.get(i)).unwrap_or_default())
+        .collect());
+    }
+    merged.sources.extend(dataset.sources);
+    merged.errors.extend(dataset.errors);
+  }
+  merged
+}
+
+/// Valid UTF-8 is borrowed as is, only Latin-1 needs a decoded copy.
+async fn decode(bytes:&[u8], encoding:TextEncoding) -> std::borrow::Cow<'_,str> {
+  let without_bom = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+  match encoding {
+    TextEncoding::Utf8 => String::from_utf8_lossy(without_bom),
+    TextEncoding::Latin1 => decode_latin1(without_bom).await.into(),
+    TextEncoding::Auto => match std::str::from_utf8(without_bom) {
+      Ok(text) => text.into(),
+      Err(_) => decode_latin1(without_bom).await.into(),
+    },
+  }
+}
+
+/// Bytes decoded between giving the browser a chance to render.
+const BYTES_PER_CHUNK : usize = 1 << 20;
+
+async fn decode_latin1(bytes:&[u8]) -> String {
+  let mut decoder = encoding_rs::WINDOWS_1252.new_decoder_without_bom_handling();
+  // Reserved up front, so the decoder never runs out of room and the results can be ignored.
+  let mut text = String::with_capacity(decoder.max_utf8_buffer_length(bytes.len()).unwrap_or(bytes.len() * 3));
+  for chunk in bytes.chunks(BYTES_PER_CHUNK) {
+    let _ = decoder.decode_to_string(chunk,&mut text,false);
+    yield_to_browser().await;
+  }
+  let _ = decoder.decode_to_string(&[],&mut text,true);
+  text
+}
+
+/// Rows with more or fewer fields than the header are reported rather than padded or cut.
+async fn read_delimited(text:&str, delimiter:u8, quote:u8, progress:impl Fn(usize)) -> Result<Dataset,String> {
+  let mut rdr = csv::ReaderBuilder::new()
+    .delimiter(delimiter)
+    .quote(quote)
+    .from_reader(text.as_bytes());
+  let headers = rdr.headers().map_err(|e| e.to_string())?.clone();
+  let mut rows = vec![];
+  let mut errors = vec![];
+  // Whether the last record read was a bad one, a quote that's never closed takes the rest of the file into it
+  let mut last_was_error = false;
+  for (i,record) in rdr.into_records().enumerate() {
+    last_was_error = record.is_err();
+    match record {
+      Ok(record) => rows.push(record),
+      Err(e) => errors.push(RowError{
+        file:String::new(),
+        // Rows start after the header line.
+        line:e.position().map(|p| p.line()).unwrap_or(i as u64 + 2),
+        message:match e.kind() {
+          csv::ErrorKind::UnequalLengths{ expected_len, len, .. } => format!("has {} fields where the header has {}",len,expected_len),
+          _ => e.to_string(),
+        },
+      }),
+    }
+    if i % ROWS_PER_CHUNK == 0 {
+      progress(rows.len());
+      yield_to_browser().await;
+    }
+  }
+  if last_was_error && text.bytes().filter(|&b| b == quote).count() % 2 == 1 {
+    if let Some(error) = errors.last_mut() {
+      error.message.push_str(&format!(", its {} is never closed",quote as char));
+    }
+  }
+  progress(rows.len());
+  Ok(Dataset{ headers:Some(headers), rows, errors, ..Default::default() })
+}
+
+/// The elements of the array are parsed one at a time, so the flattening can yield in between.
+async fn read_json(text:&str, progress:impl Fn(usize)) -> Result<Dataset,String> {
+  let mut rows = JsonRows::default();
+  let mut rest = text.trim_start().strip_prefix('[').ok_or("expected a JSON array of objects")?;
+  while let Some((value,after)) = next_array_element(rest).map_err(|e| {
+    let line = text[..text.len() - rest.len()].matches('\n').count() + e.line();
+    format!("line {}: {}",line,e)
+  })? {
+    rows.push(&value);
+    rest = after;
+    if rows.rows.len() % ROWS_PER_CHUNK == 0 {
+      progress(rows.rows.len());
+      yield_to_browser().await;
+    }
+  }
+  progress(rows.rows.len());
+  Ok(rows.finish().await)
+}
+
+/// Parses the next element of an array whose `[` has been consumed, `None` once its `]` is reached.
+fn next_array_element(text:&str) -> Result<Option<(serde_json::Value,&str)>,serde_json::Error> {
+  use serde::de::Error;
+  let text = text.trim_start();
+  if let Some(after) = text.strip_prefix(']') {
+    return match after.trim().is_empty() {
+      true => Ok(None),
+      false => Err(serde_json::Error::custom("trailing characters after the array")),
+    };
+  }
+  let mut stream = serde_json::Deserializer::from_str(text).into_iter::<serde_json::Value>();
+  let value = match stream.next() {
+    Some(value) => value?,
+    None => return Err(serde_json::Error::custom("the array isn't closed")),
+  };
+  let after = text[stream.byte_offset()..].trim_start();
+  match after.strip_prefix(',') {
+    Some(after) => Ok(Some((value,after))),
+    None if after.starts_with(']') => Ok(Some((value,after))),
+    None => Err(serde_json::Error::custom("expected `,` or `]` after an element")),
+  }
+}
+
+async fn read_jsonl(text:&str, progress:impl Fn(usize)) -> Dataset {
+  let mut rows = JsonRows::default();
+  for (i,line) in text.lines().enumerate() {
+    if line.trim().is_empty() {
+      continue;
+    }
+    match serde_json::from_str(line) {
+      Ok(value) => rows.push(&value),
+      Err(e) => rows.errors.push(RowError{ file:String::new(), line:i as u64 + 1, message:e.to_string() }),
+    }
+    if i % ROWS_PER_CHUNK == 0 {
+      progress(rows.rows.len());
+      yield_to_browser().await;
+    }
+  }
+  progress(rows.rows.len());
+  rows.finish().await
+}
+
+/// The first row of the sheet is used as headers.
+fn read_xlsx(bytes:&[u8], sheet:&str) -> Result<Dataset,String> {
+  use calamine::{open_workbook_auto_from_rs, Reader};
+  let mut workbook = open_workbook_auto_from_rs(std::io::Cursor::new(bytes)).map_err(|e| e.to_string())?;
+  let sheet = if sheet.is_empty() {
+    workbook.sheet_names().first().cloned().ok_or("the workbook has no sheets")?
+  } else {
+    sheet.to_string()
+  };
+  let range = workbook.worksheet_range(&sheet).map_err(|e| e.to_string())?;
+  let mut rows = range.rows().map(|row| row.iter().map(|cell| cell.to_string()).collect::<StringRecord>());
+  let headers = rows.next();
+  Ok(Dataset{ headers, rows:rows.collect(), ..Default::default() })
+}
+
+/// Nested objects and arrays are flattened into dotted columns, so
+/// `{"author":{"name":"..."}}` can be used in templates as `{author.name}`.
+/// Rows are flattened as they're parsed, columns are added in the order they're first seen.
+#[derive(Default)]
+struct JsonRows{
+    headers:Vec<String>,
+    columns:HashMap<String,usize>,
+    /// The fields of each row by column
+    rows:Vec<Vec<(usize,String)>>,
+    errors:Vec<RowError>,
+}
+impl JsonRows{
+  fn push(&mut self, value:&serde_json::Value) {
+    let mut fields = vec![];
+    flatten_json("",value,&mut fields);
+    let row = fields.into_iter().map(|(key,value)| {
+      let column = match self.columns.get(&key) {
+        Some(&column) => column,
+        None => {
+          self.headers.push(key.clone());
+          self.columns.insert(key,self.headers.len() - 1);
+          self.headers.len() - 1
+        },
+      };
+      (column,value)
+    }).collect();
+    self.rows.push(row);
+  }
+  /// Lays every row out over all the columns seen, a chunk at a time.
+  async fn finish(self) -> Dataset {
+    let width = self.headers.len();
+    let mut rows = Vec::with_capacity(self.rows.len());
+    for (i,fields) in self.rows.into_iter().enumerate() {
+      let mut record = vec![String::new();width];
+      // The first of repeated keys wins.
+      for (column,value) in fields.into_iter().rev() {
+        record[column] = value;
+      }
+      rows.push(record.iter().collect::<StringRecord>());
+      if i % ROWS_PER_CHUNK == 0 {
+        yield_to_browser().await;
+      }
+    }
+    Dataset{ headers:Some(self.headers.iter().collect()), rows, errors:self.errors, ..Default::default() }
+  }
+}
+
+fn flatten_json(prefix:&str, value:&serde_json::Value, fields:&mut Vec<(String,String)>) {
+  use serde_json::Value;
+  let key = |k:&str| if prefix.is_empty() { k.to_string() } else { format!("{}.{}",prefix,k) };
+  match value {
+    Value::Object(map) => for (k,v) in map {
+      flatten_json(&key(k),v,fields);
+    },
+    Value::Array(values) => for (i,v) in values.iter().enumerate() {
+      flatten_json(&key(&i.to_string()),v,fields);
+    },
+    Value::String(s) => fields.push((prefix.to_string(),s.clone())),
+    Value::Null => fields.push((prefix.to_string(),String::new())),
+    other => fields.push((prefix.to_string(),other.to_string())),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn separators_are_single_ascii_characters() {
+    assert_eq!(parse_separator(""),Ok(None));
+    assert_eq!(parse_separator(";"),Ok(Some(b';')));
+    assert_eq!(parse_separator("\\t"),Ok(Some(b'\t')));
+    assert!(parse_separator("§").is_err());
+    assert!(parse_separator(";;").is_err());
+  }
+
+  #[test]
+  fn legacy_xls_is_read_as_a_workbook() {
+    assert_eq!(InputFormat::from_file_name("Export.XLS"),InputFormat::Xlsx);
+    assert!(read_xlsx(b"not a workbook","").is_err());
+  }
+
+  #[test]
+  fn ragged_rows_and_open_quotes_are_reported() {
+    let text = "title,body\na,1\nb,2,extra\nc,3\n\"d,4\ne,5\n";
+    let dataset = block_on(read_delimited(text,b',',b'"',|_| {})).unwrap();
+    assert_eq!(dataset.rows,vec![["a","1"].iter().collect::<StringRecord>(),["c","3"].iter().collect()]);
+    let errors : Vec<(u64,&str)> = dataset.errors.iter().map(|e| (e.line,e.message.as_str())).collect();
+    assert_eq!(errors,[
+      (3,"has 3 fields where the header has 2"),
+      (5,"has 1 fields where the header has 2, its \" is never closed"),
+    ]);
+    let rejected = block_on(read_dataset("posts.csv",text.as_bytes(),&ReaderOptions::default(),|_| {})).unwrap_err();
+    assert!(rejected.starts_with("line 3: has 3 fields"));
+    let skipped = ReaderOptions{ skip_bad_rows:true, ..Default::default() };
+    assert_eq!(block_on(read_dataset("posts.csv",text.as_bytes(),&skipped,|_| {})).unwrap().rows.len(),2);
+  }
+
+  #[test]
+  fn json_arrays_are_read_an_element_at_a_time() {
+    let text = r#"{"a":1} , {"b":[2,3]}
+    ]"#;
+    let (first,rest) = next_array_element(text).unwrap().unwrap();
+    assert_eq!(first,serde_json::json!({"a":1}));
+    let (second,rest) = next_array_element(rest).unwrap().unwrap();
+    assert_eq!(second,serde_json::json!({"b":[2,3]}));
+    assert!(next_array_element(rest).unwrap().is_none());
+    assert!(next_array_element("]]").is_err());
+    assert!(next_array_element(r#"{"a":1} {"b":2}]"#).is_err());
+    let (_,rest) = next_array_element(r#"{"a":1},"#).unwrap().unwrap();
+    assert!(next_array_element(rest).is_err());
+  }
+
+  #[test]
+  fn json_rows_share_columns() {
+    let mut rows = JsonRows::default();
+    rows.push(&serde_json::json!({"title":"a","author":{"name":"x"}}));
+    rows.push(&serde_json::json!({"author":{"name":"y"},"tags":["t"]}));
+    assert_eq!(rows.headers,["title","author.name","tags.0"]);
+    assert_eq!(rows.rows[1],[(1,"y".to_string()),(2,"t".to_string())]);
+  }
+
+  fn dataset(file:&str, headers:&[&str], rows:&[&[&str]]) -> Dataset {
+    Dataset{
+      headers:Some(headers.iter().collect()),
+      rows:rows.iter().map(|row| row.iter().collect()).collect(),
+      sources:(1..=rows.len()).map(|row| RowSource{ file:file.to_string(), row }).collect(),
+      errors:vec![],
+      uploaded_at:0,
+    }
+  }
+
+  #[test]
+  fn merged_columns_are_matched_by_name() {
+    let a = dataset("a.csv",&["title","body"],&[&["t1","b1"]]);
+    let b = dataset("b.csv",&["body","author"],&[&["b2","x"],&["b3","y"]]);
+    let union = merge_datasets(vec![a.clone(),b.clone()],HeaderMerge::Union);
+    assert_eq!(union.headers,Some(["title","body","author"].iter().collect()));
+    assert_eq!(union.rows,vec![
+      ["t1","b1",""].iter().collect::<StringRecord>(),
+      ["","b2","x"].iter().collect(),
+      ["","b3","y"].iter().collect(),
+    ]);
+    assert_eq!(union.sources.iter().map(|s| (s.file.as_str(),s.row)).collect::<Vec<_>>(),[("a.csv",1),("b.csv",1),("b.csv",2)]);
+    let intersection = merge_datasets(vec![a,b],HeaderMerge::Intersection);
+    assert_eq!(intersection.headers,Some(["body"].iter().collect()));
+    assert_eq!(intersection.rows.iter().map(|r| r[0].to_string()).collect::<Vec<_>>(),["b1","b2","b3"]);
+  }
+
+  #[test]
+  fn a_single_dataset_is_kept_as_is() {
+    let a = dataset("a.csv",&["title"],&[&["t1","extra"]]);
+    assert_eq!(merge_datasets(vec![a.clone()],HeaderMerge::Intersection),a);
+  }
+
+  #[test]
+  fn selecting_rows_doesnt_hide_the_others() {
+    let data = dataset("a.csv",&["category"],&[&["politics"],&["sport"],&["politics"]]);
+    let mut view = DatasetView{
+      filters:vec![ColumnFilter{ column:"category".to_string(), op:FilterOp::Equals, value:"politics".to_string() }],
+      ..Default::default()
+    };
+    assert_eq!(view.rows(&data),[0,2]);
+    assert_eq!(view.selected_or_filtered(&data),[0,2]);
+    view.selected.extend([2,7]);
+    assert_eq!(view.rows(&data),[0,2]);
+    assert_eq!(view.selected_or_filtered(&data),[2]);
+  }
+
+  #[test]
+  fn repeated_headers_get_numbered() {
+    let headers : StringRecord = ["title","title","body","title_2","title"].iter().collect();
+    assert_eq!(unique_headers(&headers),["title","title_2","body","title_2_2","title_3"].iter().collect::<StringRecord>());
+  }
+}
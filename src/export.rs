@@ -0,0 +1,249 @@
+use super::*;
+use std::io::Write;
+
+/// Packages a run for offline archiving:
+/// - `manifest.json` with every row, its prompts, models, usage, timestamps and delivery status,
+///   and the `missing_assets` that couldn't be loaded from the asset store
+/// - `rows/<row>/payload.json` with the final payload of each row
+/// - `rows/<row>/<sha256>.<ext>` with the images and audio generated for each row
+/// - `ad_hoc/` likewise for the calls made before any row was loaded
+pub async fn run_zip(run_log:&RunLog) -> Result<Vec<u8>,String> {
+  let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+  let options = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+  // Images and audio are already compressed.
+  let stored = zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+  let mut missing = vec![];
+  for row in run_log.rows.values().chain(run_log.ad_hoc.iter()) {
+    let dir = row.row.map_or("ad_hoc".to_string(),|i| format!("rows/{}",i));
+    if let Some(payload) = &row.payload {
+      zip.start_file(format!("{}/payload.json",dir),options).map_err(|e| e.to_string())?;
+      zip.write_all(serde_json::to_string_pretty(payload).unwrap().as_bytes()).map_err(|e| e.to_string())?;
+    }
+    let mut written = std::collections::HashSet::new();
+    for asset in row.generations.iter().flat_map(|g| g.assets.iter()) {
+      if !written.insert(asset.id.clone()) {
+        continue;
+      }
+      // One asset evicted from the store shouldn't cost the whole archive.
+      let bytes = match load_asset(&asset.id).await {
+        Ok(Some(bytes)) => bytes,
+        Ok(None) => {
+          missing.push(serde_json::json!({"row":row.row,"asset":asset.uri(),"error":"missing from the asset store"}));
+          continue;
+        },
+        Err(err) => {
+          missing.push(serde_json::json!({"row":row.row,"asset":asset.uri(),"error":err}));
+          continue;
+        },
+      };
+      zip.start_file(format!("{}/{}.{}",dir,asset.id,asset.extension()),stored).map_err(|e| e.to_string())?;
+      zip.write_all(&bytes).map_err(|e| e.to_string())?;
+    }
+  }
+  let mut manifest = serde_json::to_value(run_log).unwrap();
+  manifest["missing_assets"] = serde_json::Value::Array(missing);
+  zip.start_file("manifest.json",options).map_err(|e| e.to_string())?;
+  zip.write_all(serde_json::to_string_pretty(&manifest).unwrap().as_bytes()).map_err(|e| e.to_string())?;
+  Ok(zip.finish().map_err(|e| e.to_string())?.into_inner())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat{
+    Csv,
+    Jsonl,
+    Parquet,
+}
+impl ExportFormat{
+  pub fn extension(&self) -> &'static str {
+    match self {
+      ExportFormat::Csv => "csv",
+      ExportFormat::Jsonl => "jsonl",
+      ExportFormat::Parquet => "parquet",
+    }
+  }
+  pub fn mime(&self) -> &'static str {
+    match self {
+      ExportFormat::Csv => "text/csv",
+      ExportFormat::Jsonl => "application/jsonl",
+      ExportFormat::Parquet => "application/vnd.apache.parquet",
+    }
+  }
+}
+
+/// The columns appended to the uploaded ones.
+const ENRICHED_COLUMNS : [&str;8] = [
+  "chat_gpt_choice",
+  "image_assets",
+  "audio_assets",
+  "prompt_tokens",
+  "completion_tokens",
+  "total_tokens",
+  "delivery_status",
+  "payload",
+];
+
+/// The uploaded rows with what was generated for each of them.
+pub struct EnrichedTable{
+    pub headers:Vec<String>,
+    pub rows:Vec<Vec<serde_json::Value>>,
+}
+
+pub fn enriched_table(dataset:&Dataset, run_log:&RunLog) -> EnrichedTable {
+  use serde_json::Value;
+  let width = dataset.rows.iter().map(|r| r.len()).max().unwrap_or_default();
+  let mut headers : Vec<String> = match &dataset.headers {
+    Some(headers) => headers.iter().map(|h| h.to_string()).collect(),
+    None => (0..width).map(|i| i.to_string()).collect(),
+  };
+  let columns = headers.len();
+  headers.extend(ENRICHED_COLUMNS.iter().map(|c| c.to_string()));
+  // An uploaded column may already be called like one of ours.
+  let headers : Vec<String> = unique_headers(&headers.iter().collect()).iter().map(|h| h.to_string()).collect();
+  let rows = dataset.rows.iter().enumerate().map(|(i,record)| {
+    let mut row : Vec<Value> = record.iter().map(|s| Value::String(s.to_string())).collect();
+    // Short rows of a ragged file are padded so the generated columns line up.
+    row.resize(columns,Value::Null);
+    let run = run_log.rows.get(&i);
+    let generations = run.map(|run| run.generations.as_slice()).unwrap_or_default();
+    let assets = |providers:&[&str]| Value::Array(
+      generations.iter()
+        .filter(|g| providers.contains(&g.provider.as_str()))
+        .flat_map(|g| g.assets.iter().map(|a| Value::String(a.uri())))
+        .collect()
+    );
+    // The automatically picked choice, the first when nothing picked one and null when none qualified
+    let chosen = match run.and_then(|run| run.selections.last()) {
+      Some(selection) => selection.chosen,
+      None => Some(0),
+    };
+    let choice = generations.iter().rev()
+      .find(|g| g.provider == "chat_gpt" && g.error.is_none())
+      .zip(chosen)
+      .and_then(|(g,chosen)| g.output.get(chosen as usize).cloned())
+      .unwrap_or(Value::Null);
+    let usage = generations.iter().filter_map(|g| g.usage.as_ref()).fold(TokenUsage::default(),|mut total,usage| {
+      total.prompt_tokens += usage.prompt_tokens;
+      total.completion_tokens += usage.completion_tokens;
+      total.total_tokens += usage.total_tokens;
+      total
+    });
+    row.push(choice);
+    row.push(assets(&["dall_e","dall_e_edit","dall_e_variation","stable_diffusion"]));
+    row.push(assets(&["eleven_labs","open_ai_speech"]));
+    row.push(Value::from(usage.prompt_tokens));
+    row.push(Value::from(usage.completion_tokens));
+    row.push(Value::from(usage.total_tokens));
+    row.push(run.and_then(|run| run.delivery.as_ref()).map(|d| Value::String(d.status.clone())).unwrap_or(Value::Null));
+    // The JSON built for the row, without the root key it's kept under
+    row.push(run.and_then(|run| run.payload.as_ref()).and_then(|p| p.get("")).cloned().unwrap_or(Value::Null));
+    row
+  }).collect();
+  EnrichedTable{ headers, rows }
+}
+
+/// How a value is written in the flat formats, lists are `;` separated.
+fn cell(value:&serde_json::Value) -> String {
+  match value {
+    serde_json::Value::Null => String::new(),
+    serde_json::Value::String(s) => s.clone(),
+    serde_json::Value::Array(values) => values.iter().map(cell).collect::<Vec<String>>().join(";"),
+    other => other.to_string(),
+  }
+}
+
+impl EnrichedTable{
+  pub fn export(&self, format:ExportFormat) -> Result<Vec<u8>,String> {
+    match format {
+      ExportFormat::Csv => self.to_csv(),
+      ExportFormat::Jsonl => Ok(self.to_jsonl()),
+      ExportFormat::Parquet => self.to_parquet(),
+    }
+  }
+  fn to_csv(&self) -> Result<Vec<u8>,String> {
+    let mut wtr = csv::Writer::from_writer(vec![]);
+    wtr.write_record(&self.headers).map_err(|e| e.to_string())?;
+    for row in &self.rows {
+      wtr.write_record(row.iter().map(cell)).map_err(|e| e.to_string())?;
+    }
+    wtr.into_inner().map_err(|e| e.to_string())
+  }
+  fn to_jsonl(&self) -> Vec<u8> {
+    let mut out = String::new();
+    for row in &self.rows {
+      let object : serde_json::Map<String,serde_json::Value> = self.headers.iter().cloned().zip(row.iter().cloned()).collect();
+      out.push_str(&serde_json::Value::Object(object).to_string());
+      out.push('\n');
+    }
+    out.into_bytes()
+  }
+  /// Every column is written as an optional UTF8 string.
+  fn to_parquet(&self) -> Result<Vec<u8>,String> {
+    use parquet::basic::{ConvertedType, Repetition, Type as PhysicalType};
+    use parquet::data_type::{ByteArray, ByteArrayType};
+    use parquet::file::{properties::WriterProperties, writer::SerializedFileWriter};
+    use parquet::schema::types::Type;
+    use std::sync::Arc;
+    let fields = self.headers.iter().map(|header|
+      Type::primitive_type_builder(header,PhysicalType::BYTE_ARRAY)
+        .with_repetition(Repetition::OPTIONAL)
+        .with_converted_type(ConvertedType::UTF8)
+        .build()
+        .map(Arc::new)
+    ).collect::<Result<Vec<_>,_>>().map_err(|e| e.to_string())?;
+    let schema = Type::group_type_builder("results")
+      .with_fields(fields)
+      .build()
+      .map_err(|e| e.to_string())?;
+    let mut writer = SerializedFileWriter::new(vec![],Arc::new(schema),Arc::new(WriterProperties::builder().build()))
+      .map_err(|e| e.to_string())?;
+    let mut row_group = writer.next_row_group().map_err(|e| e.to_string())?;
+    let mut column = 0;
+    while let Some(mut col) = row_group.next_column().map_err(|e| e.to_string())? {
+      let mut values = vec![];
+      let mut def_levels = vec![];
+      for row in &self.rows {
+        match row.get(column).filter(|v| !v.is_null()) {
+          Some(value) => {
+            values.push(ByteArray::from(cell(value).as_str()));
+            def_levels.push(1);
+          },
+          None => def_levels.push(0),
+        }
+      }
+      col.typed::<ByteArrayType>().write_batch(&values,Some(&def_levels),None).map_err(|e| e.to_string())?;
+      col.close().map_err(|e| e.to_string())?;
+      column += 1;
+    }
+    row_group.close().map_err(|e| e.to_string())?;
+    writer.into_inner().map_err(|e| e.to_string())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn rows_where_no_choice_qualified_export_null() {
+    let dataset = Dataset{ rows:vec![["a"].iter().collect(),["b"].iter().collect(),["c"].iter().collect()], ..Default::default() };
+    let mut run_log = RunLog::default();
+    for (row,chosen) in [(0,Some(1)),(1,None)] {
+      let run = run_log.row_mut(Some(row),&[]);
+      let mut generation = Generation::new("chat_gpt","gpt-4o","");
+      generation.output = serde_json::json!(["first","second"]);
+      run.generations.push(generation);
+      run.selections.push(Selection{ strategy:SelectionStrategy::Heuristics, field:"title".to_string(), scores:vec![], chosen });
+    }
+    let mut generation = Generation::new("chat_gpt","gpt-4o","");
+    generation.output = serde_json::json!(["first","second"]);
+    run_log.row_mut(Some(2),&[]).generations.push(generation);
+    run_log.row_mut(Some(2),&[]).payload = Some(serde_json::json!({"":{"title":"first"}}));
+    let table = enriched_table(&dataset,&run_log);
+    let column = |name:&str| {
+      let i = table.headers.iter().position(|h| h == name).unwrap();
+      table.rows.iter().map(|row| row[i].clone()).collect::<Vec<serde_json::Value>>()
+    };
+    assert_eq!(column("chat_gpt_choice"),[serde_json::json!("second"),serde_json::Value::Null,serde_json::json!("first")]);
+    assert_eq!(column("payload"),[serde_json::Value::Null,serde_json::Value::Null,serde_json::json!({"title":"first"})]);
+  }
+}
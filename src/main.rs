@@ -1,3 +1,5 @@
+#![allow(non_snake_case)]
+use std::collections::{BTreeMap, HashMap};
 use bytes::Bytes;
 use csv::StringRecord;
 use gloo::file::ObjectUrl;
@@ -5,6 +7,42 @@ use serde::{Deserialize, Deserializer, Serialize};
 use dioxus::{prelude::*, core::IntoDynNode};
 mod types;
 use types::*;
+mod assets;
+use assets::*;
+mod publish;
+use publish::*;
+mod run;
+use run::*;
+mod export;
+use export::*;
+mod dataset;
+use dataset::*;
+mod pricing;
+use pricing::*;
+mod usage;
+use usage::*;
+mod cache;
+use cache::*;
+mod models;
+use models::*;
+mod selection;
+use selection::*;
+mod moderation;
+use moderation::*;
+mod transforms;
+use transforms::*;
+mod narration;
+use narration::*;
+
+/// Runs a future that never has to wait, the native asset store, cache and file readers don't.
+#[cfg(test)]
+fn block_on<F:std::future::Future>(future:F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    match future.as_mut().poll(&mut std::task::Context::from_waker(std::task::Waker::noop())) {
+        std::task::Poll::Ready(output) => output,
+        std::task::Poll::Pending => panic!("the future is waiting on something"),
+    }
+}
 fn main() {
     // init debug tool for WebAssembly
     wasm_logger::init(wasm_logger::Config::default());
@@ -16,12 +54,35 @@ fn main() {
 fn app(cx: Scope) -> Element {
     use_shared_state_provider(cx, || serde_json::Map::from_iter(vec![(String::new(),serde_json::Value::Object(serde_json::Map::default()))].into_iter()));
     use_shared_state_provider(cx, || ApiKeys::default());
-    use_shared_state_provider::<AppState>(cx, || AppState::default());
+    use_shared_state_provider::<AppState>(cx, || AppState{
+        chat_gpt_model:"gpt-3.5-turbo".to_string(),
+        chat_gpt_max_tokens:256,
+        dall_e_model:"dall-e-2".to_string(),
+        ..Default::default()
+    });
+    use_shared_state_provider::<Vec<AudioClip>>(cx, Vec::new);
+    use_shared_state_provider(cx, S3Config::default);
+    use_shared_state_provider::<Vec<PayloadAttachment>>(cx, Vec::new);
+    use_shared_state_provider(cx, RunLog::default);
+    use_shared_state_provider(cx, Dataset::default);
+    use_shared_state_provider(cx, DatasetView::default);
+    use_shared_state_provider(cx, Budget::default);
+    use_shared_state_provider(cx, UsageLedger::load);
+    use_shared_state_provider(cx, CacheSettings::default);
+    use_shared_state_provider(cx, ModerationSettings::default);
+    use_shared_state_provider(cx, ReviewQueue::default);
     let keys = format!("{:?}",use_shared_state::<ApiKeys>(cx).unwrap().read().clone());
-    let files_uploaded: &UseRef<Vec<String>> = use_ref(cx, Vec::new);
+    let reader_options = use_state(cx, ReaderOptions::default);
+    let upload_error = use_state(cx, || "".to_string());
+    let rows_read = use_state(cx, || None::<usize>);
+    let delimiter = use_state(cx, || "".to_string());
+    let quote = use_state(cx, || "\"".to_string());
+    let separator_error = use_state(cx, || "".to_string());
     let json = use_state(cx, || "{}".to_string());
-    let records = use_ref(cx, ||None);
+    let dataset = use_shared_state::<Dataset>(cx).unwrap();
+    let view = use_shared_state::<DatasetView>(cx).unwrap();
     let app_state = use_shared_state::<AppState>(cx).unwrap();
+    let run_log = use_shared_state::<RunLog>(cx).unwrap();
     cx.render(rsx! (
         div {
             style: "text-align: center;",
@@ -31,58 +92,609 @@ fn app(cx: Scope) -> Element {
             p { "Unlock Seamless Integration with Craptent: Your Ultimate AI Content Pipeline and management tool. Effortlessly merge outputs from sources such as ChatGpt, Dall-E, and Elevenlabs into structured data to your frontend with our reliable webhook system." }
             ApiKey {model:GenModel::OpenAI}
             ApiKey {model:GenModel::ElevenLabs}
+            S3Settings{}
+            CacheSettingsPanel{}
+            ModerationPanel{}
            p {keys}
-           h5 {"Upload CSV"}
+           h5 {"Upload Dataset"}
+           div {
+            select {
+                onchange: move |evt| reader_options.make_mut().format = match evt.value.as_str() {
+                    "csv" => Some(InputFormat::Delimited(b',')),
+                    "tsv" => Some(InputFormat::Delimited(b'\t')),
+                    "json" => Some(InputFormat::Json),
+                    "jsonl" => Some(InputFormat::Jsonl),
+                    "xlsx" => Some(InputFormat::Xlsx),
+                    _ => None,
+                },
+                option {
+                    value:"auto",
+                    "From extension"
+                },
+                option {
+                    value:"csv",
+                    "CSV"
+                },
+                option {
+                    value:"tsv",
+                    "TSV"
+                },
+                option {
+                    value:"json",
+                    "JSON"
+                },
+                option {
+                    value:"jsonl",
+                    "JSONL"
+                },
+                option {
+                    value:"xlsx",
+                    "XLSX"
+                },
+            },
+            span {
+                "Delimiter"
+            }
+            input {
+                style: "width:2em;",
+                value: "{delimiter}",
+                oninput: move |evt| {
+                    delimiter.set(evt.value.clone());
+                    match parse_separator(&evt.value) {
+                        Ok(parsed) => {
+                            reader_options.make_mut().delimiter = parsed;
+                            separator_error.set("".to_string());
+                        },
+                        Err(err) => separator_error.set(format!("delimiter: {}",err)),
+                    }
+                },
+            }
+            span {
+                "Sheet"
+            }
+            input {
+                value: "{reader_options.sheet}",
+                oninput: move |evt| reader_options.make_mut().sheet = evt.value.clone(),
+            }
+            span {
+                "Quote"
+            }
+            input {
+                style: "width:2em;",
+                value: "{quote}",
+                oninput: move |evt| {
+                    quote.set(evt.value.clone());
+                    match parse_separator(&evt.value) {
+                        Ok(parsed) => {
+                            reader_options.make_mut().quote = parsed.unwrap_or(b'"');
+                            separator_error.set("".to_string());
+                        },
+                        Err(err) => separator_error.set(format!("quote: {}",err)),
+                    }
+                },
+            }
+            select {
+                onchange: move |evt| reader_options.make_mut().encoding = match evt.value.as_str() {
+                    "utf8" => TextEncoding::Utf8,
+                    "latin1" => TextEncoding::Latin1,
+                    _ => TextEncoding::Auto,
+                },
+                option {
+                    value:"auto",
+                    "Detect encoding"
+                },
+                option {
+                    value:"utf8",
+                    "UTF-8"
+                },
+                option {
+                    value:"latin1",
+                    "Latin-1"
+                },
+            },
+            label {
+                input {
+                    r#type:"checkbox",
+                    checked: "{reader_options.skip_bad_rows}",
+                    onchange: move |_| {
+                        let skip_bad_rows = reader_options.get().skip_bad_rows;
+                        reader_options.make_mut().skip_bad_rows = !skip_bad_rows;
+                    },
+                }
+                "Skip bad rows"
+            }
+            select {
+                onchange: move |evt| reader_options.make_mut().header_merge = match evt.value.as_str() {
+                    "intersection" => HeaderMerge::Intersection,
+                    _ => HeaderMerge::Union,
+                },
+                option {
+                    value:"union",
+                    "All columns"
+                },
+                option {
+                    value:"intersection",
+                    "Shared columns"
+                },
+            },
+            p {
+                style: "color:red;",
+                "{separator_error}"
+            }
+           }
            input {
             // tell the input to pick a file
             r#type:"file",
             // list the accepted extensions
-            accept: ".csv",
+            accept: ".csv,.tsv,.tab,.txt,.json,.jsonl,.ndjson,.xlsx,.xlsm,.xls",
             // pick multiple files
             multiple: true,
             onchange: |evt| {
-                to_owned![files_uploaded,records];
+                let options = reader_options.current().as_ref().clone();
+                let separator_error = separator_error.get().clone();
+                to_owned![dataset,view,app_state,run_log,upload_error,rows_read];
             async move {
+                if !separator_error.is_empty() {
+                    upload_error.set(separator_error);
+                    return;
+                }
                 if let Some(file_engine) = &evt.files {
                     let files = file_engine.files();
+                    let mut datasets = vec![];
+                    let mut errors = vec![];
                     for file_name in &files {
                         // Make sure to use async/await when doing heavy I/O operations,
                         // to not freeze the interface in the meantime
-                        if let Some(file) = file_engine.read_file_to_string(file_name).await{
-                            let mut rdr = csv::Reader::from_reader(file.as_bytes());
-                            let r = rdr.into_records();
-                            let mut res = vec![];
-                            for rec in r {
-                                res.push(rec.unwrap())
+                        if let Some(file) = file_engine.read_file(file_name).await{
+                            let progress = rows_read.clone();
+                            let read_before : usize = datasets.iter().map(|d:&Dataset| d.rows.len()).sum();
+                            match read_dataset(file_name,&file,&options,move |n| progress.set(Some(read_before+n))).await {
+                                Ok(read) => datasets.push(read),
+                                Err(err) => errors.push(format!("{}: {}",file_name,err)),
                             }
-                            *records.write() = Some(res.into_iter());
-                            files_uploaded.write().push(file);
                         }
                     }
+                    upload_error.set(errors.join("\n"));
+                    if !datasets.is_empty() {
+                        let mut merged = merge_datasets(datasets,options.header_merge);
+                        merged.uploaded_at = chrono::Utc::now().timestamp_millis();
+                        app_state.write().update_dataset(merged.headers.clone());
+                        *dataset.write() = merged;
+                        // Row indices don't mean anything in the new dataset, neither selected nor logged.
+                        view.write().selected.clear();
+                        *run_log.write() = RunLog::default();
+                    }
                 }
             }
             }
             }
-            h5 {"Build Json Structure"}
-            BuildJsonStructure{}
-            p {format!("{:?}",app_state.read().current_record)}
-            button{
-                onclick: move |_| {
-                    if let Some(records) = records.write().as_mut() {
-                        if let Some(record) = records.next() {
-                            app_state.write().update_current_record(record.clone());
-                        }
+            rows_read.get().map(|n| rsx!(
+                p {
+                    "{n} rows read"
+                }
+            ))
+            if !dataset.read().errors.is_empty() {
+                rsx!(
+                    p {
+                        format!("{} bad rows skipped",dataset.read().errors.len())
                     }
-                },
-                "next"
+                )
+            }
+            ul {
+                for error in dataset.read().errors.iter().take(100) {
+                    li {
+                        "{error.file} line {error.line}: {error.message}"
+                    }
+                }
             }
+            pre {
+                "{upload_error}"
+            }
+            h5 {"Build Json Structure"}
+            BuildJsonStructure{}
+            ReviewQueuePanel{}
+            DatasetBrowser{}
+            PromptPreview{}
+           RunExport{}
            ChatGpt{}
            DallE{}
+           StableDiffusion{}
            ElevenLabs{}
+           OpenAISpeech{}
+           Whisper{}
         }
     ))
 }
 
+/// Makes row `i` of the dataset the current one, keeping the payload built for the row being left
+/// and bringing back the one recorded for row `i`, or an empty one of the same shape.
+fn go_to_row(
+    app_state:&UseSharedState<AppState>,
+    run_log:&UseSharedState<RunLog>,
+    ledger:&UseSharedState<UsageLedger>,
+    payload:&UseSharedState<serde_json::Map<String,serde_json::Value>>,
+    attachments:&UseSharedState<Vec<PayloadAttachment>>,
+    dataset:&Dataset,
+    i:usize,
+) {
+    if let Some(record) = dataset.rows.get(i) {
+        if app_state.read().current_record.is_some() {
+            RunRecorder::new(run_log,ledger,&app_state.read()).payload(serde_json::Value::Object(payload.read().clone()));
+        }
+        // Attachments belong to the payload of the row being left.
+        attachments.write().clear();
+        // What was built for the row on an earlier visit, or picked for it by a batch.
+        let recorded = run_log.read().rows.get(&i).and_then(|run| run.payload.clone());
+        let fresh = row_payload(&payload.read(),recorded.as_ref());
+        *payload.write() = fresh;
+        app_state.write().current_row = Some(i);
+        app_state.write().update_current_record(record.clone(),dataset.sources.get(i).cloned());
+    }
+}
+
+const BROWSER_PAGE_SIZE : usize = 25;
+
+/// A table of the dataset with search, column filters and row selection.
+/// Previous and next only visit the rows in view.
+fn DatasetBrowser(cx:Scope) -> Element {
+    let dataset = use_shared_state::<Dataset>(cx).unwrap();
+    let view = use_shared_state::<DatasetView>(cx).unwrap();
+    let app_state = use_shared_state::<AppState>(cx).unwrap();
+    let run_log = use_shared_state::<RunLog>(cx).unwrap();
+    let ledger = use_shared_state::<UsageLedger>(cx).unwrap();
+    let payload = use_shared_state::<serde_json::Map<String,serde_json::Value>>(cx).unwrap();
+    let attachments = use_shared_state::<Vec<PayloadAttachment>>(cx).unwrap();
+    let page = use_state(cx, || 0_usize);
+    let jump = use_state(cx, || "".to_string());
+    let filter_column = use_state(cx, || "".to_string());
+    let filter_op = use_state(cx, || FilterOp::Equals);
+    let filter_value = use_state(cx, || "".to_string());
+    let headers : Vec<String> = dataset.read().headers.iter().flat_map(|h| h.iter().map(|s| s.to_string())).collect();
+    let rows = view.read().rows(&dataset.read());
+    let total = dataset.read().rows.len();
+    let in_view = rows.len();
+    let current = app_state.read().current_row;
+    let prev = rows.iter().rev().find(|&&i| current.is_some_and(|c| i < c)).copied();
+    let next = rows.iter().find(|&&i| current.is_none_or(|c| i > c)).copied();
+    let pages = in_view.div_ceil(BROWSER_PAGE_SIZE).max(1);
+    // The rows in view may have shrunk since the page was turned, e.g. a file was re-uploaded.
+    let current_page = (*page.get()).min(pages - 1);
+    let page_rows : Vec<(usize,bool,Vec<String>)> = rows.iter()
+        .skip(current_page * BROWSER_PAGE_SIZE)
+        .take(BROWSER_PAGE_SIZE)
+        .map(|&i| (
+            i,
+            view.read().selected.contains(&i),
+            dataset.read().rows[i].iter().map(|s| s.to_string()).collect(),
+        ))
+        .collect();
+    let filters : Vec<(usize,String)> = view.read().filters.iter()
+        .map(|f| format!("{} {} \"{}\"",f.column,match f.op {
+            FilterOp::Equals => "==",
+            FilterOp::NotEquals => "!=",
+            FilterOp::Contains => "contains",
+        },f.value))
+        .enumerate()
+        .collect();
+    let selected = view.read().selected.len();
+    let current_fields : Vec<(String,String)> = match &app_state.read().current_record {
+        Some(record) => record.iter().enumerate()
+            .map(|(i,value)| (headers.get(i).cloned().unwrap_or(i.to_string()),value.to_string()))
+            .collect(),
+        None => vec![],
+    };
+    let position = current.map(|i| format!("row {} of {}",i+1,total)).unwrap_or_default();
+    cx.render(rsx!{
+        div {
+            h5 {"Dataset"}
+            p {
+                "{in_view} of {total} rows in view"
+            }
+            div {
+                input {
+                    placeholder: "search",
+                    value: "{view.read().search}",
+                    oninput: move |evt| {
+                        view.write().search = evt.value.clone();
+                        page.set(0);
+                    },
+                }
+            }
+            div {
+                select {
+                    onchange: move |evt| filter_column.set(evt.value.clone()),
+                    option {
+                        value:"",
+                        "column"
+                    },
+                    for header in headers.iter() {
+                        option {
+                            value:"{header}",
+                            "{header}"
+                        }
+                    }
+                },
+                select {
+                    onchange: move |evt| filter_op.set(match evt.value.as_str() {
+                        "not-equals" => FilterOp::NotEquals,
+                        "contains" => FilterOp::Contains,
+                        _ => FilterOp::Equals,
+                    }),
+                    option {
+                        value:"equals",
+                        "=="
+                    },
+                    option {
+                        value:"not-equals",
+                        "!="
+                    },
+                    option {
+                        value:"contains",
+                        "contains"
+                    },
+                },
+                input {
+                    value: "{filter_value}",
+                    oninput: move |evt| filter_value.set(evt.value.clone()),
+                }
+                button {
+                    onclick: move |_| {
+                        if filter_column.get().is_empty() {
+                            return;
+                        }
+                        view.write().filters.push(ColumnFilter{
+                            column:filter_column.current().as_ref().clone(),
+                            op:*filter_op.get(),
+                            value:filter_value.current().as_ref().clone(),
+                        });
+                        page.set(0);
+                    },
+                    "add filter"
+                }
+            }
+            for (i,filter) in filters.into_iter() {
+                div {
+                    span {
+                        "{filter}"
+                    }
+                    button {
+                        onclick: move |_| {
+                            view.write().filters.remove(i);
+                            page.set(0);
+                        },
+                        "remove"
+                    }
+                }
+            }
+            if selected > 0 {
+                rsx!{
+                    div {
+                        span {
+                            "{selected} rows selected"
+                        }
+                        button {
+                            onclick: move |_| view.write().selected.clear(),
+                            "clear selection"
+                        }
+                    }
+                }
+            }
+            div {
+                button {
+                    disabled: prev.is_none(),
+                    onclick: move |_| if let Some(i) = prev {
+                        go_to_row(app_state,run_log,ledger,payload,attachments,&dataset.read(),i);
+                    },
+                    "previous"
+                }
+                span {
+                    "{position}"
+                }
+                button {
+                    disabled: next.is_none(),
+                    onclick: move |_| if let Some(i) = next {
+                        go_to_row(app_state,run_log,ledger,payload,attachments,&dataset.read(),i);
+                    },
+                    "next"
+                }
+                input {
+                    style: "width:6em;",
+                    placeholder: "row",
+                    value: "{jump}",
+                    oninput: move |evt| jump.set(evt.value.clone()),
+                }
+                button {
+                    onclick: move |_| if let Ok(row) = jump.get().trim().parse::<usize>() {
+                        go_to_row(app_state,run_log,ledger,payload,attachments,&dataset.read(),row.saturating_sub(1));
+                    },
+                    "go"
+                }
+            }
+            table {
+                tr {
+                    th {""}
+                    th {"#"}
+                    for header in headers.iter() {
+                        th {"{header}"}
+                    }
+                }
+                for (i,is_selected,row) in page_rows.into_iter() {
+                    tr {
+                        style: if current == Some(i) { "font-weight:bold;" } else { "" },
+                        td {
+                            input {
+                                r#type:"checkbox",
+                                checked: "{is_selected}",
+                                onchange: move |_| {
+                                    let mut view = view.write();
+                                    if !view.selected.remove(&i) {
+                                        view.selected.insert(i);
+                                    }
+                                },
+                            }
+                        }
+                        td {
+                            button {
+                                onclick: move |_| go_to_row(app_state,run_log,ledger,payload,attachments,&dataset.read(),i),
+                                "{i+1}"
+                            }
+                        }
+                        for cell in row.into_iter() {
+                            td {"{cell}"}
+                        }
+                    }
+                }
+            }
+            div {
+                button {
+                    disabled: current_page == 0,
+                    onclick: move |_| page.set(current_page.saturating_sub(1)),
+                    "previous page"
+                }
+                span {
+                    "page {current_page+1} of {pages}"
+                }
+                button {
+                    disabled: current_page + 1 >= pages,
+                    onclick: move |_| page.set(current_page + 1),
+                    "next page"
+                }
+            }
+            table {
+                for (header,value) in current_fields.into_iter() {
+                    tr {
+                        th {"{header}"}
+                        td {"{value}"}
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Templates shown in the preview.
+const PREVIEW_TEMPLATES : [&str;4] = [
+    "ChatGPT system",
+    "ChatGPT prompt",
+    "DALL-E",
+    "ElevenLabs",
+];
+
+/// A template's label, its rendered text as `(text, style)` segments and what's wrong with it.
+type PreviewCell<'a> = (&'a str,Vec<(String,&'a str)>,Vec<String>);
+
+/// Renders the templates against several rows at once, highlighting what was substituted
+/// and flagging results that are empty, too long or reference columns that don't exist.
+fn PromptPreview(cx:Scope) -> Element {
+    let dataset = use_shared_state::<Dataset>(cx).unwrap();
+    let view = use_shared_state::<DatasetView>(cx).unwrap();
+    let app_state = use_shared_state::<AppState>(cx).unwrap();
+    let row_count = use_state(cx, || 5_usize);
+    let app_state = app_state.read();
+    let raws = [
+        &app_state.chat_gpt_system_raw,
+        &app_state.chat_gpt_prompt_raw,
+        &app_state.dall_e_raw,
+        &app_state.eleven_labs_raw,
+    ];
+    let dall_e_limit = DallERequest::max_prompt_chars(&app_state.dall_e_model);
+    // Selected rows are previewed as a whole, otherwise the first rows in view.
+    let rows : Vec<usize> = if view.read().selected.is_empty() {
+        view.read().rows(&dataset.read()).into_iter().take(*row_count.get()).collect()
+    } else {
+        view.read().selected_or_filtered(&dataset.read())
+    };
+    let previews : Vec<(usize,Vec<PreviewCell>)> = rows.into_iter()
+        .map(|i| {
+            let dataset = dataset.read();
+            // Few-shot examples and follow-ups aren't counted, they're set up in the ChatGPT panel.
+            let prompt_tokens = conversation_prompt_tokens(&app_state,&app_state.chat_gpt_model,&[],&[],app_state.chat_gpt_max_tokens,Some((&dataset,i)));
+            let context_error = check_max_tokens(&app_state.chat_gpt_model,prompt_tokens[0],app_state.chat_gpt_max_tokens);
+            let cells = PREVIEW_TEMPLATES.iter().zip(raws.iter())
+                .filter(|(_,raw)| !raw.is_empty())
+                .map(|(label,raw)| {
+                    let segments = app_state.render_segments(raw,dataset.rows.get(i),dataset.sources.get(i));
+                    let mut flags = vec![];
+                    let rendered = join_segments(segments.clone());
+                    if rendered.trim().is_empty() {
+                        flags.push("empty".to_string());
+                    }
+                    // ElevenLabs text is narrated in chunks, however long it is.
+                    match *label {
+                        "DALL-E" if rendered.chars().count() > dall_e_limit => {
+                            flags.push(format!("{} characters, over the {} limit",rendered.chars().count(),dall_e_limit));
+                        },
+                        "ChatGPT prompt" => flags.extend(context_error.clone()),
+                        _ => (),
+                    }
+                    for segment in &segments {
+                        match segment {
+                            TemplateSegment::Value(value) if value.trim().is_empty() => flags.push("a substituted value is empty".to_string()),
+                            // Braces around JSON and the like aren't placeholders.
+                            TemplateSegment::Missing(key) if !key.contains(['"',':','\n']) => flags.push(format!("no column named {{{}}}",key)),
+                            _ => (),
+                        }
+                    }
+                    flags.dedup();
+                    let segments = segments.into_iter().map(|segment| match segment {
+                        TemplateSegment::Text(s) => (s,""),
+                        TemplateSegment::Value(s) => (s,"background:#ffef8a;"),
+                        TemplateSegment::Missing(key) => (format!("{{{}}}",key),"background:#ffb3b3;"),
+                    }).collect();
+                    (*label,segments,flags)
+                })
+                .collect();
+            (i,cells)
+        })
+        .collect();
+    cx.render(rsx!{
+        div {
+            h5 {"Prompt Preview"}
+            span {
+                "Rows"
+            }
+            input {
+                r#type:"number",
+                min:"1",
+                value:"{row_count}",
+                oninput: move |evt| if let Ok(n) = evt.value.parse::<usize>() {
+                    row_count.set(n.max(1));
+                },
+            }
+            for (row,cells) in previews.into_iter() {
+                div {
+                    style: "text-align:left;border-top:1px solid #ccc;",
+                    b {
+                        "row {row+1}"
+                    }
+                    for (label,segments,flags) in cells.into_iter() {
+                        div {
+                            em {
+                                "{label}: "
+                            }
+                            span {
+                                style: "white-space:pre-wrap;",
+                                for (text,style) in segments.into_iter() {
+                                    span {
+                                        style: "{style}",
+                                        "{text}"
+                                    }
+                                }
+                            }
+                            for flag in flags.into_iter() {
+                                p {
+                                    style: "color:red;margin:0;",
+                                    "{flag}"
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
 fn recursive_obj_search(
     map: &mut dyn Iterator<Item=(&String, &serde_json::Value)>, 
     list: &mut Vec<LazyNodes>, 
@@ -105,84 +717,572 @@ fn recursive_obj_search(
 }
 
 async fn post_json(
+    recorder:RunRecorder,
     key:String,
     endpoint:String,
     json:String,
+    attachments:Vec<PayloadAttachment>,
     ) {
+    recorder.payload(serde_json::from_str(&json).unwrap());
         log::error!("{}",endpoint);
-    let _ = reqwest::Client::new()
-        .post(endpoint)
-        .header("Authorization",format!("Bearer {}",key))
-        .header("Content-Type","application/json")
-        .body(json)
-    .send()
-    .await
-    .unwrap();
+    let request = reqwest::Client::new()
+        .post(&endpoint)
+        .header("Authorization",format!("Bearer {}",key));
+    let request = if attachments.is_empty() {
+        request
+            .header("Content-Type","application/json")
+            .body(json)
+    } else {
+        // The JSON goes in a `payload` part, each attachment in a part named after its payload field.
+        let mut form = reqwest::multipart::Form::new()
+            .part("payload",reqwest::multipart::Part::text(json).mime_str("application/json").unwrap());
+        for attachment in attachments {
+            let bytes = match load_asset(&attachment.asset.id).await {
+                Ok(Some(bytes)) => bytes,
+                Ok(None) => {
+                    recorder.delivery(&endpoint,format!("{} is missing from the asset store",attachment.asset.uri()));
+                    return;
+                },
+                Err(err) => {
+                    recorder.delivery(&endpoint,format!("couldn't load {}: {}",attachment.asset.uri(),err));
+                    return;
+                },
+            };
+            form = form.part(
+                attachment.path.clone(),
+                reqwest::multipart::Part::bytes(bytes.to_vec())
+                    .file_name(format!("{}.{}",attachment.asset.id,attachment.asset.extension()))
+                    .mime_str(&attachment.asset.mime)
+                    .unwrap()
+            );
+        }
+        request.multipart(form)
+    };
+    let status = match request.send().await {
+        Ok(resp) => resp.status().to_string(),
+        Err(e) => e.to_string(),
+    };
+    recorder.delivery(&endpoint,status);
 }
 
-
-fn BuildJsonStructure(cx:Scope) -> Element {
-    let map = use_shared_state::<serde_json::Map<String,serde_json::Value>>(cx).unwrap();
-    let mut add_list = vec![];
-    let mut path = vec![];
-    let endpoint = use_state(cx, || "".to_string());
-    let endpoint_key = use_state(cx, || "".to_string());
-    recursive_obj_search(&mut map.read().iter(),&mut add_list,path);
-    cx.render(rsx!{
-        p{
-            "{serde_json::to_string(&*map.read().get(\"\").unwrap()).unwrap()}"
+/// Posts the payload unless moderation flags it, in which case it's blocked or held for review.
+/// `input` is the row the payload came from, checked too when the settings ask for it.
+async fn deliver(
+    delivery:PendingDelivery,
+    moderation:ModerationSettings,
+    open_ai_key:String,
+    input:Vec<String>,
+    review_queue:UseSharedState<ReviewQueue>,
+    ) {
+    if moderation.enabled {
+        let mut texts = moderation_texts(&serde_json::from_str(&delivery.json).unwrap());
+        if moderation.include_input {
+            texts.extend(input);
         }
-        div{
-            div {
-                span{
-                    "endpoint"
-                }
-                input{
-                    oninput: move |evt| {
-                        endpoint.set(evt.value.clone())
-                    },
-                }
-            }
-            
-            div {
-                span{
-                    "endpoint auth"
-                }
-                input{
-                    oninput: move |evt| {
-                        endpoint_key.set(evt.value.clone())
-                    },
-                }
-            }
-            button{
-                onclick:move |_| {
-                    post_json(
-                        endpoint_key.current().as_ref().clone(),
-                        endpoint.current().as_ref().clone(),
-                        serde_json::to_string(&*map.read()).unwrap()
-                    )
+        let result = moderate(&moderation,&open_ai_key,&texts).await;
+        let recorder = &delivery.recorder;
+        recorder.moderation(result.clone());
+        if result.flagged {
+            recorder.payload(serde_json::from_str(&delivery.json).unwrap());
+            match moderation.action {
+                FlaggedAction::Block => recorder.delivery(&delivery.endpoint,format!("blocked by moderation: {}",result.reasons.join(", "))),
+                FlaggedAction::Review => {
+                    recorder.delivery(&delivery.endpoint,"held for review".to_string());
+                    review_queue.write().hold(PendingDelivery{ reasons:result.reasons, ..delivery });
                 },
-                "post json"
             }
+            return;
         }
-        add_list.into_iter()
-    })
+    }
+    let PendingDelivery{ recorder, key, endpoint, json, attachments, .. } = delivery;
+    post_json(recorder,key,endpoint,json,attachments).await;
 }
 
-#[derive(Props,PartialEq)]
-pub struct BuildJsonObjectProps{
-    path:Vec<String>,
+/// Flags the texts with the configured provider. Fails closed, text that couldn't be checked counts as flagged.
+async fn moderate(settings:&ModerationSettings, key:&str, texts:&[String]) -> ModerationResult {
+    if texts.is_empty() {
+        return ModerationResult{ flagged:false, reasons:vec![] };
+    }
+    let reasons = match settings.provider {
+        ModerationProvider::Blocklist => blocklist_check(&settings.blocklist,texts),
+        ModerationProvider::OpenAi => fetch_moderation(key,texts).await.map(|resp| flagged_categories(&resp)),
+    };
+    match reasons {
+        Ok(reasons) => ModerationResult{ flagged:!reasons.is_empty(), reasons },
+        Err(err) => ModerationResult{ flagged:true, reasons:vec![format!("couldn't moderate: {}",err)] },
+    }
 }
-fn BuildJsonObject(cx:Scope<BuildJsonObjectProps>) -> Element {
-    let schema = use_state(cx,||"".to_string());
+
+async fn fetch_moderation(key:&str, texts:&[String]) -> Result<ModerationResponse,String> {
+    reqwest::Client::new()
+        .post("https://api.openai.com/v1/moderations")
+        .header("Authorization",format!("Bearer {}",key))
+        .json(&serde_json::json!({
+            "model":"omni-moderation-latest",
+            "input":texts,
+        }))
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json::<ModerationResponse>()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+fn ModerationPanel(cx:Scope) -> Element {
+    let moderation = use_shared_state::<ModerationSettings>(cx).unwrap();
+    let settings = moderation.read().clone();
     cx.render(rsx!{
-        div{
-            p{"Json Schema"}
-            input{
-                oninput: move |evt| schema.set(evt.value.clone()),
+        div {
+            div {
+                "Moderation"
             }
-        }
-        
+            span {
+                "enabled"
+            }
+            input {
+                r#type:"checkbox",
+                checked: "{settings.enabled}",
+                onchange: move |_| {
+                    let enabled = moderation.read().enabled;
+                    moderation.write().enabled = !enabled;
+                },
+            }
+            select {
+                onchange: move |evt| moderation.write().provider = match evt.value.as_str() {
+                    "blocklist" => ModerationProvider::Blocklist,
+                    _ => ModerationProvider::OpenAi,
+                },
+                option {
+                    value:"openai",
+                    "OpenAI moderation"
+                },
+                option {
+                    value:"blocklist",
+                    "Blocklist"
+                },
+            }
+            span {
+                "flagged payloads"
+            }
+            select {
+                onchange: move |evt| moderation.write().action = match evt.value.as_str() {
+                    "block" => FlaggedAction::Block,
+                    _ => FlaggedAction::Review,
+                },
+                option {
+                    value:"review",
+                    "go to review"
+                },
+                option {
+                    value:"block",
+                    "are blocked"
+                },
+            }
+            span {
+                "check the input row too"
+            }
+            input {
+                r#type:"checkbox",
+                checked: "{settings.include_input}",
+                onchange: move |_| {
+                    let include_input = moderation.read().include_input;
+                    moderation.write().include_input = !include_input;
+                },
+            }
+            if settings.provider == ModerationProvider::Blocklist {
+                rsx!{
+                    div {
+                        textarea {
+                            placeholder: "one keyword or /regex/ per line",
+                            value: "{settings.blocklist}",
+                            oninput: move |evt| moderation.write().blocklist = evt.value.clone(),
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Payloads moderation flagged, sent or dropped by hand.
+fn ReviewQueuePanel(cx:Scope) -> Element {
+    let review_queue = use_shared_state::<ReviewQueue>(cx).unwrap();
+    let pending : Vec<(u64,String,String,String,String)> = review_queue.read().pending.iter()
+        .map(|(id,p)| (
+            *id,
+            p.row.map(|row| format!("row {}",row+1)).unwrap_or_default(),
+            p.endpoint.clone(),
+            p.reasons.join(", "),
+            p.json.clone(),
+        ))
+        .collect();
+    cx.render(rsx!{
+        if !pending.is_empty() {
+            rsx!{
+                div {
+                    h5 {"Review"}
+                    for (id,row,endpoint,reasons,json) in pending.into_iter() {
+                        div {
+                            style: "text-align:left;border-top:1px solid #ccc;",
+                            p {
+                                "{row} to {endpoint}"
+                            }
+                            p {
+                                style: "color:red;",
+                                "{reasons}"
+                            }
+                            p {
+                                "{json}"
+                            }
+                            button {
+                                onclick: move |_| {
+                                    let item = review_queue.write().take(id);
+                                    async move {
+                                        if let Some(item) = item {
+                                            post_json(item.recorder,item.key,item.endpoint,item.json,item.attachments).await;
+                                        }
+                                    }
+                                },
+                                "Approve and send"
+                            }
+                            button {
+                                onclick: move |_| if let Some(item) = review_queue.write().take(id) {
+                                    item.recorder.delivery(&item.endpoint,"rejected in review".to_string());
+                                },
+                                "Reject"
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+
+fn BuildJsonStructure(cx:Scope) -> Element {
+    let map = use_shared_state::<serde_json::Map<String,serde_json::Value>>(cx).unwrap();
+    let attachments = use_shared_state::<Vec<PayloadAttachment>>(cx).unwrap();
+    let app_state = use_shared_state::<AppState>(cx).unwrap();
+    let run_log = use_shared_state::<RunLog>(cx).unwrap();
+    let ledger = use_shared_state::<UsageLedger>(cx).unwrap();
+    let keys = use_shared_state::<ApiKeys>(cx).unwrap();
+    let moderation = use_shared_state::<ModerationSettings>(cx).unwrap();
+    let review_queue = use_shared_state::<ReviewQueue>(cx).unwrap();
+    let mut add_list = vec![];
+    let mut path = vec![];
+    let endpoint = use_state(cx, || "".to_string());
+    let endpoint_key = use_state(cx, || "".to_string());
+    recursive_obj_search(&mut map.read().iter(),&mut add_list,path);
+    cx.render(rsx!{
+        p{
+            "{serde_json::to_string(&*map.read().get(\"\").unwrap()).unwrap()}"
+        }
+        div{
+            div {
+                span{
+                    "endpoint"
+                }
+                input{
+                    oninput: move |evt| {
+                        endpoint.set(evt.value.clone())
+                    },
+                }
+            }
+            
+            div {
+                span{
+                    "endpoint auth"
+                }
+                input{
+                    oninput: move |evt| {
+                        endpoint_key.set(evt.value.clone())
+                    },
+                }
+            }
+            button{
+                onclick:move |_| {
+                    let delivery = PendingDelivery{
+                        recorder:RunRecorder::new(run_log,ledger,&app_state.read()),
+                        row:app_state.read().current_row,
+                        key:endpoint_key.current().as_ref().clone(),
+                        endpoint:endpoint.current().as_ref().clone(),
+                        json:serde_json::to_string(&*map.read()).unwrap(),
+                        attachments:attachments.read().clone(),
+                        reasons:vec![],
+                    };
+                    deliver(
+                        delivery,
+                        moderation.read().clone(),
+                        keys.read().open_ai.clone(),
+                        app_state.read().current_record.iter().flat_map(|r| r.iter().map(|s| s.to_string())).collect(),
+                        review_queue.clone(),
+                    )
+                },
+                "post json"
+            }
+        }
+        add_list.into_iter()
+    })
+}
+
+#[derive(Props,PartialEq)]
+pub struct BuildJsonObjectProps{
+    path:Vec<String>,
+}
+fn BuildJsonObject(cx:Scope<BuildJsonObjectProps>) -> Element {
+    let schema = use_state(cx,||"".to_string());
+    cx.render(rsx!{
+        div{
+            p{"Json Schema"}
+            input{
+                oninput: move |evt| schema.set(evt.value.clone()),
+            }
+        }
+        
+    })
+}
+/// Puts a stored asset into the payload as its `asset:` reference, as the public url
+/// it gets after being published to the S3 bucket, inline as base64 or a data URI,
+/// or as a multipart attachment sent alongside the JSON.
+fn AssetField(cx:Scope<AssetFieldProps>) -> Element {
+    use serde_json::Value;
+    let map = use_shared_state::<serde_json::Map<String,Value>>(cx).unwrap();
+    let attachments = use_shared_state::<Vec<PayloadAttachment>>(cx).unwrap();
+    let app_state = use_shared_state::<AppState>(cx).unwrap();
+    let s3 = use_shared_state::<S3Config>(cx).unwrap();
+    let path = use_state(cx, || "".to_string());
+    let mode = use_state(cx, || "reference".to_string());
+    let key_template = use_state(cx, || "{asset.id}.{asset.ext}".to_string());
+    let status = use_state(cx, || "".to_string());
+    let key = render_key_template(key_template.get(),&app_state.read(),&cx.props.asset);
+    let key_preview = key.clone();
+    cx.render(rsx!(
+        div{
+            select {
+                onchange: move |evt| mode.set(evt.value.clone()),
+                option {
+                    value:"reference",
+                    "Reference"
+                },
+                option {
+                    value:"publish",
+                    "Publish"
+                },
+                option {
+                    value:"base64",
+                    "Base64"
+                },
+                option {
+                    value:"data-uri",
+                    "Data URI"
+                },
+                option {
+                    value:"multipart",
+                    "Multipart"
+                },
+            },
+            if mode.get() == "publish" {
+                rsx!{
+                    input{
+                        value:"{key_template}",
+                        oninput: move |evt| key_template.set(evt.value.clone())
+                    }
+                    span{
+                        "{key_preview}"
+                    }
+                }
+            }
+            input{
+                value:"{path}",
+                oninput: move |evt| path.set(evt.value.clone())
+            }
+            button{
+                onclick: move |_| {
+                    let path = path.current().as_ref().clone();
+                    let mode = mode.current().as_ref().clone();
+                    let asset = cx.props.asset.clone();
+                    let config = s3.read().clone();
+                    let key = key.clone();
+                    to_owned![map,attachments,status];
+                    async move {
+                        use base64::Engine;
+                        if path.is_empty() {
+                            return;
+                        }
+                        attachments.write().retain(|a| !a.replaced_by(&path));
+                        let value = match mode.as_str() {
+                            "publish" => {
+                                status.set(format!("uploading {}",key));
+                                match publish_asset(&config,&key,&asset).await {
+                                    Ok(url) => url,
+                                    Err(err) => {
+                                        status.set(err);
+                                        return;
+                                    },
+                                }
+                            },
+                            "base64" | "data-uri" => {
+                                let bytes = match load_asset(&asset.id).await {
+                                    Ok(Some(bytes)) => bytes,
+                                    Ok(None) => {
+                                        status.set(format!("{} is missing from the asset store",asset.uri()));
+                                        return;
+                                    },
+                                    Err(err) => {
+                                        status.set(err);
+                                        return;
+                                    },
+                                };
+                                let b64 = base64::engine::general_purpose::STANDARD.encode(&bytes);
+                                if mode == "data-uri" {
+                                    format!("data:{};base64,{}",asset.mime,b64)
+                                } else {
+                                    b64
+                                }
+                            },
+                            // The field names the form-data part the file is sent in.
+                            "multipart" => {
+                                attachments.write().push(PayloadAttachment{ path:path.clone(), asset:asset.clone() });
+                                path.clone()
+                            },
+                            _ => asset.uri(),
+                        };
+                        status.set("".to_string());
+                        set_payload_field(&mut map.write(),&path,Value::String(value));
+                    }
+                },
+                style:"width:10em;height:2em;",
+                "add to field"
+            }
+            span{
+                "{status}"
+            }
+        }
+    ))
+}
+
+fn S3Settings(cx:Scope) -> Element {
+    let s3 = use_shared_state::<S3Config>(cx).unwrap();
+    let config = use_state(cx, || s3.read().clone());
+    cx.render(rsx!{
+        div {
+            div {
+                "S3 Bucket"
+            }
+            input {
+                placeholder: "endpoint",
+                value: "{config.endpoint}",
+                oninput: move |evt| config.make_mut().endpoint = evt.value.clone(),
+            }
+            input {
+                placeholder: "bucket",
+                value: "{config.bucket}",
+                oninput: move |evt| config.make_mut().bucket = evt.value.clone(),
+            }
+            input {
+                placeholder: "region",
+                value: "{config.region}",
+                oninput: move |evt| config.make_mut().region = evt.value.clone(),
+            }
+            input {
+                placeholder: "access key",
+                value: "{config.access_key}",
+                oninput: move |evt| config.make_mut().access_key = evt.value.clone(),
+            }
+            input {
+                placeholder: "secret key",
+                r#type: "password",
+                value: "{config.secret_key}",
+                oninput: move |evt| config.make_mut().secret_key = evt.value.clone(),
+            }
+            input {
+                placeholder: "public base url",
+                value: "{config.public_base_url}",
+                oninput: move |evt| config.make_mut().public_base_url = evt.value.clone(),
+            }
+            span {
+                "path style"
+            }
+            input {
+                r#type:"checkbox",
+                checked: "{config.path_style}",
+                onchange: move |_| {
+                    let path_style = config.get().path_style;
+                    config.make_mut().path_style = !path_style;
+                },
+            }
+            button {
+                style: "width:3em;height:2em;",
+                onclick : move |_| *s3.write() = config.get().clone(),
+                "Set"
+            }
+        }
+    })
+}
+/// Turns the response cache on and off, sets how long entries are kept and clears it.
+fn CacheSettingsPanel(cx:Scope) -> Element {
+    let cache = use_shared_state::<CacheSettings>(cx).unwrap();
+    let ttl_hours = use_state(cx, || cache.read().ttl_hours.to_string());
+    let status = use_state(cx, || "".to_string());
+    cx.render(rsx!{
+        div {
+            div {
+                "Response Cache"
+            }
+            span {
+                "enabled"
+            }
+            input {
+                r#type:"checkbox",
+                checked: "{cache.read().enabled}",
+                onchange: move |_| {
+                    let enabled = cache.read().enabled;
+                    cache.write().enabled = !enabled;
+                },
+            }
+            span {
+                "hours to keep"
+            }
+            input {
+                style: "width:5em;",
+                value: "{ttl_hours}",
+                oninput: move |evt| {
+                    ttl_hours.set(evt.value.clone());
+                    if let Ok(hours) = evt.value.parse::<f64>() {
+                        cache.write().ttl_hours = hours;
+                    }
+                },
+            }
+            span {
+                "refresh"
+            }
+            input {
+                r#type:"checkbox",
+                checked: "{cache.read().refresh}",
+                onchange: move |_| {
+                    let refresh = cache.read().refresh;
+                    cache.write().refresh = !refresh;
+                },
+            }
+            button {
+                onclick: move |_| {
+                    to_owned![status];
+                    async move {
+                        match cache_clear().await {
+                            Ok(()) => status.set("cache cleared".to_string()),
+                            Err(err) => status.set(err),
+                        }
+                    }
+                },
+                "Clear cache"
+            }
+            span {
+                "{status}"
+            }
+        }
     })
 }
 /* 
@@ -304,387 +1404,2401 @@ fn ApiKey(cx:Scope<ApiKeyProps>) -> Element {
 }
 
 
-async fn fetch_chat_gpt(
-    model_response:UseSharedState<CompletionResponse>,
-    key:String,
-    model:String,
-    frequency_penalty:f32,
-    max_tokens:u32,
-    batch_size:u8,
-    presence_penalty:f32,
-    stop_sequence:Vec<String>,
-    temperature:f32,
-    top_p:f32,
-    system:String,
-    prompt:String,
-    ) {
-    let resp = reqwest::Client::new()
-        .post("https://api.openai.com/v1/chat/completions")
-        .header("Authorization",format!("Bearer {}",key))
+async fn fetch_chat_gpt(
+    model_response:UseSharedState<CompletionResponse>,
+    recorder:RunRecorder,
+    cache:CacheSettings,
+    key:String,
+    request:ChatCompletionRequest,
+    ) -> Result<CompletionResponse,String> {
+    let resp = complete_chat("chat_gpt",recorder,cache,key,request).await?;
+    *model_response.write() = resp.clone();
+    Ok(resp)
+}
+
+/// Sends a chat request through the cache and records it, without showing the response.
+/// A failed call is recorded too, with its error.
+async fn complete_chat(
+    provider:&str,
+    recorder:RunRecorder,
+    cache:CacheSettings,
+    key:String,
+    request:ChatCompletionRequest,
+    ) -> Result<CompletionResponse,String> {
+    let entry = cache_key(provider,&serde_json::to_value(&request).unwrap());
+    let cached = cache_get(&cache,&entry).await;
+    let prompt = request.messages.iter().map(|m| m.content.as_str()).collect::<Vec<&str>>().join("\n\n");
+    let body = match (&cached,recorder.cap_exceeded()) {
+        (Some(body),_) => Ok(body.clone()),
+        (None,Some(reason)) => Err(reason),
+        (None,None) => post_chat(&key,&request).await,
+    };
+    let resp = body.clone().and_then(|body| serde_json::from_slice::<CompletionResponse>(&body).map_err(|e| e.to_string()));
+    let (body,resp) = match (body,resp) {
+        (Ok(body),Ok(resp)) => (body,resp),
+        (Err(err),_) | (_,Err(err)) => {
+            let mut generation = Generation::new(provider,&request.model,&prompt);
+            generation.seed = request.seed;
+            return Err(recorder.failed(generation,err));
+        },
+    };
+    let mut generation = Generation::new(provider,&resp.model,&prompt);
+    generation.usage = Some(resp.usage.clone());
+    generation.seed = request.seed;
+    generation.system_fingerprint = resp.system_fingerprint.clone();
+    if cached.is_some() {
+        generation.cached = true;
+    } else {
+        cache_put(&cache,&entry,&body).await;
+        generation.consumption = Some(Consumption::Tokens{
+            prompt:resp.usage.prompt_tokens as u64,
+            completion:resp.usage.completion_tokens as u64,
+        });
+    }
+    generation.output = serde_json::Value::Array(
+        resp.message_choices.iter().map(|choice| serde_json::Value::String(choice.message.content.clone())).collect()
+    );
+    recorder.generation(generation);
+    Ok(resp)
+}
+
+async fn post_chat(key:&str, request:&ChatCompletionRequest) -> Result<Bytes,String> {
+    let resp = reqwest::Client::new()
+        .post("https://api.openai.com/v1/chat/completions")
+        .header("Authorization",format!("Bearer {}",key))
+        .json(request)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !resp.status().is_success() {
+        return Err(format!("ChatGPT failed with {}: {}",resp.status(),resp.text().await.unwrap_or_default()));
+    }
+    resp.bytes().await.map_err(|e| e.to_string())
+}
+
+/// Sends the conversation, then each follow-up turn after the first choice of the answer before it.
+/// Returns the last request and its response, or the error of the first call that failed.
+async fn run_chat(
+    model_response:UseSharedState<CompletionResponse>,
+    recorder:RunRecorder,
+    cache:CacheSettings,
+    key:String,
+    mut request:ChatCompletionRequest,
+    follow_ups:Vec<String>,
+    ) -> Result<(ChatCompletionRequest,CompletionResponse),String> {
+    let mut resp = fetch_chat_gpt(model_response.clone(),recorder.clone(),cache.clone(),key.clone(),request.clone()).await?;
+    for follow_up in follow_ups {
+        let Some(choice) = resp.message_choices.first() else {
+            break;
+        };
+        request.messages.push(choice.message.clone());
+        request.messages.push(ChatMessage{ role:Role::User, content:follow_up });
+        resp = fetch_chat_gpt(model_response.clone(),recorder.clone(),cache.clone(),key.clone(),request.clone()).await?;
+    }
+    Ok((request,resp))
+}
+
+/// Scores the choices of a response with the selected strategy and records the scores against the row.
+/// `rubric` is already rendered for the row.
+async fn select_choice(
+    recorder:RunRecorder,
+    cache:CacheSettings,
+    key:String,
+    settings:&SelectionSettings,
+    rubric:&str,
+    request:&ChatCompletionRequest,
+    resp:&CompletionResponse,
+    ) -> Result<Selection,String> {
+    let choices = &resp.message_choices;
+    let scores = match settings.strategy {
+        SelectionStrategy::Manual => vec![],
+        SelectionStrategy::Heuristics => heuristic_scores(&settings.rules,choices)?,
+        SelectionStrategy::Judge => {
+            let task = request.messages.iter().rev()
+                .find(|m| m.role == Role::User)
+                .map(|m| m.content.as_str())
+                .unwrap_or_default();
+            let judgement = complete_chat("chat_gpt_judge",recorder.clone(),cache,key,ChatCompletionRequest{
+                model:settings.judge_model.clone(),
+                messages:judge_messages(rubric,task,choices),
+                frequency_penalty:0.,
+                max_tokens:JUDGE_MAX_TOKENS,
+                n:1,
+                presence_penalty:0.,
+                stop:vec![],
+                temperature:0.,
+                top_p:1.,
+                seed:None,
+                logit_bias:BTreeMap::new(),
+                logprobs:false,
+                top_logprobs:None,
+                user:request.user.clone(),
+                response_format:Some(ResponseFormat::JsonObject),
+            }).await?;
+            let reply = judgement.message_choices.first().map(|c| c.message.content.as_str()).unwrap_or_default();
+            parse_judge_scores(reply,choices)?
+        },
+    };
+    let selection = Selection::new(settings.strategy,&settings.field,scores,choices);
+    recorder.selection(selection.clone());
+    Ok(selection)
+}
+
+
+fn ChatGpt(cx:Scope) -> Element {
+    use_shared_state_provider(cx, || CompletionResponse::default());
+    use_shared_state_provider::<Vec<Transform>>(cx, Vec::new);
+    let transforms = use_shared_state::<Vec<Transform>>(cx).unwrap();
+    let app_state = use_shared_state::<AppState>(cx).unwrap();
+    let model_resp = use_shared_state::<CompletionResponse>(cx).unwrap();
+    let run_log = use_shared_state::<RunLog>(cx).unwrap();
+    let ledger = use_shared_state::<UsageLedger>(cx).unwrap();
+    let keys = use_shared_state::<ApiKeys>(cx).unwrap();
+    let cache = use_shared_state::<CacheSettings>(cx).unwrap();
+    let batch_size = use_state(cx, || 1);
+    let temperature = use_state(cx, || 1.);
+    let max_tokens = use_state(cx, || app_state.read().chat_gpt_max_tokens);
+    let stop_sequence: &UseState<Vec<String>> = use_state(cx, || vec![]);
+    let top_p = use_state(cx, || 1.);
+    let frequency_penalty = use_state(cx, || 0.);
+    let presence_penalty = use_state(cx, || 0.);
+    let model = use_state(cx, || app_state.read().chat_gpt_model.clone());
+    let sequence = use_state(cx, || "".to_string());
+    let dataset = use_shared_state::<Dataset>(cx).unwrap();
+    let view = use_shared_state::<DatasetView>(cx).unwrap();
+    let budget = use_shared_state::<Budget>(cx).unwrap();
+    let estimate = use_state(cx, || None::<CostEstimate>);
+    let approver = use_state(cx, || "".to_string());
+    let batch_status = use_state(cx, || "".to_string());
+    let threshold_input = use_state(cx, || budget.read().sign_off_threshold.to_string());
+    let batch_stop = use_ref(cx, || false);
+    // Where a stopped or paused batch picks up, for the settings it was started with.
+    let batch_resume: &UseRef<Option<(u64,usize)>> = use_ref(cx, || None);
+    // Two batches over the same rows would pay for every row twice.
+    let batch_running = use_state(cx, || false);
+    let examples = use_state(cx, Vec::<FewShotExample>::new);
+    let examples_error = use_state(cx, || "".to_string());
+    let follow_ups = use_state(cx, Vec::<String>::new);
+    let seed = use_state(cx, || "".to_string());
+    let logit_bias = use_state(cx, BTreeMap::<String,i32>::new);
+    let bias_token = use_state(cx, || "".to_string());
+    let bias_value = use_state(cx, || "".to_string());
+    let logprobs = use_state(cx, || false);
+    let top_logprobs = use_state(cx, || 0);
+    let user = use_state(cx, || "".to_string());
+    let response_format = use_state(cx, || None::<ResponseFormat>);
+    let payload = use_shared_state::<serde_json::Map<String,serde_json::Value>>(cx).unwrap();
+    let attachments = use_shared_state::<Vec<PayloadAttachment>>(cx).unwrap();
+    let selection = use_state(cx, SelectionSettings::default);
+    let last_selection = use_state(cx, || None::<Selection>);
+    let selection_error = use_state(cx, || "".to_string());
+    let chat_error = use_state(cx, || "".to_string());
+    let models = use_future(cx, &keys.read().open_ai,
+    |key| async move {
+        if key.is_empty() {
+            None
+        } else {
+            Some(fetch_models(key).await)
+        }
+    });
+    let mut model_options = match models.value() {
+        Some(Some(Ok(models))) => models.clone(),
+        _ => vec![],
+    };
+    if !model_options.contains(model.get()) {
+        model_options.insert(0,model.get().clone());
+    }
+    let models_note = match models.value() {
+        None => "Loading models".to_string(),
+        Some(None) => "Set your OpenAI Key to list all models".to_string(),
+        Some(Some(Err(err))) => format!("Couldn't list the models: {}",err),
+        Some(Some(Ok(_))) => "".to_string(),
+    };
+    let limits_text = match model_limits(model.get()) {
+        Some(limits) => format!("{} token context, at most {} generated",limits.context_window,limits.max_output),
+        None => "Limits unknown, requests aren't checked".to_string(),
+    };
+    // The last request of the conversation is the longest.
+    let prompt_tokens = conversation_prompt_tokens(&app_state.read(),model.get(),examples.get(),follow_ups.get(),*max_tokens.get(),None)
+        .last()
+        .copied()
+        .unwrap_or_default();
+    let max_tokens_error = check_max_tokens(model.get(),prompt_tokens,*max_tokens.get());
+    let can_submit = max_tokens_error.is_none();
+    let max_tokens_error = max_tokens_error.unwrap_or_default();
+    let batch_rows = view.read().selected_or_filtered(&dataset.read());
+    // What the estimate depends on, it has to be redone when any of it changes.
+    let fingerprint = {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        // Row indices alone would match the rows of a re-uploaded file too.
+        (model.get(),max_tokens.get(),batch_size.get(),&batch_rows,dataset.read().uploaded_at).hash(&mut hasher);
+        (&app_state.read().chat_gpt_system_raw,&app_state.read().chat_gpt_prompt_raw).hash(&mut hasher);
+        (examples.get(),follow_ups.get()).hash(&mut hasher);
+        (selection.strategy == SelectionStrategy::Judge,&selection.judge_model,&selection.rubric).hash(&mut hasher);
+        hasher.finish()
+    };
+    let chat_request = move |messages:Vec<ChatMessage>| ChatCompletionRequest{
+        model:model.current().as_ref().clone(),
+        messages,
+        frequency_penalty:*frequency_penalty.get(),
+        max_tokens:*max_tokens.get(),
+        n:*batch_size.get(),
+        presence_penalty:*presence_penalty.get(),
+        stop:stop_sequence.current().as_ref().clone(),
+        temperature:*temperature.get(),
+        top_p:*top_p.get(),
+        seed:seed.get().trim().parse::<i64>().ok(),
+        logit_bias:logit_bias.get().clone(),
+        logprobs:*logprobs.get(),
+        top_logprobs:logprobs.get().then_some(*top_logprobs.get()).filter(|n| *n > 0),
+        user:user.get().trim().to_string(),
+        response_format:*response_format.get(),
+    };
+    let current_estimate = estimate.get().as_ref().filter(|e| e.fingerprint == fingerprint);
+    let threshold = budget.read().sign_off_threshold;
+    // Models missing from the price table always need a sign-off.
+    let needs_sign_off = current_estimate.is_some_and(|e| e.cost.is_none_or(|cost| cost > threshold));
+    let estimated_cost = current_estimate.and_then(|e| e.cost);
+    let batch_limit_error = current_estimate.and_then(|e| check_max_tokens(model.get(),e.largest_prompt,*max_tokens.get()));
+    let can_start = current_estimate.is_some()
+        && batch_limit_error.is_none()
+        && (!needs_sign_off || !approver.get().trim().is_empty());
+    let batch_limit_error = batch_limit_error.map(|e| format!("Some rows won't fit: {}",e)).unwrap_or_default();
+    let resume_at = batch_resume.read().filter(|(f,_)| *f == fingerprint).map(|(_,at)| at);
+    let batch_label = if resume_at.is_some() { "Resume batch" } else { "Run batch" };
+    let estimate_text = match current_estimate {
+        Some(e) if e.judge_completion_tokens > 0 => format!(
+            "This run will cost ≈{} ({} rows, {} prompt tokens, up to {} completion tokens, judging {} prompt tokens, up to {} completion tokens)",
+            e.cost.map(format_usd).unwrap_or("an unknown amount".to_string()),
+            e.rows,
+            e.prompt_tokens,
+            e.completion_tokens,
+            e.judge_prompt_tokens,
+            e.judge_completion_tokens,
+        ),
+        Some(e) => format!(
+            "This run will cost ≈{} ({} rows, {} prompt tokens, up to {} completion tokens)",
+            e.cost.map(format_usd).unwrap_or("an unknown amount".to_string()),
+            e.rows,
+            e.prompt_tokens,
+            e.completion_tokens,
+        ),
+        None if estimate.get().is_some() => "The settings changed since the last estimate".to_string(),
+        None => "".to_string(),
+    };
+    let usage = model_resp.read().usage.clone();
+    let usage_cost = chat_cost(&model_resp.read().model,usage.prompt_tokens as u64,usage.completion_tokens as u64)
+        .map(format_usd)
+        .unwrap_or_default();
+    let mut judge_models = model_options.clone();
+    if !judge_models.contains(&selection.judge_model) {
+        judge_models.insert(0,selection.judge_model.clone());
+    }
+    let min_chars = selection.rules.min_chars.map(|n| n.to_string()).unwrap_or_default();
+    let max_chars = selection.rules.max_chars.map(|n| n.to_string()).unwrap_or_default();
+    let selection_rows : Vec<(String,String,String,String)> = last_selection.get().iter()
+        .flat_map(|s| s.scores.iter().map(|score| (
+            format!("{}{}",score.index + 1,if s.chosen == Some(score.index) { " (picked)" } else { "" }),
+            format!("{:.2}",score.score),
+            if score.qualified { "yes" } else { "no" }.to_string(),
+            score.reason.clone(),
+        )))
+        .collect();
+    let system_fingerprint = model_resp.read().system_fingerprint.clone()
+        .map(|f| format!("system fingerprint {}",f))
+        .unwrap_or_default();
+    let mut stop_sequence_rendered = vec![];
+    for seq in stop_sequence.current()
+        .iter() {
+        let seq = seq.clone();
+        stop_sequence_rendered.push(
+            rsx!{
+                button {
+                    onclick : move |evt| {
+                        let current = stop_sequence.current().iter().filter_map(|s| if s != &seq{
+                            Some(s.clone())
+                        } else {
+                            None
+                        }).collect();
+                        stop_sequence.set(current);
+                    },
+                    "X {seq}"
+                }
+            }
+        )
+    }
+    cx.render(
+
+        rsx!{
+            h3{"ChatGPT"}
+
+            div {
+                p {
+                    "System"
+                }
+                textarea {
+                    value: "{app_state.read().chat_gpt_system_raw}",
+                    oninput: move |evt| app_state.write().update_field(AppStateFieldUpdate::ChatGPTSystem(evt.value.clone())),
+                },
+                p {
+                    "{app_state.read().chat_gpt_system_edited}"
+                }
+            },
+            div {
+                 p {
+                    "Prompt"
+                }
+                textarea {
+                    value: "{app_state.read().chat_gpt_prompt_raw}",
+                    oninput: move |evt| app_state.write().update_field(AppStateFieldUpdate::ChatGPTPrompt(evt.value.clone())),
+                },
+                p {
+                    "{app_state.read().chat_gpt_prompt_edited}"
+                }
+            },
+            div {
+                p {
+                    "Few-shot Examples"
+                }
+                for (i,example) in examples.get().iter().enumerate() {
+                    div {
+                        textarea {
+                            placeholder: "user",
+                            value: "{example.user}",
+                            oninput: move |evt| examples.make_mut()[i].user = evt.value.clone(),
+                        }
+                        textarea {
+                            placeholder: "assistant",
+                            value: "{example.assistant}",
+                            oninput: move |evt| examples.make_mut()[i].assistant = evt.value.clone(),
+                        }
+                        button {
+                            onclick: move |_| {
+                                examples.make_mut().remove(i);
+                            },
+                            "X"
+                        }
+                    }
+                }
+                button {
+                    onclick: move |_| examples.make_mut().push(FewShotExample::default()),
+                    "Add example"
+                }
+                span {
+                    "From CSV"
+                }
+                input {
+                    r#type:"file",
+                    accept: ".csv,.tsv,.json,.jsonl,.xlsx",
+                    onchange: |evt| {
+                        to_owned![examples,examples_error];
+                        async move {
+                            let Some(file_engine) = &evt.files else {
+                                return;
+                            };
+                            for file_name in file_engine.files() {
+                                if let Some(file) = file_engine.read_file(&file_name).await {
+                                    match read_dataset(&file_name,&file,&ReaderOptions::default(),|_| ()).await {
+                                        Ok(read) => {
+                                            examples.make_mut().extend(FewShotExample::from_dataset(&read));
+                                            examples_error.set("".to_string());
+                                        },
+                                        Err(err) => examples_error.set(format!("{}: {}",file_name,err)),
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+                p {
+                    "{examples_error}"
+                }
+            },
+            div {
+                p {
+                    "Follow-up Turns"
+                }
+                for (i,follow_up) in follow_ups.get().iter().enumerate() {
+                    div {
+                        input {
+                            placeholder: "now shorten it",
+                            value: "{follow_up}",
+                            oninput: move |evt| follow_ups.make_mut()[i] = evt.value.clone(),
+                        }
+                        button {
+                            onclick: move |_| {
+                                follow_ups.make_mut().remove(i);
+                            },
+                            "X"
+                        }
+                    }
+                }
+                button {
+                    onclick: move |_| follow_ups.make_mut().push(String::new()),
+                    "Add follow-up"
+                }
+            },
+            
+           div {
+            p {
+                "Model"
+            }
+            select {
+                onchange: move |evt| {
+                    if let Some(limits) = model_limits(&evt.value) {
+                        max_tokens.set((*max_tokens.get()).min(limits.max_output));
+                        let mut app_state = app_state.write();
+                        app_state.chat_gpt_max_tokens = app_state.chat_gpt_max_tokens.min(limits.max_output);
+                    }
+                    model.set(evt.value.clone());
+                    app_state.write().chat_gpt_model = evt.value.clone();
+                },
+                for id in model_options.iter() {
+                    option {
+                        value: "{id}",
+                        selected: id == model.get(),
+                        "{id}"
+                    }
+                }
+            },
+            p {
+                "{limits_text}"
+            }
+            p {
+                "{models_note}"
+            }
+           }
+        div {
+            p {
+               "Temperature"
+           }
+           input {
+               value: "{temperature}",
+               oninput: move |evt| temperature.set(evt.value.clone().parse::<f32>().unwrap_or_default().max(0.).min(2.)),
+           },
+        }
+        div {
+            p {
+               "Max Tokens"
+           }
+           input {
+               value: "{max_tokens}",
+               oninput: move |evt| {
+                   let max_output = model_limits(model.get()).map_or(u32::MAX,|limits| limits.max_output);
+                   let value = evt.value.clone().parse::<u32>().unwrap_or_default().min(max_output);
+                   max_tokens.set(value);
+                   app_state.write().chat_gpt_max_tokens = value;
+               },
+           },
+           p {
+               "{prompt_tokens} prompt tokens"
+           }
+           p {
+               style: "color:red;",
+               "{max_tokens_error}"
+           }
+        }
+        div {
+            p {
+                "Stop Sequence"
+            }
+            form {
+                onsubmit : move |evt| {
+                        let mut current : Vec<String> = stop_sequence.current().clone().iter().map(|s|s.clone()).collect();
+                        current.push(sequence.current().as_ref().clone());
+                        stop_sequence.set(current);
+                        sequence.set("".to_string()) 
+                    },
+                input { 
+                    value:"{sequence}",
+                    oninput: move |evt| sequence.set(evt.value.clone()),
+                },
+                input { r#type: "submit", value:"Add" },
+            }
+            {stop_sequence_rendered.into_iter()}
+        }
+        div {
+            p {
+               "Top P"
+           }
+           input {
+               value: "{top_p}",
+               oninput: move |evt| top_p.set(evt.value.clone().parse::<f32>().unwrap_or_default().max(0.).min(1.)),
+           },
+        }
+        div {
+            p {
+               "Frequency Penalty"
+           }
+           input {
+               value: "{frequency_penalty}",
+               oninput: move |evt| frequency_penalty.set(evt.value.clone().parse::<f32>().unwrap_or_default().max(0.).min(2.)),
+           },
+        }
+        div {
+            p {
+               "Presence Penalty"
+           }
+           input {
+               value: "{presence_penalty}",
+               oninput: move |evt| presence_penalty.set(evt.value.clone().parse::<f32>().unwrap_or_default().max(-2.).min(2.)),
+           },
+        }
+        div {
+            p {
+               "Seed"
+           }
+           input {
+               placeholder: "random",
+               value: "{seed}",
+               oninput: move |evt| seed.set(evt.value.clone()),
+           },
+        }
+        div {
+            p {
+                "Logit Bias"
+            }
+            // A token id, or text whose tokens all get the bias
+            form {
+                onsubmit: move |_| {
+                    let bias = bias_value.get().trim().parse::<i32>().unwrap_or_default().clamp(-100,100);
+                    let ids = match bias_token.get().trim().parse::<usize>() {
+                        Ok(id) => vec![id],
+                        Err(_) => token_ids(model.get(),bias_token.get()),
+                    };
+                    let mut current = logit_bias.get().clone();
+                    for id in ids {
+                        current.insert(id.to_string(),bias);
+                    }
+                    logit_bias.set(current);
+                    bias_token.set("".to_string());
+                },
+                input {
+                    placeholder: "token id or text",
+                    value: "{bias_token}",
+                    oninput: move |evt| bias_token.set(evt.value.clone()),
+                },
+                input {
+                    style: "width:4em;",
+                    placeholder: "-100 to 100",
+                    value: "{bias_value}",
+                    oninput: move |evt| bias_value.set(evt.value.clone()),
+                },
+                input { r#type: "submit", value:"Add" },
+            }
+            for (id,bias) in logit_bias.get().clone().into_iter() {
+                button {
+                    onclick: move |_| {
+                        logit_bias.make_mut().remove(&id);
+                    },
+                    "X {id}: {bias}"
+                }
+            }
+        }
+        div {
+            p {
+                "Logprobs"
+            }
+            input {
+                r#type: "checkbox",
+                checked: "{logprobs}",
+                onclick: move |_| logprobs.set(!*logprobs.get()),
+            }
+            span {
+                "Top alternatives"
+            }
+            input {
+                style: "width:3em;",
+                disabled: !*logprobs.get(),
+                value: "{top_logprobs}",
+                oninput: move |evt| top_logprobs.set(evt.value.clone().parse::<u8>().unwrap_or_default().min(20)),
+            },
+        }
+        div {
+            p {
+               "User"
+           }
+           input {
+               value: "{user}",
+               oninput: move |evt| user.set(evt.value.clone()),
+           },
+        }
+        div {
+            p {
+                "Response Format"
+            }
+            select {
+                onchange: move |evt| response_format.set(match evt.value.as_str() {
+                    "text" => Some(ResponseFormat::Text),
+                    "json_object" => Some(ResponseFormat::JsonObject),
+                    _ => None,
+                }),
+                option {
+                    value: "",
+                    "default"
+                },
+                option {
+                    value: "text",
+                    "text"
+                },
+                option {
+                    value: "json_object",
+                    "JSON object"
+                },
+            }
+        }
+        div {
+            p {
+               "Batch Size"
+           }
+           input {
+               value: "{batch_size}",
+               oninput: move |evt| batch_size.set(evt.value.clone().parse::<u8>().unwrap_or_default()),
+           },
+       }
+       div {
+        h5 {"Best of n"}
+        select {
+            onchange: move |evt| selection.make_mut().strategy = match evt.value.as_str() {
+                "heuristics" => SelectionStrategy::Heuristics,
+                "judge" => SelectionStrategy::Judge,
+                _ => SelectionStrategy::Manual,
+            },
+            option {
+                value: "manual",
+                "Pick by hand"
+            },
+            option {
+                value: "heuristics",
+                "Heuristics"
+            },
+            option {
+                value: "judge",
+                "Judge"
+            },
+        }
+        span {
+            "Payload field"
+        }
+        input {
+            value: "{selection.field}",
+            oninput: move |evt| selection.make_mut().field = evt.value.clone(),
+        }
+        if selection.strategy == SelectionStrategy::Heuristics {
+            rsx!(
+                div {
+                    span {
+                        "Chars from"
+                    }
+                    input {
+                        style: "width:5em;",
+                        value: "{min_chars}",
+                        oninput: move |evt| selection.make_mut().rules.min_chars = evt.value.trim().parse().ok(),
+                    }
+                    span {
+                        "to"
+                    }
+                    input {
+                        style: "width:5em;",
+                        value: "{max_chars}",
+                        oninput: move |evt| selection.make_mut().rules.max_chars = evt.value.trim().parse().ok(),
+                    }
+                }
+                div {
+                    span {
+                        "Required keywords"
+                    }
+                    input {
+                        placeholder: "comma separated",
+                        value: "{selection.rules.keywords}",
+                        oninput: move |evt| selection.make_mut().rules.keywords = evt.value.clone(),
+                    }
+                }
+                div {
+                    span {
+                        "Must match"
+                    }
+                    input {
+                        placeholder: "regex",
+                        value: "{selection.rules.pattern}",
+                        oninput: move |evt| selection.make_mut().rules.pattern = evt.value.clone(),
+                    }
+                }
+            )
+        }
+        if selection.strategy == SelectionStrategy::Judge {
+            rsx!(
+                div {
+                    span {
+                        "Judge model"
+                    }
+                    select {
+                        onchange: move |evt| selection.make_mut().judge_model = evt.value.clone(),
+                        for id in judge_models.iter() {
+                            option {
+                                value: "{id}",
+                                selected: *id == selection.judge_model,
+                                "{id}"
+                            }
+                        }
+                    }
+                }
+                div {
+                    p {
+                        "Rubric"
+                    }
+                    textarea {
+                        value: "{selection.rubric}",
+                        oninput: move |evt| selection.make_mut().rubric = evt.value.clone(),
+                    }
+                }
+            )
+        }
+        p {
+            style: "color:red;",
+            "{selection_error}"
+        }
+       }
+       div {
+        button{
+            style: "width:6em;height:2em;",
+            disabled: !can_submit,
+            onclick: move |_| {
+                let recorder = RunRecorder::new(run_log,ledger,&app_state.read());
+                let cache = cache.read().clone();
+                let key = (*keys).read().open_ai.clone();
+                let request = chat_request(app_state.read().chat_messages(examples.get(),None));
+                let follow_ups : Vec<String> = follow_ups.get().iter().map(|f| app_state.read().render(f)).collect();
+                let settings = selection.get().clone();
+                let rubric = app_state.read().render(&settings.rubric);
+                let transforms = transforms.read().clone();
+                to_owned![model_resp,payload,attachments,last_selection,selection_error,chat_error];
+                async move {
+                    chat_error.set("".to_string());
+                    let (request,resp) = match run_chat(model_resp,recorder.clone(),cache.clone(),key.clone(),request,follow_ups).await {
+                        Ok(sent) => sent,
+                        Err(err) => {
+                            chat_error.set(err);
+                            return;
+                        },
+                    };
+                    if settings.strategy == SelectionStrategy::Manual {
+                        return;
+                    }
+                    match select_choice(recorder,cache,key,&settings,&rubric,&request,&resp).await {
+                        Ok(selected) => {
+                            let mut error = "".to_string();
+                            if let Some(content) = selected.chosen_content(&resp.message_choices).filter(|_| !settings.field.is_empty()) {
+                                match apply_transforms(&transforms,content) {
+                                    Ok(value) => {
+                                        attachments.write().retain(|a| !a.replaced_by(&settings.field));
+                                        set_payload_field(&mut payload.write(),&settings.field,value);
+                                    },
+                                    Err(err) => error = err,
+                                }
+                            }
+                            last_selection.set(Some(selected));
+                            selection_error.set(error);
+                        },
+                        Err(err) => selection_error.set(err),
+                    }
+                }
+            },
+            "Submit"
+        }
+        p {
+            style: "color:red;",
+            "{chat_error}"
+        }
+       }
+       div {
+        h5 {"Batch"}
+        p {
+            "Runs the prompt for {batch_rows.len()} rows, the selected ones or else those matching the search and filters"
+        }
+        button {
+            onclick: move |_| {
+                let rows = view.read().selected_or_filtered(&dataset.read());
+                let app_state = app_state.read().clone();
+                let dataset = dataset.read().clone();
+                let settings = ChatBatchSettings{
+                    model:model.current().as_ref().clone(),
+                    examples:examples.get().clone(),
+                    follow_ups:follow_ups.get().clone(),
+                    max_tokens:*max_tokens.get(),
+                    n:*batch_size.get(),
+                    judge:Some(selection.get().clone()).filter(|s| s.strategy == SelectionStrategy::Judge),
+                };
+                to_owned![estimate,batch_status];
+                async move {
+                    batch_status.set("counting tokens".to_string());
+                    estimate.set(Some(estimate_chat_batch(&app_state,&dataset,&rows,&settings,fingerprint).await));
+                    batch_status.set("".to_string());
+                }
+            },
+            "Estimate cost"
+        }
+        p {
+            "{estimate_text}"
+        }
+        p {
+            style: "color:red;",
+            "{batch_limit_error}"
+        }
+        div {
+            span {
+                "Sign-off above $"
+            }
+            input {
+                style: "width:5em;",
+                value: "{threshold_input}",
+                oninput: move |evt| {
+                    threshold_input.set(evt.value.clone());
+                    if let Ok(threshold) = evt.value.parse::<f64>() {
+                        budget.write().sign_off_threshold = threshold;
+                    }
+                },
+            }
+        }
+        if needs_sign_off {
+            rsx!{
+                div {
+                    span {
+                        "Approved by"
+                    }
+                    input {
+                        value: "{approver}",
+                        oninput: move |evt| approver.set(evt.value.clone()),
+                    }
+                }
+            }
+        }
+        button {
+            disabled: !can_start || *batch_running.get(),
+            onclick: move |_| {
+                let rows = view.read().selected_or_filtered(&dataset.read());
+                // Picks for the row being shown go to its editor too, leaving the row records what's there.
+                let shown = app_state.clone();
+                let app_state = app_state.read().clone();
+                let dataset = dataset.read().clone();
+                let key = (*keys).read().open_ai.clone();
+                let cache = cache.read().clone();
+                let examples = examples.get().clone();
+                let follow_ups = follow_ups.get().clone();
+                let request = chat_request(vec![]);
+                let settings = selection.get().clone();
+                let transforms = transforms.read().clone();
+                *batch_stop.write() = false;
+                batch_running.set(true);
+                run_log.write().batches.push(BatchRun{
+                    model:request.model.clone(),
+                    rows:rows.len(),
+                    resumed_at:resume_at,
+                    estimated_cost,
+                    sign_off_threshold:threshold,
+                    approved_by:needs_sign_off.then(|| approver.get().trim().to_string()),
+                    timestamp:chrono::Utc::now().to_rfc3339(),
+                });
+                to_owned![model_resp,run_log,ledger,payload,attachments,batch_status,batch_stop,batch_resume,batch_running,last_selection,selection_error];
+                async move {
+                    // Failed rows are recorded in the run log with their error, the batch carries on.
+                    let mut failed = 0;
+                    for (done,&i) in rows.iter().enumerate().skip(resume_at.unwrap_or_default()) {
+                        if *batch_stop.read() {
+                            batch_status.set(format!("stopped after {} of {} rows",done,rows.len()));
+                            *batch_resume.write() = Some((fingerprint,done));
+                            batch_running.set(false);
+                            return;
+                        }
+                        if let Some(reason) = ledger.read().cap_exceeded() {
+                            batch_status.set(format!("paused after {} of {} rows, {}",done,rows.len(),reason));
+                            *batch_resume.write() = Some((fingerprint,done));
+                            batch_running.set(false);
+                            return;
+                        }
+                        batch_status.set(format!("row {} ({} of {})",i+1,done+1,rows.len()));
+                        let recorder = RunRecorder::for_row(&run_log,&ledger,&dataset,i);
+                        let sent = run_chat(
+                            model_resp.clone(),
+                            recorder.clone(),
+                            cache.clone(),
+                            key.clone(),
+                            ChatCompletionRequest{
+                                messages:app_state.chat_messages(&examples,Some((&dataset,i))),
+                                ..request.clone()
+                            },
+                            follow_ups.iter().map(|f| app_state.render_row(f,&dataset,i)).collect(),
+                        ).await;
+                        let Ok((sent,resp)) = sent else {
+                            failed += 1;
+                            continue;
+                        };
+                        if settings.strategy != SelectionStrategy::Manual {
+                            let rubric = app_state.render_row(&settings.rubric,&dataset,i);
+                            match select_choice(recorder.clone(),cache.clone(),key.clone(),&settings,&rubric,&sent,&resp).await {
+                                Ok(selected) => {
+                                    if let Some(content) = selected.chosen_content(&resp.message_choices).filter(|_| !settings.field.is_empty()) {
+                                        match apply_transforms(&transforms,content) {
+                                            Ok(value) => {
+                                                if shown.read().current_row == Some(i) {
+                                                    attachments.write().retain(|a| !a.replaced_by(&settings.field));
+                                                    set_payload_field(&mut payload.write(),&settings.field,value.clone());
+                                                }
+                                                recorder.payload_field(&settings.field,value);
+                                            },
+                                            Err(err) => selection_error.set(format!("row {}: {}",i+1,err)),
+                                        }
+                                    }
+                                    last_selection.set(Some(selected));
+                                },
+                                Err(err) => selection_error.set(format!("row {}: {}",i+1,err)),
+                            }
+                        }
+                    }
+                    batch_status.set(match failed {
+                        0 => format!("finished {} rows",rows.len()),
+                        failed => format!("finished {} rows, {} failed, their errors are in the run log",rows.len(),failed),
+                    });
+                    *batch_resume.write() = None;
+                    batch_running.set(false);
+                }
+            },
+            "{batch_label}"
+        }
+        button {
+            onclick: move |_| *batch_stop.write() = true,
+            "Stop"
+        }
+        p {
+            "{batch_status}"
+        }
+        UsageSummary{}
+       }
+       TransformChainEditor{}
+       div {
+        if (*model_resp.read()) != CompletionResponse::default() {
+            rsx!(
+                p {
+                    "{usage.prompt_tokens} prompt + {usage.completion_tokens} completion tokens {usage_cost}"
+                }
+                p {
+                    "{system_fingerprint}"
+                }
+                if !selection_rows.is_empty() {
+                    rsx!(
+                        table {
+                            tr {
+                                th {"Choice"}
+                                th {"Score"}
+                                th {"Qualified"}
+                                th {"Reason"}
+                            }
+                            for (choice,score,qualified,reason) in selection_rows.iter() {
+                                tr {
+                                    td {"{choice}"}
+                                    td {"{score}"}
+                                    td {"{qualified}"}
+                                    td {"{reason}"}
+                                }
+                            }
+                        }
+                    )
+                }
+                MessageChoices{
+                    choices:(*model_resp.read()).message_choices.clone()
+                }
+            )
+        }
+       }
+        }
+    )
+}
+
+
+/// Spend per day, project and model, and the caps past which no billed call is sent.
+fn UsageSummary(cx:Scope) -> Element {
+    let ledger = use_shared_state::<UsageLedger>(cx).unwrap();
+    // What's typed is only committed to the ledger, and saved, once the field is left.
+    let project = use_state(cx, || ledger.read().project.clone());
+    let daily_cap = use_state(cx, || ledger.read().daily_cap.map(|cap| cap.to_string()).unwrap_or_default());
+    let project_cap = use_state(cx, || ledger.read().project_cap.map(|cap| cap.to_string()).unwrap_or_default());
+    let mut entries = ledger.read().entries.clone();
+    entries.sort_by(|a,b| b.day.cmp(&a.day).then(a.project.cmp(&b.project)).then(a.model.cmp(&b.model)));
+    let spent_today = format_usd(ledger.read().spent(Some(&chrono::Local::now().format("%Y-%m-%d").to_string())));
+    let spent_total = format_usd(ledger.read().spent(None));
+    let cap_exceeded = ledger.read().cap_exceeded().unwrap_or_default();
+    let update = move |f:&dyn Fn(&mut UsageLedger)| {
+        let mut ledger = ledger.write();
+        f(&mut ledger);
+        if let Err(err) = ledger.save() {
+            log::error!("couldn't save usage: {}",err);
+        }
+    };
+    cx.render(rsx!{
+        div {
+            h5 {"Usage"}
+            div {
+                span {
+                    "Project"
+                }
+                input {
+                    value: "{project}",
+                    oninput: move |evt| project.set(evt.value.clone()),
+                    onchange: move |evt| update(&|l| l.project = evt.value.clone()),
+                }
+                span {
+                    "Daily cap $"
+                }
+                input {
+                    style: "width:5em;",
+                    value: "{daily_cap}",
+                    oninput: move |evt| daily_cap.set(evt.value.clone()),
+                    onchange: move |evt| update(&|l| l.daily_cap = evt.value.parse::<f64>().ok()),
+                }
+                span {
+                    "Project cap $"
+                }
+                input {
+                    style: "width:5em;",
+                    value: "{project_cap}",
+                    oninput: move |evt| project_cap.set(evt.value.clone()),
+                    onchange: move |evt| update(&|l| l.project_cap = evt.value.parse::<f64>().ok()),
+                }
+            }
+            p {
+                "{spent_today} spent today, {spent_total} on the project"
+            }
+            p {
+                style: "color:red;",
+                "{cap_exceeded}"
+            }
+            table {
+                tr {
+                    th {"Day"}
+                    th {"Project"}
+                    th {"Model"}
+                    th {"Prompt tokens"}
+                    th {"Completion tokens"}
+                    th {"Images"}
+                    th {"Characters"}
+                    th {"Audio seconds"}
+                    th {"Cost"}
+                }
+                for entry in entries.into_iter() {
+                    tr {
+                        td {"{entry.day}"}
+                        td {"{entry.project}"}
+                        td {"{entry.model}"}
+                        td {"{entry.prompt_tokens}"}
+                        td {"{entry.completion_tokens}"}
+                        td {"{entry.images}"}
+                        td {"{entry.characters}"}
+                        td {"{entry.audio_seconds:.0}"}
+                        td {"{format_usd(entry.cost)}"}
+                    }
+                }
+            }
+            button {
+                onclick: move |_| update(&|l| l.entries.clear()),
+                "Clear usage"
+            }
+        }
+    })
+}
+
+/// A choice's confidence and its tokens as `(token, style, title)`.
+type HighlightedChoice = (String,Vec<(String,String,String)>);
+
+fn MessageChoices(cx:Scope<MessageChoicesProps>) -> Element {
+    let transforms = use_shared_state::<Vec<Transform>>(cx).unwrap();
+    let min_confidence = use_state(cx, || "".to_string());
+    let threshold = min_confidence.get().trim().parse::<f64>().ok().map(|percent| percent / 100.);
+    let has_logprobs = cx.props.choices.iter().any(|c| c.confidence().is_some());
+    let shown : Vec<&MessageChoice> = cx.props.choices.iter()
+        .filter(|c| threshold.is_none_or(|t| c.confidence().is_none_or(|confidence| confidence >= t)))
+        .collect();
+    let hidden = cx.props.choices.len() - shown.len();
+    // Each token tinted from red for unlikely through to green for certain, with its alternatives on hover
+    let highlighted : Vec<Option<HighlightedChoice>> = shown.iter()
+        .map(|choice| {
+            let tokens = choice.logprobs.as_ref()?.content.as_ref()?;
+            Some((
+                format!("confidence {:.1}%",choice.confidence().unwrap_or_default() * 100.),
+                tokens.iter().map(|token| (
+                    token.token.clone(),
+                    format!("background-color:hsl({:.0},70%,85%);",token.probability() * 120.),
+                    token_title(token),
+                )).collect(),
+            ))
+        })
+        .collect();
+    // What each choice becomes in the payload once it's gone through the transforms
+    let outputs : Vec<(Option<serde_json::Value>,String,String)> = shown.iter()
+        .map(|choice| match apply_transforms(&transforms.read(),&choice.message.content) {
+            Ok(value) if transforms.read().is_empty() => (Some(value),"".to_string(),"".to_string()),
+            Ok(value) => {
+                let preview = format!("→ {}",serde_json::to_string(&value).unwrap());
+                (Some(value),preview,"".to_string())
+            },
+            Err(err) => (None,"".to_string(),err),
+        })
+        .collect();
+    cx.render(rsx!(
+        if has_logprobs {
+            rsx!(
+                div {
+                    span {
+                        "Hide choices below confidence %"
+                    }
+                    input {
+                        style: "width:4em;",
+                        value: "{min_confidence}",
+                        oninput: move |evt| min_confidence.set(evt.value.clone()),
+                    }
+                    span {
+                        " {hidden} hidden"
+                    }
+                }
+            )
+        }
+        for ((choice,highlighted),(value,preview,error)) in shown.into_iter().zip(highlighted.into_iter()).zip(outputs.into_iter()) {
+            {match highlighted {
+                Some((confidence,tokens)) => rsx!(
+                    p {
+                        "{confidence}"
+                    }
+                    p {
+                        style: "white-space:pre-wrap;",
+                        for (token,style,title) in tokens {
+                            span {
+                                style: "{style}",
+                                title: "{title}",
+                                "{token}"
+                            }
+                        }
+                    }
+                ),
+                None => rsx!(
+                    p{
+                        "{choice.message.content.clone()}"
+                    }
+                ),
+            }}
+            p {
+                "{preview}"
+            }
+            p {
+                style: "color:red;",
+                "{error}"
+            }
+            value.map(|value| rsx!(
+                AddToField{
+                    value:value
+                }
+            ))
+        }
+    ))
+}
+
+/// The transforms every choice goes through on its way into the payload, in order.
+fn TransformChainEditor(cx:Scope) -> Element {
+    let transforms = use_shared_state::<Vec<Transform>>(cx).unwrap();
+    let kind = use_state(cx, || "trim".to_string());
+    let first = use_state(cx, || "".to_string());
+    let second = use_state(cx, || "".to_string());
+    let error = use_state(cx, || "".to_string());
+    let labels : Vec<(usize,String)> = transforms.read().iter().map(|t| t.label()).enumerate().collect();
+    let (first_placeholder,second_placeholder) = match kind.get().as_str() {
+        "code_block" => (Some("language, any when empty"),None),
+        "replace" => (Some("regex"),Some("replacement")),
+        "capture" => (Some("regex"),None),
+        "words" => (Some("words"),None),
+        _ => (None,None),
+    };
+    cx.render(rsx!{
+        div {
+            h5 {"Transforms"}
+            for (i,label) in labels.into_iter() {
+                div {
+                    span {
+                        "{i+1}. {label}"
+                    }
+                    button {
+                        disabled: i == 0,
+                        onclick: move |_| transforms.write().swap(i - 1,i),
+                        "Up"
+                    }
+                    button {
+                        onclick: move |_| {
+                            transforms.write().remove(i);
+                        },
+                        "X"
+                    }
+                }
+            }
+            select {
+                onchange: move |evt| kind.set(evt.value.clone()),
+                option {
+                    value: "trim",
+                    "Trim"
+                },
+                option {
+                    value: "markdown",
+                    "Strip markdown"
+                },
+                option {
+                    value: "code_block",
+                    "Extract code block"
+                },
+                option {
+                    value: "json",
+                    "Extract JSON"
+                },
+                option {
+                    value: "replace",
+                    "Regex replace"
+                },
+                option {
+                    value: "capture",
+                    "Regex capture"
+                },
+                option {
+                    value: "lines",
+                    "Split lines"
+                },
+                option {
+                    value: "bullets",
+                    "Split bullets"
+                },
+                option {
+                    value: "words",
+                    "Truncate words"
+                },
+                option {
+                    value: "slugify",
+                    "Slugify"
+                },
+            }
+            first_placeholder.map(|placeholder| rsx!(
+                input {
+                    placeholder: "{placeholder}",
+                    value: "{first}",
+                    oninput: move |evt| first.set(evt.value.clone()),
+                }
+            ))
+            second_placeholder.map(|placeholder| rsx!(
+                input {
+                    placeholder: "{placeholder}",
+                    value: "{second}",
+                    oninput: move |evt| second.set(evt.value.clone()),
+                }
+            ))
+            button {
+                onclick: move |_| {
+                    let transform = match kind.get().as_str() {
+                        "markdown" => Ok(Transform::StripMarkdown),
+                        "code_block" => Ok(Transform::ExtractCodeBlock{ language:first.get().trim().to_string() }),
+                        "json" => Ok(Transform::ExtractJson),
+                        "replace" => Ok(Transform::RegexReplace{ pattern:first.get().clone(), replacement:second.get().clone() }),
+                        "capture" => Ok(Transform::RegexCapture{ pattern:first.get().clone() }),
+                        "lines" => Ok(Transform::SplitLines),
+                        "bullets" => Ok(Transform::SplitBullets),
+                        "words" => first.get().trim().parse::<usize>()
+                            .map(Transform::TruncateWords)
+                            .map_err(|_| "the number of words has to be a whole number".to_string()),
+                        "slugify" => Ok(Transform::Slugify),
+                        _ => Ok(Transform::Trim),
+                    };
+                    match transform {
+                        Ok(transform) => {
+                            transforms.write().push(transform);
+                            first.set("".to_string());
+                            second.set("".to_string());
+                            error.set("".to_string());
+                        },
+                        Err(err) => error.set(err),
+                    }
+                },
+                "Add"
+            }
+            p {
+                style: "color:red;",
+                "{error}"
+            }
+        }
+    })
+}
+
+/// The probability of a token and of the alternatives that were returned for it.
+fn token_title(token:&TokenLogprob) -> String {
+    let mut title = format!("{:.1}%",token.probability() * 100.);
+    for alternative in &token.top_logprobs {
+        title.push_str(&format!("\n{:?} {:.1}%",alternative.token,alternative.logprob.exp() * 100.));
+    }
+    title
+}
+
+/// Writes `value` into the payload at a dot separated `path`, creating objects along the way.
+fn set_payload_field(
+    map:&mut serde_json::Map<String,serde_json::Value>,
+    path:&str,
+    value:serde_json::Value,
+    ) {
+    use serde_json::Value;
+    let mut temp = map.get_mut("").unwrap().as_object_mut().unwrap();
+    let mut fields = path.split(".").peekable();
+    while let Some(p) = fields.next() {
+        if fields.peek().is_none() {
+            temp.insert(p.to_string(),value);
+            break;
+        }
+        let new_temp = temp.entry(p.to_string()).or_insert_with(|| Value::Object(serde_json::Map::default()));
+        if !new_temp.is_object() {
+            *new_temp = Value::Object(serde_json::Map::default());
+        }
+        temp = new_temp.as_object_mut().unwrap();
+    }
+}
+
+fn AddToField(cx:Scope<AddToFieldProps>) -> Element {
+    use serde_json::Value;
+    let map = use_shared_state::<serde_json::Map<String,Value>>(cx).unwrap();
+    let attachments = use_shared_state::<Vec<PayloadAttachment>>(cx).unwrap();
+    let path = use_state(cx, || "".to_string());
+    cx.render(rsx!(
+        div{
+            input{
+                value:"{path}",
+                oninput: move |evt| path.set(evt.value.clone())
+            }
+            button{
+                onclick: move |_| {
+                    if !path.get().is_empty() {
+                        attachments.write().retain(|a| !a.replaced_by(path.get()));
+                        set_payload_field(&mut map.write(),path.get(),cx.props.value.clone());
+                    }
+                },
+                style:"width:10em;height:2em;",
+                "add to field"
+            }
+        }
+    ))
+}
+async fn fetch_dall_e(
+    model_response:UseSharedState<DallEResponse>,
+    recorder:RunRecorder,
+    cache:CacheSettings,
+    key:String,
+    request:DallERequest,
+    ) -> Result<(),String> {
+    let entry = cache_key("dall_e",&serde_json::to_value(&request).unwrap());
+    let cached = match cache_get(&cache,&entry).await {
+        Some(body) => cached_image_objects(&body).await,
+        None => None,
+    };
+    let mut generation = Generation::new("dall_e",&request.model,&request.prompt);
+    let resp = match cached {
+        Some(data) => {
+            generation.cached = true;
+            DallEResponse{ created_timestamp:None, data }
+        },
+        None => {
+            let mut resp = match post_dall_e(&recorder,&key,&request).await {
+                Ok(resp) => resp,
+                Err(err) => return Err(recorder.failed(generation,err)),
+            };
+            store_image_objects(&mut resp.data).await;
+            if let Some(body) = image_cache_entry(&resp.data) {
+                cache_put(&cache,&entry,&body).await;
+            }
+            generation.consumption = Some(Consumption::Images{
+                count:resp.data.len() as u32,
+                size:request.size.clone(),
+                quality:request.quality.clone(),
+            });
+            resp
+        },
+    };
+    generation.output = serde_json::Value::Array(
+        resp.data.iter().map(|img| serde_json::Value::String(img.revised_prompt.clone().unwrap_or_default())).collect()
+    );
+    generation.assets = resp.data.iter().filter_map(|img| img.asset.clone()).collect();
+    recorder.generation(generation);
+    *model_response.write() = resp;
+    Ok(())
+}
+
+async fn post_dall_e(recorder:&RunRecorder, key:&str, request:&DallERequest) -> Result<DallEResponse,String> {
+    if let Some(reason) = recorder.cap_exceeded() {
+        return Err(reason);
+    }
+    reqwest::Client::new()
+        .post("https://api.openai.com/v1/images/generations")
+        .header("Authorization",format!("Bearer {}",key))
+        .header("Content-Type","application/json")
+        .body(serde_json::to_string(request).unwrap())
+    .send()
+    .await
+    .and_then(|resp| resp.error_for_status())
+    .map_err(|e| e.to_string())?
+    .json::<DallEResponse>()
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/// Copies generated images into the asset store, DALL-E urls expire after an hour.
+async fn store_image_objects(images:&mut [ImageObject]) {
+    use base64::Engine;
+    for img in images.iter_mut() {
+        let stored = match &img.b64_json {
+            Some(b64) => match base64::engine::general_purpose::STANDARD.decode(b64) {
+                Ok(bytes) => store_asset(Bytes::from(bytes),"image/png").await,
+                Err(e) => Err(e.to_string()),
+            },
+            None => download_asset(&img.url).await,
+        };
+        match stored {
+            Ok(asset) => img.asset = Some(asset),
+            Err(e) => log::error!("failed to store image: {}",e),
+        }
+    }
+}
+
+/// What's cached of generated images, the images themselves are already in the asset store.
+/// `None` when one of them couldn't be stored.
+fn image_cache_entry(images:&[ImageObject]) -> Option<Vec<u8>> {
+    let cached : Vec<CachedImage> = images.iter()
+        .map(|img| img.asset.clone().map(|asset| CachedImage{ asset, revised_prompt:img.revised_prompt.clone() }))
+        .collect::<Option<Vec<CachedImage>>>()?;
+    serde_json::to_vec(&cached).ok()
+}
+
+/// Rebuilds cached images from the asset store, `None` when one has gone missing from it.
+async fn cached_image_objects(body:&[u8]) -> Option<Vec<ImageObject>> {
+    use base64::Engine;
+    let cached : Vec<CachedImage> = serde_json::from_slice(body).ok()?;
+    let mut images = vec![];
+    for image in cached {
+        let bytes = load_asset(&image.asset.id).await.ok()??;
+        images.push(ImageObject{
+            url:String::new(),
+            b64_json:Some(base64::engine::general_purpose::STANDARD.encode(&bytes)),
+            revised_prompt:image.revised_prompt,
+            asset:Some(image.asset),
+        });
+    }
+    Some(images)
+}
+
+fn DallE(cx:Scope) -> Element {
+    use_shared_state_provider(cx, || DallEResponse::default());
+    let app_state = use_shared_state::<AppState>(cx).unwrap();
+    let model_resp = use_shared_state::<DallEResponse>(cx).unwrap();
+    let run_log = use_shared_state::<RunLog>(cx).unwrap();
+    let ledger = use_shared_state::<UsageLedger>(cx).unwrap();
+    let cache = use_shared_state::<CacheSettings>(cx).unwrap();
+    let model = app_state.read().dall_e_model.clone();
+    let batch_size = use_state(cx, || 1);
+    let size = use_state(cx, || "256x256".to_string());
+    let quality = use_state(cx, || "standard".to_string());
+    let style = use_state(cx, || "vivid".to_string());
+    let response_format = use_state(cx, || "url".to_string());
+    let error = use_state(cx, || "".to_string());
+    let keys = use_shared_state::<ApiKeys>(cx).unwrap();
+    let is_dall_e_3 = model == "dall-e-3";
+    let request_cost = image_cost(&model,size.get(),is_dall_e_3.then(|| quality.get().as_str()),*batch_size.get() as u32)
+        .map(format_usd)
+        .unwrap_or_default();
+
+    cx.render(
+        rsx!{
+            h3{"Dall-E"}
+
+            div {
+                p {
+                   "Prompt"
+               }
+               textarea {
+                   value: "{app_state.read().dall_e_raw}",
+                   oninput: move |evt| app_state.write().update_field(AppStateFieldUpdate::DallE(evt.value.clone())),
+                },
+                p {
+                    "{app_state.read().dall_e_edited}"
+                }
+           }
+           div {
+            p {
+                "Model"
+            }
+            select {
+                value: "{model}",
+                onchange: move |evt| {
+                    size.set(DallERequest::sizes(&evt.value)[0].to_string());
+                    if evt.value == "dall-e-3" {
+                        batch_size.set(1);
+                    }
+                    app_state.write().dall_e_model = evt.value.clone();
+                },
+                option {
+                    value:"dall-e-2",
+                    "dall-e-2"
+                },
+                option {
+                    value:"dall-e-3",
+                    "dall-e-3"
+                },
+            },
+           }
+           div {
+            p {
+               "Batch Size"
+           }
+           input {
+               value: "{batch_size}",
+               oninput: move |evt| batch_size.set(evt.value.clone().parse::<u8>().unwrap_or_default()),
+           },
+        }
+        div {
+            p {
+                "Size"
+            }
+            select {
+                value: "{size}",
+                onchange: move |evt| size.set(evt.value.clone()),
+                DallERequest::sizes(&model).iter().map(|s|
+                    rsx!{
+                        option {
+                            value: *s,
+                            *s
+                        }
+                    }
+                )
+                },
+           }
+           if is_dall_e_3 {
+            rsx!{
+                div {
+                    p {
+                        "Quality"
+                    }
+                    select {
+                        onchange: move |evt| quality.set(evt.value.clone()),
+                        option {
+                            value:"standard",
+                            "standard"
+                        },
+                        option {
+                            value:"hd",
+                            "hd"
+                        },
+                    },
+                }
+                div {
+                    p {
+                        "Style"
+                    }
+                    select {
+                        onchange: move |evt| style.set(evt.value.clone()),
+                        option {
+                            value:"vivid",
+                            "vivid"
+                        },
+                        option {
+                            value:"natural",
+                            "natural"
+                        },
+                    },
+                }
+            }
+           }
+           div {
+            p {
+                "Response Format"
+            }
+            select {
+                onchange: move |evt| response_format.set(evt.value.clone()),
+                option {
+                    value:"url",
+                    "url"
+                },
+                option {
+                    value:"b64_json",
+                    "b64_json"
+                },
+            },
+           }
+           div {
+            button{
+                style: "width:6em;height:2em;",
+                onclick: move |_| {
+                        let request = DallERequest {
+                            model: app_state.read().dall_e_model.clone(),
+                            prompt: app_state.read().dall_e_edited.clone(),
+                            n: *batch_size.get(),
+                            size: size.current().as_ref().clone(),
+                            quality: is_dall_e_3.then(|| quality.current().as_ref().clone()),
+                            style: is_dall_e_3.then(|| style.current().as_ref().clone()),
+                            response_format: response_format.current().as_ref().clone(),
+                        };
+                        let model_resp = model_resp.clone();
+                        let recorder = RunRecorder::new(run_log,ledger,&app_state.read());
+                        let cache = cache.read().clone();
+                        let key = (*keys).read().open_ai.clone();
+                        to_owned![error];
+                        async move {
+                            match request.validate() {
+                                Ok(()) => {
+                                    error.set("".to_string());
+                                    if let Err(err) = fetch_dall_e(model_resp,recorder,cache,key,request).await {
+                                        error.set(err);
+                                    }
+                                },
+                                Err(err) => error.set(err),
+                            }
+                        }
+                },
+                "Submit"
+            }
+            span {
+                "≈{request_cost}"
+            }
+            p {
+                "{error}"
+            }
+           }
+           DallERefine{}
+           div {
+                (*model_resp.read()).data.iter().map(|img|
+                    {
+                        let src = img.src();
+                        let revised_prompt = img.revised_prompt.clone().unwrap_or_default();
+                        rsx!(
+                            div {
+                                key: "{src}",
+                                img { src: "{src}" }
+                                p { "{revised_prompt}" }
+                                match img.asset.clone() {
+                                    Some(asset) => rsx!( AssetField{ asset:asset } ),
+                                    None => rsx!( AddToField{ value:serde_json::Value::String(img.url.clone()) } ),
+                                }
+                            }
+                        )
+                    }
+                )
+            }
+        }
+    )
+}
+
+/// The raw bytes of a previous DALL-E output, so it can be sent back for refinement.
+async fn image_object_bytes(img:ImageObject) -> Result<Vec<u8>,String> {
+    use base64::Engine;
+    if let Some(asset) = &img.asset {
+        if let Some(bytes) = load_asset(&asset.id).await? {
+            return Ok(bytes.to_vec());
+        }
+    }
+    match img.b64_json {
+        Some(b64) => base64::engine::general_purpose::STANDARD.decode(b64).map_err(|e| e.to_string()),
+        None => Ok(reqwest::get(img.url)
+            .await
+            .map_err(|e| e.to_string())?
+            .bytes()
+            .await
+            .map_err(|e| e.to_string())?
+            .to_vec()),
+    }
+}
+
+/// Sends a source image (and optionally a mask) to `/images/edits`, or to `/images/variations` when `prompt` is `None`.
+async fn fetch_dall_e_refine(
+    model_response:UseSharedState<DallEResponse>,
+    recorder:RunRecorder,
+    cache:CacheSettings,
+    key:String,
+    request:DallERefineRequest,
+    ) -> Result<(),String> {
+    let DallERefineRequest{ image, mask, prompt, n:batch_size, size, response_format } = request;
+    let (provider,endpoint) = match prompt {
+        Some(_) => ("dall_e_edit","https://api.openai.com/v1/images/edits"),
+        None => ("dall_e_variation","https://api.openai.com/v1/images/variations"),
+    };
+    let mut generation = Generation::new(provider,"dall-e-2",prompt.as_deref().unwrap_or_default());
+    // The images go into the key by their hash, like they go into the asset store
+    let entry = cache_key(provider,&serde_json::json!({
+        "image":asset_id(&image),
+        "mask":mask.as_deref().map(asset_id),
+        "prompt":prompt,
+        "n":batch_size,
+        "size":size,
+        "response_format":response_format,
+    }));
+    if let Some(data) = match cache_get(&cache,&entry).await {
+        Some(body) => cached_image_objects(&body).await,
+        None => None,
+    } {
+        generation.cached = true;
+        generation.assets = data.iter().filter_map(|img| img.asset.clone()).collect();
+        recorder.generation(generation);
+        *model_response.write() = DallEResponse{ created_timestamp:None, data };
+        return Ok(());
+    }
+    let billed_size = size.clone();
+    let mut form = reqwest::multipart::Form::new()
+        .part("image",reqwest::multipart::Part::bytes(image).file_name("image.png").mime_str("image/png").unwrap())
+        .text("n",batch_size.to_string())
+        .text("size",size)
+        .text("response_format",response_format);
+    if let Some(mask) = mask {
+        form = form.part("mask",reqwest::multipart::Part::bytes(mask).file_name("mask.png").mime_str("image/png").unwrap());
+    }
+    if let Some(prompt) = prompt {
+        form = form.text("prompt",prompt);
+    }
+    let mut resp = match post_image_form(&recorder,&key,endpoint,form).await {
+        Ok(resp) => resp,
+        Err(err) => return Err(recorder.failed(generation,err)),
+    };
+    store_image_objects(&mut resp.data).await;
+    if let Some(body) = image_cache_entry(&resp.data) {
+        cache_put(&cache,&entry,&body).await;
+    }
+    generation.consumption = Some(Consumption::Images{ count:resp.data.len() as u32, size:billed_size, quality:None });
+    generation.assets = resp.data.iter().filter_map(|img| img.asset.clone()).collect();
+    recorder.generation(generation);
+    *model_response.write() = resp;
+    Ok(())
+}
+
+async fn post_image_form(recorder:&RunRecorder, key:&str, endpoint:&str, form:reqwest::multipart::Form) -> Result<DallEResponse,String> {
+    if let Some(reason) = recorder.cap_exceeded() {
+        return Err(reason);
+    }
+    reqwest::Client::new()
+        .post(endpoint)
+        .header("Authorization",format!("Bearer {}",key))
+        .multipart(form)
+    .send()
+    .await
+    .and_then(|resp| resp.error_for_status())
+    .map_err(|e| e.to_string())?
+    .json::<DallEResponse>()
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/// Edits or varies an uploaded image or one of the images currently shown by `DallE`.
+fn DallERefine(cx:Scope) -> Element {
+    let model_resp = use_shared_state::<DallEResponse>(cx).unwrap();
+    let app_state = use_shared_state::<AppState>(cx).unwrap();
+    let run_log = use_shared_state::<RunLog>(cx).unwrap();
+    let ledger = use_shared_state::<UsageLedger>(cx).unwrap();
+    let cache = use_shared_state::<CacheSettings>(cx).unwrap();
+    let keys = use_shared_state::<ApiKeys>(cx).unwrap();
+    let mode = use_state(cx, || "edit".to_string());
+    let source = use_state(cx, || "upload".to_string());
+    let uploaded_image: &UseRef<Option<Vec<u8>>> = use_ref(cx, || None);
+    let uploaded_mask: &UseRef<Option<Vec<u8>>> = use_ref(cx, || None);
+    let prompt = use_state(cx, || "".to_string());
+    let batch_size = use_state(cx, || 1);
+    let size = use_state(cx, || "1024x1024".to_string());
+    let error = use_state(cx, || "".to_string());
+    let outputs = model_resp.read().data.len();
+
+    cx.render(rsx!{
+        h4{"Refine"}
+        div {
+            p {
+                "Mode"
+            }
+            select {
+                onchange: move |evt| mode.set(evt.value.clone()),
+                option {
+                    value:"edit",
+                    "Edit"
+                },
+                option {
+                    value:"variation",
+                    "Variation"
+                },
+            },
+        }
+        div {
+            p {
+                "Source"
+            }
+            select {
+                onchange: move |evt| source.set(evt.value.clone()),
+                option {
+                    value:"upload",
+                    "Uploaded image"
+                },
+                (0..outputs).map(|i|
+                    rsx!{
+                        option {
+                            value: "{i}",
+                            "Output {i}"
+                        }
+                    }
+                )
+            },
+        }
+        if source.get() == "upload" {
+            rsx!{
+                div {
+                    input {
+                        r#type:"file",
+                        accept: ".png",
+                        onchange: |evt| {
+                            to_owned![uploaded_image];
+                            async move {
+                                if let Some(file_engine) = &evt.files {
+                                    if let Some(file_name) = file_engine.files().first() {
+                                        *uploaded_image.write() = file_engine.read_file(file_name).await;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if mode.get() == "edit" {
+            rsx!{
+                div {
+                    p {
+                        "Mask"
+                    }
+                    input {
+                        r#type:"file",
+                        accept: ".png",
+                        onchange: |evt| {
+                            to_owned![uploaded_mask];
+                            async move {
+                                if let Some(file_engine) = &evt.files {
+                                    if let Some(file_name) = file_engine.files().first() {
+                                        *uploaded_mask.write() = file_engine.read_file(file_name).await;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+                div {
+                    p {
+                        "Prompt"
+                    }
+                    textarea {
+                        value: "{prompt}",
+                        oninput: move |evt| prompt.set(evt.value.clone()),
+                    },
+                }
+            }
+        }
+        div {
+            p {
+               "Batch Size"
+           }
+           input {
+               value: "{batch_size}",
+               oninput: move |evt| batch_size.set(evt.value.clone().parse::<u8>().unwrap_or_default().min(10)),
+           },
+        }
+        div {
+            p {
+                "Size"
+            }
+            select {
+                value: "{size}",
+                onchange: move |evt| size.set(evt.value.clone()),
+                DallERequest::sizes("dall-e-2").iter().map(|s|
+                    rsx!{
+                        option {
+                            value: *s,
+                            *s
+                        }
+                    }
+                )
+            },
+        }
+        div {
+            button{
+                style: "width:6em;height:2em;",
+                onclick: move |_| {
+                    let from_output = source.get().parse::<usize>().ok();
+                    let previous = from_output.and_then(|i| model_resp.read().data.get(i).cloned());
+                    let uploaded = uploaded_image.read().clone();
+                    let is_edit = mode.get() == "edit";
+                    let mask = if is_edit { uploaded_mask.read().clone() } else { None };
+                    let prompt = is_edit.then(|| prompt.current().as_ref().clone());
+                    let batch_size = *batch_size.get();
+                    let size = size.current().as_ref().clone();
+                    // Keep handing back whatever format the last generation used.
+                    let response_format = if model_resp.read().data.iter().any(|img| img.b64_json.is_some()) {
+                        "b64_json".to_string()
+                    } else {
+                        "url".to_string()
+                    };
+                    let model_resp = model_resp.clone();
+                    let recorder = RunRecorder::new(run_log,ledger,&app_state.read());
+                    let cache = cache.read().clone();
+                    let key = (*keys).read().open_ai.clone();
+                    to_owned![error];
+                    async move {
+                        let image = match (from_output,previous,uploaded) {
+                            (Some(_),Some(img),_) => match image_object_bytes(img).await {
+                                Ok(bytes) => bytes,
+                                Err(err) => {
+                                    error.set(err);
+                                    return;
+                                },
+                            },
+                            (Some(i),None,_) => {
+                                error.set(format!("output {} is no longer shown, choose the source again",i));
+                                return;
+                            },
+                            (None,_,Some(bytes)) => bytes,
+                            (None,_,None) => {
+                                error.set("choose a source image".to_string());
+                                return;
+                            },
+                        };
+                        if let Err(err) = check_refine_images(&image,mask.as_deref()) {
+                            error.set(err);
+                            return;
+                        }
+                        if prompt.as_ref().is_some_and(|p| p.trim().is_empty()) {
+                            error.set("edits need a prompt".to_string());
+                            return;
+                        }
+                        if batch_size < 1 {
+                            error.set("the batch size must be at least 1".to_string());
+                            return;
+                        }
+                        error.set("".to_string());
+                        if let Err(err) = fetch_dall_e_refine(model_resp,recorder,cache,key,DallERefineRequest{ image, mask, prompt, n:batch_size, size, response_format }).await {
+                            error.set(err);
+                        }
+                    }
+                },
+                "Refine"
+            }
+            p {
+                "{error}"
+            }
+        }
+    })
+}
+
+async fn fetch_stable_diffusion(
+    model_response:UseSharedState<StableDiffusionResponse>,
+    recorder:RunRecorder,
+    cache:CacheSettings,
+    backend:StableDiffusionBackend,
+    base_url:String,
+    checkpoint:String,
+    request:StableDiffusionRequest,
+    ) -> Result<(),String> {
+    let base_url = base_url.trim_end_matches('/').to_string();
+    let mut generation = Generation::new(
+        "stable_diffusion",
+        if backend == StableDiffusionBackend::ComfyUI { &checkpoint } else { "automatic1111" },
+        &request.prompt,
+    );
+    let mut entry = serde_json::to_value(&request).unwrap();
+    entry["backend"] = serde_json::json!(format!("{:?}",backend));
+    entry["base_url"] = serde_json::json!(base_url);
+    entry["checkpoint"] = serde_json::json!(checkpoint);
+    let entry = cache_key("stable_diffusion",&entry);
+    if let Some(resp) = match cache_get(&cache,&entry).await {
+        Some(body) => cached_stable_diffusion(&body).await,
+        None => None,
+    } {
+        generation.cached = true;
+        generation.output = serde_json::json!({"seeds":resp.seeds});
+        generation.assets = resp.assets.iter().flatten().cloned().collect();
+        recorder.generation(generation);
+        *model_response.write() = resp;
+        return Ok(());
+    }
+    let resp = match recorder.cap_exceeded() {
+        Some(reason) => Err(reason),
+        None => match backend {
+            StableDiffusionBackend::Automatic1111 => automatic1111_txt2img(base_url,request).await,
+            StableDiffusionBackend::ComfyUI => comfy_txt2img(base_url,checkpoint,request).await,
+        },
+    };
+    let mut resp = match resp {
+        Ok(resp) => resp,
+        Err(err) => return Err(recorder.failed(generation,err)),
+    };
+    for b64 in &resp.images {
+        let stored = match base64::Engine::decode(&base64::engine::general_purpose::STANDARD,b64) {
+            Ok(bytes) => store_asset(Bytes::from(bytes),"image/png").await,
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = &stored {
+            log::error!("failed to store image: {}",e);
+        }
+        resp.assets.push(stored.ok());
+    }
+    // Only cached once every image made it into the asset store
+    if let Some(assets) = resp.assets.iter().cloned().collect::<Option<Vec<AssetRef>>>() {
+        let cached = CachedStableDiffusion{ seeds:resp.seeds.clone(), assets };
+        cache_put(&cache,&entry,&serde_json::to_vec(&cached).unwrap()).await;
+    }
+    generation.output = serde_json::json!({"seeds":resp.seeds});
+    generation.assets = resp.assets.iter().flatten().cloned().collect();
+    recorder.generation(generation);
+    *model_response.write() = resp;
+    Ok(())
+}
+
+/// Rebuilds cached images from the asset store, `None` when one has gone missing from it.
+async fn cached_stable_diffusion(body:&[u8]) -> Option<StableDiffusionResponse> {
+    use base64::Engine;
+    let cached : CachedStableDiffusion = serde_json::from_slice(body).ok()?;
+    let mut resp = StableDiffusionResponse{ seeds:cached.seeds, ..Default::default() };
+    for asset in cached.assets {
+        let bytes = load_asset(&asset.id).await.ok()??;
+        resp.images.push(base64::engine::general_purpose::STANDARD.encode(&bytes));
+        resp.assets.push(Some(asset));
+    }
+    Some(resp)
+}
+
+async fn automatic1111_txt2img(
+    base_url:String,
+    request:StableDiffusionRequest,
+    ) -> Result<StableDiffusionResponse,String> {
+    let mut resp = reqwest::Client::new()
+        .post(format!("{}/sdapi/v1/txt2img",base_url))
+        .header("Content-Type","application/json")
+        .body(serde_json::to_string(&request).unwrap())
+    .send()
+    .await
+    .and_then(|resp| resp.error_for_status())
+    .map_err(|e| e.to_string())?
+    .json::<StableDiffusionResponse>()
+    .await
+    .map_err(|e| e.to_string())?;
+    resp.seeds = serde_json::from_str::<StableDiffusionInfo>(&resp.info)
+        .unwrap_or_default()
+        .all_seeds;
+    Ok(resp)
+}
+
+/// How many times ComfyUI's history is polled, once a second, before giving up on a prompt.
+const COMFY_POLL_ATTEMPTS : u32 = 600;
+
+/// Queues a plain txt2img workflow on ComfyUI, waits for it to finish and downloads the images.
+async fn comfy_txt2img(
+    base_url:String,
+    checkpoint:String,
+    request:StableDiffusionRequest,
+    ) -> Result<StableDiffusionResponse,String> {
+    use base64::Engine;
+    // ComfyUI has no notion of a random seed, so pick one here like AUTOMATIC1111 would.
+    let seed = if request.seed < 0 {
+        (js_sys::Math::random() * u32::MAX as f64) as i64
+    } else {
+        request.seed
+    };
+    let workflow = serde_json::json!({
+        "3": {"class_type":"KSampler","inputs":{
+            "seed":seed,
+            "steps":request.steps,
+            "cfg":request.cfg_scale,
+            "sampler_name":request.sampler_name,
+            "scheduler":"normal",
+            "denoise":1,
+            "model":["4",0],
+            "positive":["6",0],
+            "negative":["7",0],
+            "latent_image":["5",0]
+        }},
+        "4": {"class_type":"CheckpointLoaderSimple","inputs":{"ckpt_name":checkpoint}},
+        "5": {"class_type":"EmptyLatentImage","inputs":{
+            "width":request.width,
+            "height":request.height,
+            "batch_size":request.batch_size
+        }},
+        "6": {"class_type":"CLIPTextEncode","inputs":{"text":request.prompt,"clip":["4",1]}},
+        "7": {"class_type":"CLIPTextEncode","inputs":{"text":request.negative_prompt,"clip":["4",1]}},
+        "8": {"class_type":"VAEDecode","inputs":{"samples":["3",0],"vae":["4",2]}},
+        "9": {"class_type":"SaveImage","inputs":{"filename_prefix":"craptent","images":["8",0]}}
+    });
+    let client = reqwest::Client::new();
+    let prompt_id = client
+        .post(format!("{}/prompt",base_url))
         .header("Content-Type","application/json")
-        .body(format!("{{
-            \"model\":\"{}\",
-            \"frequency_penalty\":{},
-            \"max_tokens\":{},
-            \"n\":{},
-            \"presence_penalty\":{},
-            \"stop\":\"[{}]\",
-            \"temperature\":{},
-            \"top_p\":{},
-            \"messages\":[
-                {{\"role\":\"system\",\"content\":\"{}\"}},
-                {{\"role\":\"user\",\"content\":\"{}\"}}
-                ]
-        }}",
-        model,
-        frequency_penalty,
-        max_tokens,
-        batch_size,
-        presence_penalty,
-        stop_sequence.join(","),
-        temperature,
-        top_p,
-        system,
-        prompt 
-    ))
+        .body(serde_json::json!({"prompt":workflow}).to_string())
     .send()
     .await
-    .unwrap()
-    .json::<CompletionResponse>()
+    .and_then(|resp| resp.error_for_status())
+    .map_err(|e| e.to_string())?
+    .json::<ComfyPromptResponse>()
     .await
-    .unwrap();
-    *model_response.write() = resp;
+    .map_err(|e| e.to_string())?
+    .prompt_id;
+    // The history entry only shows up once the prompt has finished executing.
+    let mut outputs = None;
+    for _ in 0..COMFY_POLL_ATTEMPTS {
+        let history = client
+            .get(format!("{}/history/{}",base_url,prompt_id))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?
+        .json::<serde_json::Value>()
+        .await
+        .map_err(|e| e.to_string())?;
+        if let Some(entry) = history.get(&prompt_id) {
+            if entry.pointer("/status/status_str").and_then(|s| s.as_str()) == Some("error") {
+                let messages : Vec<&str> = entry.pointer("/status/messages")
+                    .and_then(|m| m.as_array())
+                    .map(|m| m.iter().filter_map(|m| m.pointer("/1/exception_message")).filter_map(|m| m.as_str()).collect())
+                    .unwrap_or_default();
+                return Err(format!("ComfyUI failed to run the prompt: {}",messages.join(", ")));
+            }
+            if let Some(found) = entry.get("outputs") {
+                outputs = Some(found.clone());
+                break;
+            }
+        }
+        gloo::timers::future::TimeoutFuture::new(1000).await;
+    }
+    let outputs = outputs.ok_or_else(|| format!("ComfyUI didn't finish prompt {} within {} seconds",prompt_id,COMFY_POLL_ATTEMPTS))?;
+    // Custom nodes and other ComfyUI versions may describe their images differently.
+    let images : Vec<ComfyImage> = outputs.get("9")
+        .and_then(|node|node.get("images"))
+        .map(|images|serde_json::from_value(images.clone()))
+        .transpose()
+        .map_err(|e| format!("unexpected images in the ComfyUI output: {}",e))?
+        .unwrap_or_default();
+    let mut resp = StableDiffusionResponse::default();
+    for image in images {
+        let bytes = client
+            .get(format!("{}/view",base_url))
+            .query(&[("filename",image.filename),("subfolder",image.subfolder),("type",image.kind)])
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|e| e.to_string())?
+        .bytes()
+        .await
+        .map_err(|e| e.to_string())?;
+        resp.images.push(base64::engine::general_purpose::STANDARD.encode(&bytes));
+        resp.seeds.push(seed);
+    }
+    Ok(resp)
 }
 
 
-fn ChatGpt(cx:Scope) -> Element {
-    use_shared_state_provider(cx, || CompletionResponse::default());
+fn StableDiffusion(cx:Scope) -> Element {
+    use_shared_state_provider(cx, StableDiffusionResponse::default);
     let app_state = use_shared_state::<AppState>(cx).unwrap();
-    let model_resp = use_shared_state::<CompletionResponse>(cx).unwrap();
-    let keys = use_shared_state::<ApiKeys>(cx).unwrap();
+    let model_resp = use_shared_state::<StableDiffusionResponse>(cx).unwrap();
+    let run_log = use_shared_state::<RunLog>(cx).unwrap();
+    let ledger = use_shared_state::<UsageLedger>(cx).unwrap();
+    let cache = use_shared_state::<CacheSettings>(cx).unwrap();
+    let backend = use_state(cx, || StableDiffusionBackend::Automatic1111);
+    let base_url = use_state(cx, || "http://127.0.0.1:7860".to_string());
+    let checkpoint = use_state(cx, || "".to_string());
+    let negative_prompt = use_state(cx, || "".to_string());
+    let seed = use_state(cx, || -1);
+    let steps = use_state(cx, || 20);
+    let sampler_name = use_state(cx, || "Euler a".to_string());
+    let cfg_scale = use_state(cx, || 7.);
+    let width = use_state(cx, || 512);
+    let height = use_state(cx, || 512);
     let batch_size = use_state(cx, || 1);
-    let temperature = use_state(cx, || 1.);
-    let max_tokens = use_state(cx, || 256);
-    let stop_sequence: &UseState<Vec<String>> = use_state(cx, || vec![]);
-    let top_p = use_state(cx, || 1.);
-    let frequency_penalty = use_state(cx, || 0.);
-    let presence_penalty = use_state(cx, || 0.);
-    let model = use_state(cx, ||  "gpt-3.5-turbo".to_string());
-    let sequence = use_state(cx, || "".to_string());
-    let mut stop_sequence_rendered = vec![];
-    for seq in stop_sequence.current()
-        .iter() {
-        let seq = seq.clone();
-        stop_sequence_rendered.push(
-            rsx!{
-                button {
-                    onclick : move |evt| {
-                        let current = stop_sequence.current().iter().filter_map(|s| if s != &seq{
-                            Some(s.clone())
-                        } else {
-                            None
-                        }).collect();
-                        stop_sequence.set(current);
-                    },
-                    "X {seq}"
-                }
-            }
-        )
-    }
-    cx.render(
+    let error = use_state(cx, || "".to_string());
 
+    cx.render(
         rsx!{
-            h3{"ChatGPT"}
+            h3{"Stable Diffusion"}
 
             div {
                 p {
-                    "System"
+                    "Backend"
                 }
-                textarea {
-                    value: "{app_state.read().chat_gpt_system_raw}",
-                    oninput: move |evt| app_state.write().update_field(AppStateFieldUpdate::ChatGPTSystem(evt.value.clone())),
+                select {
+                    onchange: move |evt| match evt.value.as_str() {
+                        "comfyui" => {
+                            backend.set(StableDiffusionBackend::ComfyUI);
+                            base_url.set("http://127.0.0.1:8188".to_string());
+                            sampler_name.set("euler".to_string());
+                        },
+                        _ => {
+                            backend.set(StableDiffusionBackend::Automatic1111);
+                            base_url.set("http://127.0.0.1:7860".to_string());
+                            sampler_name.set("Euler a".to_string());
+                        },
+                    },
+                    option {
+                        value:"automatic1111",
+                        "AUTOMATIC1111"
+                    },
+                    option {
+                        value:"comfyui",
+                        "ComfyUI"
+                    },
                 },
+            }
+            div {
                 p {
-                    "{app_state.read().chat_gpt_system_edited}"
-                }
-            },
+                   "Server URL"
+               }
+               input {
+                   value: "{base_url}",
+                   oninput: move |evt| base_url.set(evt.value.clone()),
+               },
+            }
+            if *backend.get() == StableDiffusionBackend::ComfyUI {
+                rsx!(div {
+                    p {
+                       "Checkpoint"
+                   }
+                   input {
+                       value: "{checkpoint}",
+                       oninput: move |evt| checkpoint.set(evt.value.clone()),
+                   },
+                })
+            }
             div {
-                 p {
-                    "Prompt"
-                }
-                textarea {
-                    value: "{app_state.read().chat_gpt_prompt_raw}",
-                    oninput: move |evt| app_state.write().update_field(AppStateFieldUpdate::ChatGPTPrompt(evt.value.clone())),
+                p {
+                   "Prompt"
+               }
+               textarea {
+                   value: "{app_state.read().stable_diffusion_raw}",
+                   oninput: move |evt| app_state.write().update_field(AppStateFieldUpdate::StableDiffusion(evt.value.clone())),
                 },
                 p {
-                    "{app_state.read().chat_gpt_prompt_edited}"
+                    "{app_state.read().stable_diffusion_edited}"
                 }
-            },
-            
+           }
            div {
-            p {
-                "Model"
-            }
-            select {
-                onchange: move |evt| model.set(evt.value.clone()),
-
-                option {
-                    value:"gpt-3.5-turbo",
-                    "gpt-3.5-turbo"
-                },
-                option {
-                    value:"gpt-4",
-                    "gpt-4"
-                },
-                option {
-                    value: "gpt-3.5-turbo-16k",
-                    "gpt-3.5-turbo-16k"
+                p {
+                   "Negative Prompt"
+               }
+               textarea {
+                   value: "{negative_prompt}",
+                   oninput: move |evt| negative_prompt.set(evt.value.clone()),
                 },
-            },
            }
-        div {
+           div {
             p {
-               "Temperature"
+               "Seed"
            }
            input {
-               value: "{temperature}",
-               oninput: move |evt| temperature.set(evt.value.clone().parse::<f32>().unwrap_or_default().max(0.).min(2.)),
+               value: "{seed}",
+               oninput: move |evt| seed.set(evt.value.clone().parse::<i64>().unwrap_or(-1).max(-1)),
            },
         }
         div {
             p {
-               "Max Tokens"
+               "Steps"
            }
            input {
-               value: "{max_tokens}",
-               oninput: move |evt| max_tokens.set(evt.value.clone().parse::<u32>().unwrap_or_default().min(4092)),
+               value: "{steps}",
+               oninput: move |evt| steps.set(evt.value.clone().parse::<u32>().unwrap_or_default().clamp(1,150)),
            },
         }
         div {
             p {
-                "Stop Sequence"
-            }
-            form {
-                onsubmit : move |evt| {
-                        let mut current : Vec<String> = stop_sequence.current().clone().iter().map(|s|s.clone()).collect();
-                        current.push(sequence.current().as_ref().clone());
-                        stop_sequence.set(current);
-                        sequence.set("".to_string()) 
-                    },
-                input { 
-                    value:"{sequence}",
-                    oninput: move |evt| sequence.set(evt.value.clone()),
-                },
-                input { r#type: "submit", value:"Add" },
-            }
-            {stop_sequence_rendered.into_iter()}
-        }
-        div {
-            p {
-               "Top P"
+               "Sampler"
            }
            input {
-               value: "{top_p}",
-               oninput: move |evt| top_p.set(evt.value.clone().parse::<f32>().unwrap_or_default().max(0.).min(1.)),
+               value: "{sampler_name}",
+               oninput: move |evt| sampler_name.set(evt.value.clone()),
            },
         }
         div {
             p {
-               "Frequency Penalty"
+               "CFG Scale"
            }
            input {
-               value: "{frequency_penalty}",
-               oninput: move |evt| frequency_penalty.set(evt.value.clone().parse::<f32>().unwrap_or_default().max(0.).min(2.)),
+               value: "{cfg_scale}",
+               oninput: move |evt| cfg_scale.set(evt.value.clone().parse::<f32>().unwrap_or_default().clamp(1.,30.)),
            },
         }
         div {
             p {
-               "Presence Penalty"
+               "Width"
            }
            input {
-               value: "{presence_penalty}",
-               oninput: move |evt| presence_penalty.set(evt.value.clone().parse::<f32>().unwrap_or_default().max(-2.).min(2.)),
+               value: "{width}",
+               oninput: move |evt| width.set(evt.value.clone().parse::<u32>().unwrap_or_default().clamp(64,2048)),
            },
         }
         div {
             p {
-               "Batch Size"
+               "Height"
            }
            input {
-               value: "{batch_size}",
-               oninput: move |evt| batch_size.set(evt.value.clone().parse::<u8>().unwrap_or_default()),
+               value: "{height}",
+               oninput: move |evt| height.set(evt.value.clone().parse::<u32>().unwrap_or_default().clamp(64,2048)),
            },
-       }
-       div {
-        button{
-            style: "width:6em;height:2em;",
-            onclick: move |_| {
-                    fetch_chat_gpt(
-                        model_resp.clone(),
-                        (*keys).read().open_ai.clone(),
-                        model.current().as_ref().clone(),
-                        frequency_penalty.current().as_ref().clone(),
-                        max_tokens.current().as_ref().clone(),
-                        batch_size.current().as_ref().clone(),
-                        presence_penalty.current().as_ref().clone(),
-                        stop_sequence.current().as_ref().clone(),
-                        temperature.current().as_ref().clone(),
-                        top_p.current().as_ref().clone(),
-                        app_state.read().chat_gpt_system_edited.clone(),
-                        app_state.read().chat_gpt_prompt_edited.clone(),
-                    )
-            },
-            "Submit"
-        }
-       }
-       div {
-        if (*model_resp.read()) != CompletionResponse::default() {
-            rsx!(MessageChoices{
-                choices:(*model_resp.read()).message_choices.clone()
-            })
-        }
-       }
-        }
-    )
-}
-
-
-fn MessageChoices(cx:Scope<MessageChoicesProps>) -> Element {
-    use serde_json::Value;
-    let map = use_shared_state::<serde_json::Map<String,Value>>(cx).unwrap();
-    let path = use_state(cx, || "".to_string());
-    cx.render(rsx!(
-        for choice in &cx.props.choices {
-            p{
-                "{choice.message.content.clone()}"
-            }
-            input{
-                value:"{path}",
-                oninput: move |evt| path.set(evt.value.clone())
-            }
-            button{
-                onclick: move |_| {
-                    let mut map_c = map.read().clone();
-                    let mut temp = map_c.get_mut("").unwrap().as_object_mut().unwrap();
-                    for p in path.current().split(".") {
-                        let new_temp = temp.get_mut(p).unwrap();
-                        if new_temp.is_string() {
-                            *new_temp = Value::String(choice.message.content.clone());
-                            break;
-                        }
-                        temp = new_temp.as_object_mut().unwrap();
-                       
-                    }
-                    *map.write() = map_c;
-                },
-                style:"width:10em;height:2em;",
-                "add to field"
-            }
         }
-    ))
-}
-async fn fetch_dall_e(
-    model_response:UseSharedState<DallEResponse>,
-    key:String,
-    size:String,
-    batch_size:u8,
-    prompt:String,
-    ) {
-    let resp = reqwest::Client::new()
-        .post("https://api.openai.com/v1/images/generations")
-        .header("Authorization",format!("Bearer {}",key))
-        .header("Content-Type","application/json")
-        .body(format!("{{
-            \"n\":{},
-            \"size\":\"{}\",
-            \"prompt\":\"{}\"
-        }}",
-        batch_size,
-        size,
-        prompt 
-    ))
-    .send()
-    .await
-    .unwrap()
-    .json::<DallEResponse>()
-    .await
-    .unwrap();
-    *model_response.write() = resp;
-}
-
-
-fn DallE(cx:Scope) -> Element {
-    use_shared_state_provider(cx, || DallEResponse::default());
-    let app_state = use_shared_state::<AppState>(cx).unwrap();
-    let model_resp = use_shared_state::<DallEResponse>(cx).unwrap();
-    let prompt = use_state(cx, || "".to_string());
-    let batch_size = use_state(cx, || 1);
-    let size = use_state(cx, || "256x256".to_string());
-    let keys = use_shared_state::<ApiKeys>(cx).unwrap();
-
-    cx.render(
-        rsx!{
-            h3{"Dall-E"}
-
-            div {
-                p {
-                   "Prompt"
-               }
-               textarea {
-                   value: "{prompt}",
-                   oninput: move |evt| app_state.write().update_field(AppStateFieldUpdate::DallE(evt.value.clone())),
-                },
-           }
-           div {
+        div {
             p {
                "Batch Size"
            }
            input {
                value: "{batch_size}",
-               oninput: move |evt| batch_size.set(evt.value.clone().parse::<u8>().unwrap_or_default()),
+               oninput: move |evt| batch_size.set(evt.value.clone().parse::<u8>().unwrap_or_default().max(1)),
            },
         }
-        div {
-            p {
-                "Size"
-            }
-            select {
-                onchange: move |evt| size.set(evt.value.clone()),
-                option {
-                    value: "256x256",
-                    "256x256"
-                },
-                option {
-                    value:"512x512",
-                    "512x512"
-                },
-                option {
-                    value:"1024x1024",
-                    "1024x1024"
-                    },  
-                },
-           }
            div {
             button{
                 style: "width:6em;height:2em;",
                 onclick: move |_| {
-                        fetch_dall_e(
+                        let request = fetch_stable_diffusion(
                             model_resp.clone(),
-                            (*keys).read().open_ai.clone(),
-                            size.current().as_ref().clone(),
-                            batch_size.current().as_ref().clone(),
-                            prompt.current().as_ref().clone() 
-                        )
+                            RunRecorder::new(run_log,ledger,&app_state.read()),
+                            cache.read().clone(),
+                            *backend.get(),
+                            base_url.current().as_ref().clone(),
+                            checkpoint.current().as_ref().clone(),
+                            StableDiffusionRequest {
+                                prompt: app_state.read().stable_diffusion_edited.clone(),
+                                negative_prompt: negative_prompt.current().as_ref().clone(),
+                                seed: *seed.get(),
+                                steps: *steps.get(),
+                                sampler_name: sampler_name.current().as_ref().clone(),
+                                cfg_scale: *cfg_scale.get(),
+                                width: *width.get(),
+                                height: *height.get(),
+                                batch_size: *batch_size.get(),
+                            }
+                        );
+                        to_owned![error];
+                        async move {
+                            error.set("".to_string());
+                            if let Err(err) = request.await {
+                                error.set(err);
+                            }
+                        }
                 },
                 "Submit"
             }
+            p {
+                "{error}"
+            }
            }
            div {
-                (*model_resp.read()).data.iter().map(|img|
+                model_resp.read().images.iter().enumerate().map(|(i,b64)|
                     {
-                        let url = &img.url;
+                        let seed = model_resp.read().seeds.get(i).copied().unwrap_or(-1);
+                        let asset = model_resp.read().assets.get(i).cloned().flatten();
                         rsx!(
-                            img { key: "{url}", src: "{url}" }
+                            div {
+                                key: "{i}-{seed}",
+                                img { src: "data:image/png;base64,{b64}" }
+                                p { "seed {seed}" }
+                                asset.map(|asset| rsx!( AssetField{ asset:asset } ))
+                            }
                         )
                     }
                 )
@@ -693,46 +3807,177 @@ fn DallE(cx:Scope) -> Element {
     )
 }
 
+/// Narrates the text in chunks of whole sentences, each sent with the chunks around it so the
+/// delivery carries over, and joins the audio into one clip with a chapter per chunk.
 pub async fn text_to_audio(
-    model_response:UseSharedState<Vec<ObjectUrl>>,
+    model_response:UseSharedState<Vec<AudioClip>>,
+    recorder:RunRecorder,
+    cache:CacheSettings,
     key:String,
     voice_id:String,
     text:String,
     voice_settings:VoiceSettings,
-) {
-    let bytes = reqwest::Client::new()
+    max_chunk_chars:usize,
+) -> Result<(),String> {
+    let mut generation = Generation::new("eleven_labs","eleven_multilingual_v1",&text);
+    let chunks = chunk_text(&text,max_chunk_chars);
+    if chunks.is_empty() {
+        return Err("there's no text to narrate".to_string());
+    }
+    let mut audio = vec![];
+    let mut chapters = vec![];
+    let mut characters = 0;
+    for (i,chunk) in chunks.iter().enumerate() {
+        let mut request = serde_json::json!({
+            "text":chunk,
+            "model_id":"eleven_multilingual_v1",
+            "voice_settings":voice_settings,
+        });
+        if let Some(previous) = i.checked_sub(1).and_then(|i| chunks.get(i)) {
+            request["previous_text"] = serde_json::Value::String(previous.clone());
+        }
+        if let Some(next) = chunks.get(i + 1) {
+            request["next_text"] = serde_json::Value::String(next.clone());
+        }
+        let mut entry = request.clone();
+        entry["voice_id"] = serde_json::Value::String(voice_id.clone());
+        let entry = cache_key("eleven_labs",&entry);
+        let bytes = match cache_get(&cache,&entry).await {
+            Some(bytes) => bytes,
+            None => match narrate_chunk(&recorder,&key,&voice_id,&request).await {
+                Ok(bytes) => {
+                    cache_put(&cache,&entry,&bytes).await;
+                    // Only the chunk itself is billed, not the context around it
+                    characters += chunk.chars().count();
+                    bytes
+                },
+                Err(err) => {
+                    // The chunks narrated so far are billed all the same
+                    let err = format!("chunk {} of {}: {}",i + 1,chunks.len(),err);
+                    if characters > 0 {
+                        generation.consumption = Some(Consumption::Characters(characters));
+                    }
+                    return Err(recorder.failed(generation,err));
+                },
+            },
+        };
+        let segment = mp3_audio(&bytes);
+        // Only the first segment starts the clip
+        let segment = if i == 0 { segment } else { without_info_frame(segment) };
+        let start = chapters.last().map_or(0.,|c:&Chapter| c.end);
+        chapters.push(Chapter{
+            index:i,
+            start,
+            end:start + mp3_duration(segment),
+            text:chunk.clone(),
+        });
+        audio.extend_from_slice(segment);
+    }
+    generation.cached = characters == 0;
+    if characters > 0 {
+        generation.consumption = Some(Consumption::Characters(characters));
+    }
+    generation.output = serde_json::json!({"chapters":chapters});
+    let mut clip = AudioClip::new(GenModel::ElevenLabs,text,Bytes::from(audio),"audio/mpeg");
+    clip.chapters = chapters;
+    replace_audio_clip(model_response, recorder, generation, clip).await;
+    Ok(())
+}
+
+/// One uncached chunk of a narration.
+async fn narrate_chunk(recorder:&RunRecorder, key:&str, voice_id:&str, request:&serde_json::Value) -> Result<Bytes,String> {
+    if let Some(reason) = recorder.cap_exceeded() {
+        return Err(reason);
+    }
+    let resp = reqwest::Client::new()
         .post(&format!("https://api.elevenlabs.io/v1/text-to-speech/{}",voice_id))
-        .header("xi-api-key",key)  
-        .header("Content-Type","application/json")
-        .body(format!("{{
-            \"text\":\"{}\",
-            \"model_id\":\"eleven_multilingual_v1\",
-            \"voice_settings\":{}
-        }}",
-        text,
-        serde_json::to_string(&voice_settings).unwrap(),
-    ))    
+        .header("xi-api-key",key)
+        .json(request)
         .send()
         .await
-        .unwrap()
-        .bytes()
-        .await
-        .unwrap();
-    let blob = gloo::file::Blob::new_with_options(&*bytes,Some("mpeg/audio"));
-    let object_url = ObjectUrl::from(blob);
-    *model_response.write() = vec![object_url];
+        .map_err(|e| e.to_string())?;
+    // Error bodies are JSON, they mustn't be cached or played as audio.
+    if !resp.status().is_success() {
+        return Err(format!("ElevenLabs failed with {}: {}",resp.status(),resp.text().await.unwrap_or_default()));
+    }
+    resp.bytes().await.map_err(|e| e.to_string())
+}
+
+/// Stores the clip and swaps the previous clip of the same provider for it.
+async fn replace_audio_clip(
+    model_response:UseSharedState<Vec<AudioClip>>,
+    recorder:RunRecorder,
+    mut generation:Generation,
+    mut clip:AudioClip,
+    ) {
+    match store_asset(clip.bytes.clone(),&clip.mime).await {
+        Ok(asset) => clip.asset = Some(asset),
+        Err(e) => log::error!("failed to store audio: {}",e),
+    }
+    generation.assets = clip.asset.iter().cloned().collect();
+    recorder.generation(generation);
+    let mut clips = model_response.write();
+    clips.retain(|c| c.provider != clip.provider);
+    clips.push(clip);
+}
+
+fn AudioClips(cx:Scope<AudioClipsProps>) -> Element {
+    let clips = use_shared_state::<Vec<AudioClip>>(cx).unwrap();
+    cx.render(rsx!{
+        clips.read().iter().filter(|clip| clip.provider == cx.props.model).map(|clip|
+            {
+                let url = clip.url.to_string();
+                let asset = clip.asset.clone();
+                let chapters : Vec<String> = clip.chapters.iter()
+                    .map(|c| format!("{:.1}s to {:.1}s: {}",c.start,c.end,c.text))
+                    .collect();
+                let chapters_value = (clip.chapters.len() > 1).then(|| serde_json::to_value(&clip.chapters).unwrap());
+                rsx!(
+                    div {
+                        key: "{url}",
+                        audio { src: "{url}", controls: true }
+                        asset.map(|asset| rsx!( AssetField{ asset:asset } ))
+                        chapters_value.map(|value| rsx!(
+                            ol {
+                                style: "text-align:left;",
+                                for chapter in chapters.iter() {
+                                    li {
+                                        "{chapter}"
+                                    }
+                                }
+                            }
+                            span {
+                                "Chapters"
+                            }
+                            AddToField{ value:value }
+                        ))
+                    }
+                )
+            }
+        )
+    })
 }
 
 pub fn ElevenLabs(cx:Scope) -> Element {
-    use_shared_state_provider::<Vec<ObjectUrl>>(cx, || vec![]);
     let app_state = use_shared_state::<AppState>(cx).unwrap();
-    let model_resp = use_shared_state::<Vec<ObjectUrl>>(cx).unwrap();
+    let model_resp = use_shared_state::<Vec<AudioClip>>(cx).unwrap();
+    let run_log = use_shared_state::<RunLog>(cx).unwrap();
+    let ledger = use_shared_state::<UsageLedger>(cx).unwrap();
+    let cache = use_shared_state::<CacheSettings>(cx).unwrap();
     let keys = use_shared_state::<ApiKeys>(cx).unwrap();
     let similarity_boost = use_state(cx, || 0.70);
     let stability = use_state(cx, || 0.70);
     let style = use_state(cx, || 0.20);
     let use_speaker_boost = use_state(cx, || false);
     let voice_id = use_state(cx, || "".to_string());
+    // Kept as typed and checked on submit, so the field can be cleared while editing
+    let max_chunk_chars = use_state(cx, || "1000".to_string());
+    let error = use_state(cx, || "".to_string());
+    let characters = app_state.read().eleven_labs_edited.chars().count();
+    let chunks = match parse_chunk_chars(max_chunk_chars.get()) {
+        Ok(max_chars) => format!("{} chunks",chunk_text(&app_state.read().eleven_labs_edited,max_chars).len()),
+        Err(err) => err,
+    };
     let future_voices = use_future(cx, (&keys.read().eleven_labs), 
     |key| async move {
         if key.is_empty() {
@@ -818,36 +4063,61 @@ pub fn ElevenLabs(cx:Scope) -> Element {
                             p {
                                 "{app_state.read().eleven_labs_edited}"
                             }
+                            p {
+                                "{characters} characters ≈{format_usd(eleven_labs_cost(characters))}"
+                            }
+                        }
+                        div {
+                            p {
+                               "Characters per Chunk"
+                           }
+                           input {
+                               value: "{max_chunk_chars}",
+                               oninput: move |evt| max_chunk_chars.set(evt.value.clone()),
+                           },
+                           p {
+                               "{chunks}"
+                           }
                         }
                         div {
                             button{
                                 style: "width:6em;height:2em;",
                                 onclick: move |_| {
-                                        text_to_audio(
+                                        let narration = parse_chunk_chars(max_chunk_chars.get()).map(|max_chunk_chars| text_to_audio(
                                             model_resp.clone(),
+                                            RunRecorder::new(run_log,ledger,&app_state.read()),
+                                            cache.read().clone(),
                                             (*keys).read().eleven_labs.clone(),
                                             voice_id.current().as_ref().clone(),
-                                            app_state.read().dall_e_edited.clone(),
+                                            app_state.read().eleven_labs_edited.clone(),
                                             VoiceSettings { 
                                                 similarity_boost: similarity_boost.current().as_ref().clone(), 
                                                 stability: stability.current().as_ref().clone(),
                                                  style: style.current().as_ref().clone(), 
-                                                 use_speaker_boost: use_speaker_boost.current().as_ref().clone(), 
-                                                }
-                                        )
+                                                 use_speaker_boost: use_speaker_boost.current().as_ref().clone(),
+                                                },
+                                            max_chunk_chars,
+                                        ));
+                                        to_owned![error];
+                                        async move {
+                                            error.set("".to_string());
+                                            let result = match narration {
+                                                Ok(narration) => narration.await,
+                                                Err(err) => Err(err),
+                                            };
+                                            if let Err(err) = result {
+                                                error.set(err);
+                                            }
+                                        }
                                 },
                                 "Submit"
                             }
+                            p {
+                                style: "color:red;",
+                                "{error}"
+                            }
                        }
-                       (*model_resp.read()).iter().map(|obj|
-                        {
-                            let obj = obj.clone();
-                            let url = obj.parse::<String>().unwrap();
-                            rsx!(
-                                audio { key: "{url}", src: "{url}", controls: true }
-                            )
-                        }
-                    )
+                       AudioClips{ model:GenModel::ElevenLabs }
                     }
                 } else {
                     rsx!{
@@ -866,4 +4136,539 @@ pub fn ElevenLabs(cx:Scope) -> Element {
             }
         }
     })
-}
\ No newline at end of file
+}
+
+async fn fetch_open_ai_speech(
+    model_response:UseSharedState<Vec<AudioClip>>,
+    recorder:RunRecorder,
+    cache:CacheSettings,
+    key:String,
+    request:OpenAISpeechRequest,
+) -> Result<(),String> {
+    let mut generation = Generation::new("open_ai_speech",&request.model,&request.input);
+    let entry = cache_key("open_ai_speech",&serde_json::to_value(&request).unwrap());
+    let bytes = match cache_get(&cache,&entry).await {
+        Some(bytes) => {
+            generation.cached = true;
+            bytes
+        },
+        None => {
+            let bytes = match post_open_ai_speech(&recorder,&key,&request).await {
+                Ok(bytes) => bytes,
+                Err(err) => return Err(recorder.failed(generation,err)),
+            };
+            cache_put(&cache,&entry,&bytes).await;
+            generation.consumption = Some(Consumption::Characters(request.input.chars().count()));
+            bytes
+        },
+    };
+    replace_audio_clip(model_response, recorder, generation, AudioClip::new(GenModel::OpenAI,request.input.clone(),bytes,request.mime())).await;
+    Ok(())
+}
+
+async fn post_open_ai_speech(recorder:&RunRecorder, key:&str, request:&OpenAISpeechRequest) -> Result<Bytes,String> {
+    if let Some(reason) = recorder.cap_exceeded() {
+        return Err(reason);
+    }
+    let resp = reqwest::Client::new()
+        .post("https://api.openai.com/v1/audio/speech")
+        .header("Authorization",format!("Bearer {}",key))
+        .header("Content-Type","application/json")
+        .body(serde_json::to_string(request).unwrap())
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    // Error bodies are JSON, they mustn't be cached or played as audio.
+    if !resp.status().is_success() {
+        return Err(format!("OpenAI speech failed with {}: {}",resp.status(),resp.text().await.unwrap_or_default()));
+    }
+    resp.bytes().await.map_err(|e| e.to_string())
+}
+
+pub fn OpenAISpeech(cx:Scope) -> Element {
+    let app_state = use_shared_state::<AppState>(cx).unwrap();
+    let model_resp = use_shared_state::<Vec<AudioClip>>(cx).unwrap();
+    let run_log = use_shared_state::<RunLog>(cx).unwrap();
+    let ledger = use_shared_state::<UsageLedger>(cx).unwrap();
+    let cache = use_shared_state::<CacheSettings>(cx).unwrap();
+    let keys = use_shared_state::<ApiKeys>(cx).unwrap();
+    let model = use_state(cx, || "tts-1".to_string());
+    let voice = use_state(cx, || "alloy".to_string());
+    let error = use_state(cx, || "".to_string());
+    let speed = use_state(cx, || 1.);
+    let response_format = use_state(cx, || "mp3".to_string());
+    let characters = app_state.read().open_ai_speech_edited.chars().count();
+    let speech_cost = speech_cost(model.get(),characters).map(format_usd).unwrap_or_default();
+
+    cx.render(rsx!{
+        h3{"OpenAI Speech"}
+        div {
+            p {
+                "Model"
+            }
+            select {
+                onchange: move |evt| model.set(evt.value.clone()),
+                option {
+                    value:"tts-1",
+                    "tts-1"
+                },
+                option {
+                    value:"tts-1-hd",
+                    "tts-1-hd"
+                },
+            },
+        }
+        div {
+            p {
+                "Voice"
+            }
+            select {
+                onchange: move |evt| voice.set(evt.value.clone()),
+                ["alloy","echo","fable","onyx","nova","shimmer"].into_iter().map(|v|
+                    rsx!{
+                        option{
+                            value:v,
+                            v
+                        }
+                    }
+                )
+            },
+        }
+        div {
+            p {
+               "Speed"
+           }
+           input {
+               value: "{speed}",
+               oninput: move |evt| speed.set(evt.value.clone().parse::<f32>().unwrap_or(1.).clamp(0.25,4.)),
+           },
+        }
+        div {
+            p {
+                "Format"
+            }
+            select {
+                onchange: move |evt| response_format.set(evt.value.clone()),
+                ["mp3","opus","aac","flac"].into_iter().map(|f|
+                    rsx!{
+                        option{
+                            value:f,
+                            f
+                        }
+                    }
+                )
+            },
+        }
+        div {
+            p {
+               "Text"
+            }
+            textarea {
+                value: "{app_state.read().open_ai_speech_raw}",
+                oninput: move |evt| app_state.write().update_field(AppStateFieldUpdate::OpenAISpeech(evt.value.clone())),
+            },
+            p {
+                "{app_state.read().open_ai_speech_edited}"
+            }
+            p {
+                "{characters} characters ≈{speech_cost}"
+            }
+        }
+        div {
+            button{
+                style: "width:6em;height:2em;",
+                onclick: move |_| {
+                        let speech = fetch_open_ai_speech(
+                            model_resp.clone(),
+                            RunRecorder::new(run_log,ledger,&app_state.read()),
+                            cache.read().clone(),
+                            (*keys).read().open_ai.clone(),
+                            OpenAISpeechRequest {
+                                model: model.current().as_ref().clone(),
+                                input: app_state.read().open_ai_speech_edited.clone(),
+                                voice: voice.current().as_ref().clone(),
+                                speed: *speed.get(),
+                                response_format: response_format.current().as_ref().clone(),
+                            }
+                        );
+                        to_owned![error];
+                        async move {
+                            error.set("".to_string());
+                            if let Err(err) = speech.await {
+                                error.set(err);
+                            }
+                        }
+                },
+                "Submit"
+            }
+            p {
+                style: "color:red;",
+                "{error}"
+            }
+       }
+       AudioClips{ model:GenModel::OpenAI }
+    })
+}
+
+async fn fetch_transcription(
+    app_state:UseSharedState<AppState>,
+    model_response:UseSharedState<TranscriptionResponse>,
+    recorder:RunRecorder,
+    cache:CacheSettings,
+    key:String,
+    request:TranscriptionRequest,
+) -> Result<(),String> {
+    let mut generation = Generation::new("whisper","whisper-1",&request.file_name);
+    // The same audio under another name is still the same transcription
+    let entry = cache_key("whisper",&serde_json::json!({
+        "model":"whisper-1",
+        "file":asset_id(&request.bytes),
+        "language":request.language,
+        "prompt":request.prompt,
+    }));
+    let cached = cache_get(&cache,&entry).await
+        .and_then(|body| serde_json::from_slice::<TranscriptionResponse>(&body).ok());
+    let resp = match cached {
+        Some(resp) => {
+            generation.cached = true;
+            resp
+        },
+        None => transcribe(&recorder,&cache,&entry,&key,request).await
+            .map_err(|err| recorder.failed(generation.clone(),err))?,
+    };
+    generation.output = serde_json::Value::String(resp.text.clone());
+    if !generation.cached {
+        generation.consumption = Some(Consumption::AudioSeconds(resp.duration));
+    }
+    recorder.generation(generation);
+    app_state.write().update_transcript(resp.text.clone());
+    *model_response.write() = resp;
+    Ok(())
+}
+
+/// Sends the audio to Whisper and caches the transcription.
+async fn transcribe(
+    recorder:&RunRecorder,
+    cache:&CacheSettings,
+    entry:&str,
+    key:&str,
+    request:TranscriptionRequest,
+) -> Result<TranscriptionResponse,String> {
+    let TranscriptionRequest{ file_name, mime, bytes, language, prompt } = request;
+    let file = reqwest::multipart::Part::bytes(bytes)
+        .file_name(file_name)
+        .mime_str(&mime)
+        .unwrap();
+    let mut form = reqwest::multipart::Form::new()
+        .part("file",file)
+        .text("model","whisper-1")
+        .text("response_format","verbose_json")
+        .text("timestamp_granularities[]","segment")
+        .text("timestamp_granularities[]","word");
+    if !language.is_empty() {
+        form = form.text("language",language);
+    }
+    if !prompt.is_empty() {
+        form = form.text("prompt",prompt);
+    }
+    let body = post_transcription(recorder,key,form).await?;
+    let resp = serde_json::from_slice::<TranscriptionResponse>(&body).map_err(|e| e.to_string())?;
+    cache_put(cache,entry,&body).await;
+    Ok(resp)
+}
+
+async fn post_transcription(recorder:&RunRecorder, key:&str, form:reqwest::multipart::Form) -> Result<Bytes,String> {
+    if let Some(reason) = recorder.cap_exceeded() {
+        return Err(reason);
+    }
+    reqwest::Client::new()
+        .post("https://api.openai.com/v1/audio/transcriptions")
+        .header("Authorization",format!("Bearer {}",key))
+        .multipart(form)
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|e| e.to_string())?
+        .bytes()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+pub fn Whisper(cx:Scope) -> Element {
+    use_shared_state_provider(cx, TranscriptionResponse::default);
+    let app_state = use_shared_state::<AppState>(cx).unwrap();
+    let model_resp = use_shared_state::<TranscriptionResponse>(cx).unwrap();
+    let clips = use_shared_state::<Vec<AudioClip>>(cx).unwrap();
+    let run_log = use_shared_state::<RunLog>(cx).unwrap();
+    let ledger = use_shared_state::<UsageLedger>(cx).unwrap();
+    let cache = use_shared_state::<CacheSettings>(cx).unwrap();
+    let keys = use_shared_state::<ApiKeys>(cx).unwrap();
+    let audio_files: &UseRef<HashMap<String,Vec<u8>>> = use_ref(cx, HashMap::new);
+    let source = use_state(cx, || "upload".to_string());
+    let language = use_state(cx, || "".to_string());
+    let prompt = use_state(cx, || "".to_string());
+    let subtitles: &UseState<Option<ObjectUrl>> = use_state(cx, || None);
+    // The narration being checked, if the transcription came from one.
+    let narration = use_state(cx, || "".to_string());
+    let error = use_state(cx, || "".to_string());
+    let resp = model_resp.read().clone();
+    let transcribed = resp != TranscriptionResponse::default();
+    let mismatches = if narration.get().is_empty() {
+        vec![]
+    } else {
+        resp.mismatches(narration.get())
+    };
+    let subtitles_url = subtitles.get().as_ref().map(|url| url.to_string());
+
+    cx.render(rsx!{
+        h3{"Whisper"}
+        div {
+            p {
+                "Audio"
+            }
+            select {
+                onchange: move |evt| source.set(evt.value.clone()),
+                option {
+                    value:"upload",
+                    "Uploaded file"
+                },
+                option {
+                    value:"narration",
+                    "Latest narration"
+                },
+            },
+        }
+        if source.get() == "upload" {
+            rsx!{
+                div {
+                    input {
+                        r#type:"file",
+                        accept: "audio/*",
+                        multiple: true,
+                        onchange: |evt| {
+                            to_owned![audio_files];
+                            async move {
+                                if let Some(file_engine) = &evt.files {
+                                    for file_name in &file_engine.files() {
+                                        if let Some(file) = file_engine.read_file(file_name).await {
+                                            audio_files.write().insert(file_name.clone(),file);
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    p {
+                        "File name"
+                    }
+                    input {
+                        value: "{app_state.read().whisper_file_raw}",
+                        oninput: move |evt| app_state.write().update_field(AppStateFieldUpdate::WhisperFile(evt.value.clone())),
+                    },
+                    p {
+                        "{app_state.read().whisper_file_edited}"
+                    }
+                }
+            }
+        }
+        div {
+            p {
+               "Language"
+           }
+           input {
+               value: "{language}",
+               oninput: move |evt| language.set(evt.value.clone()),
+           },
+        }
+        div {
+            p {
+               "Prompt"
+           }
+           textarea {
+               value: "{prompt}",
+               oninput: move |evt| prompt.set(evt.value.clone()),
+           },
+        }
+        div {
+            button{
+                style: "width:6em;height:2em;",
+                onclick: move |_| {
+                    let audio = if source.get() == "narration" {
+                        clips.read().last().map(|clip|
+                            (clip.file_name().to_string(),clip.mime.clone(),clip.bytes.to_vec(),clip.text.clone())
+                        )
+                    } else {
+                        let file_name = app_state.read().whisper_file_edited.clone();
+                        audio_files.read().get(&file_name).map(|bytes|
+                            (file_name.clone(),"application/octet-stream".to_string(),bytes.clone(),String::new())
+                        )
+                    };
+                    let model_resp = model_resp.clone();
+                    let recorder = RunRecorder::new(run_log,ledger,&app_state.read());
+                    let cache = cache.read().clone();
+                    let app_state = app_state.clone();
+                    let key = (*keys).read().open_ai.clone();
+                    let language = language.current().as_ref().clone();
+                    let prompt = prompt.current().as_ref().clone();
+                    to_owned![narration,error];
+                    async move {
+                        error.set("".to_string());
+                        if let Some((file_name,mime,bytes,text)) = audio {
+                            narration.set(text);
+                            if let Err(err) = fetch_transcription(app_state,model_resp,recorder,cache,key,TranscriptionRequest{ file_name, mime, bytes, language, prompt }).await {
+                                error.set(err);
+                            }
+                        } else {
+                            error.set("no audio to transcribe".to_string());
+                        }
+                    }
+                },
+                "Submit"
+            }
+            p {
+                style: "color:red;",
+                "{error}"
+            }
+        }
+        if transcribed {
+            rsx!{
+                p {
+                    "{resp.text}"
+                }
+                AddToField{ value:serde_json::Value::String(resp.text.clone()) }
+                p {
+                    "Segments"
+                }
+                AddToField{ value:serde_json::to_value(&resp.segments).unwrap() }
+                p {
+                    "Words"
+                }
+                AddToField{ value:serde_json::to_value(&resp.words).unwrap() }
+                p {
+                    "Subtitles"
+                }
+                AddToField{ value:serde_json::Value::String(resp.to_srt()) }
+                button{
+                    style: "width:6em;height:2em;",
+                    onclick: move |_| {
+                        let blob = gloo::file::Blob::new_with_options(model_resp.read().to_srt().as_str(),Some("application/x-subrip"));
+                        subtitles.set(Some(ObjectUrl::from(blob)));
+                    },
+                    "SRT"
+                }
+                button{
+                    style: "width:6em;height:2em;",
+                    onclick: move |_| {
+                        let blob = gloo::file::Blob::new_with_options(model_resp.read().to_vtt().as_str(),Some("text/vtt"));
+                        subtitles.set(Some(ObjectUrl::from(blob)));
+                    },
+                    "VTT"
+                }
+                subtitles_url.map(|url| rsx!( a { href: "{url}", download: "subtitles", "download subtitles" } ))
+            }
+        }
+        if !narration.get().is_empty() && transcribed {
+            if mismatches.is_empty() {
+                rsx!( p { "Narration matches the source text." } )
+            } else {
+                rsx!{
+                    p {
+                        "Not heard in the narration:"
+                    }
+                    ul {
+                        mismatches.iter().map(|word| rsx!( li { "{word}" } ))
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn RunExport(cx:Scope) -> Element {
+    let run_log = use_shared_state::<RunLog>(cx).unwrap();
+    let ledger = use_shared_state::<UsageLedger>(cx).unwrap();
+    let dataset = use_shared_state::<Dataset>(cx).unwrap();
+    let format = use_state(cx, || ExportFormat::Csv);
+    let results: &UseState<Option<ObjectUrl>> = use_state(cx, || None);
+    let app_state = use_shared_state::<AppState>(cx).unwrap();
+    let payload = use_shared_state::<serde_json::Map<String,serde_json::Value>>(cx).unwrap();
+    let download: &UseState<Option<ObjectUrl>> = use_state(cx, || None);
+    let status = use_state(cx, || "".to_string());
+    let rows = run_log.read().rows.len();
+    let download_url = download.get().as_ref().map(|url| url.to_string());
+    let results_url = results.get().as_ref().map(|url| url.to_string());
+    let results_name = format!("results.{}",format.get().extension());
+    cx.render(rsx!{
+        div {
+            h5 {"Run"}
+            p {
+                "{rows} rows processed"
+            }
+            button{
+                style: "width:10em;height:2em;",
+                onclick: move |_| {
+                    // The current row's payload may not have been posted or left yet.
+                    if app_state.read().current_record.is_some() {
+                        RunRecorder::new(run_log,ledger,&app_state.read()).payload(serde_json::Value::Object(payload.read().clone()));
+                    }
+                    let run_log = run_log.read().clone();
+                    to_owned![download,status];
+                    async move {
+                        status.set("packaging run".to_string());
+                        match run_zip(&run_log).await {
+                            Ok(zip) => {
+                                let blob = gloo::file::Blob::new_with_options(zip.as_slice(),Some("application/zip"));
+                                download.set(Some(ObjectUrl::from(blob)));
+                                status.set("".to_string());
+                            },
+                            Err(err) => status.set(err),
+                        }
+                    }
+                },
+                "download run"
+            }
+            download_url.map(|url| rsx!( a { href: "{url}", download: "run.zip", "run.zip" } ))
+            select {
+                onchange: move |evt| format.set(match evt.value.as_str() {
+                    "jsonl" => ExportFormat::Jsonl,
+                    "parquet" => ExportFormat::Parquet,
+                    _ => ExportFormat::Csv,
+                }),
+                option {
+                    value:"csv",
+                    "CSV"
+                },
+                option {
+                    value:"jsonl",
+                    "JSONL"
+                },
+                option {
+                    value:"parquet",
+                    "Parquet"
+                },
+            },
+            button{
+                style: "width:10em;height:2em;",
+                onclick: move |_| {
+                    // Like the run, the table has to have the current row's payload.
+                    if app_state.read().current_record.is_some() {
+                        RunRecorder::new(run_log,ledger,&app_state.read()).payload(serde_json::Value::Object(payload.read().clone()));
+                    }
+                    let table = enriched_table(&dataset.read(),&run_log.read());
+                    match table.export(*format.get()) {
+                        Ok(bytes) => {
+                            let blob = gloo::file::Blob::new_with_options(bytes.as_slice(),Some(format.get().mime()));
+                            results.set(Some(ObjectUrl::from(blob)));
+                        },
+                        Err(err) => status.set(err),
+                    }
+                },
+                "export results"
+            }
+            results_url.map(|url| rsx!( a { href: "{url}", download: "{results_name}", "{results_name}" } ))
+            span {
+                "{status}"
+            }
+        }
+    })
+}
@@ -0,0 +1,102 @@
+use super::*;
+
+/// Context window and most tokens a single completion can have, by model prefix.
+/// More specific prefixes come first, like the price tables.
+const MODEL_LIMITS : [(&str,u32,u32);16] = [
+  ("gpt-4.1",1_047_576,32_768),
+  ("gpt-4.5",128_000,16_384),
+  ("gpt-4o-mini",128_000,16_384),
+  ("gpt-4o-2024-05-13",128_000,4_096),
+  ("gpt-4o",128_000,16_384),
+  ("chatgpt-4o",128_000,16_384),
+  ("gpt-4-turbo",128_000,4_096),
+  ("gpt-4-1106",128_000,4_096),
+  ("gpt-4-0125",128_000,4_096),
+  ("gpt-4-vision",128_000,4_096),
+  ("gpt-4-32k",32_768,32_768),
+  ("gpt-4",8_192,8_192),
+  ("gpt-3.5-turbo-16k",16_385,16_385),
+  ("gpt-3.5-turbo-0613",4_096,4_096),
+  ("gpt-3.5-turbo-0301",4_096,4_096),
+  ("gpt-3.5-turbo",16_385,4_096),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelLimits{
+    /// Prompt and completion tokens together
+    pub context_window:u32,
+    pub max_output:u32,
+}
+
+/// `None` for models missing from the registry, their requests aren't checked before they're sent.
+pub fn model_limits(model:&str) -> Option<ModelLimits> {
+  MODEL_LIMITS.iter()
+    .find(|(prefix,_,_)| model.starts_with(prefix))
+    .map(|(_,context_window,max_output)| ModelLimits{ context_window:*context_window, max_output:*max_output })
+}
+
+/// Why a request with `prompt_tokens` and `max_tokens` would be rejected by `model`, if it would.
+pub fn check_max_tokens(model:&str, prompt_tokens:u64, max_tokens:u32) -> Option<String> {
+  let limits = model_limits(model)?;
+  if max_tokens > limits.max_output {
+    return Some(format!("{} generates at most {} tokens",model,limits.max_output));
+  }
+  if prompt_tokens + max_tokens as u64 > limits.context_window as u64 {
+    return Some(format!(
+      "{} prompt tokens + {} max tokens is more than the {} token context of {}",
+      prompt_tokens,max_tokens,limits.context_window,model,
+    ));
+  }
+  None
+}
+
+/// Models `/v1/models` lists that take chat completions, leaving out the audio, realtime, search and other variants.
+/// The gpt-5 reasoning models are left out too, they reject `max_tokens` and sampling settings other than the defaults.
+pub fn is_chat_model(id:&str) -> bool {
+  (id.starts_with("gpt-") || id.starts_with("chatgpt-"))
+    && !id.starts_with("gpt-5")
+    && !["instruct","audio","realtime","transcribe","tts","search","image"].iter().any(|variant| id.contains(variant))
+}
+
+/// Chat models available to `key`, sorted.
+pub async fn fetch_models(key:String) -> Result<Vec<String>,String> {
+  let resp = reqwest::Client::new()
+    .get("https://api.openai.com/v1/models")
+    .header("Authorization",format!("Bearer {}",key))
+    .send()
+    .await
+    .and_then(|resp| resp.error_for_status())
+    .map_err(|e| e.to_string())?
+    .json::<ModelsResponse>()
+    .await
+    .map_err(|e| e.to_string())?;
+  let mut models : Vec<String> = resp.data.into_iter().map(|m| m.id).filter(|id| is_chat_model(id)).collect();
+  models.sort();
+  Ok(models)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn max_tokens_is_checked_against_the_output_limit_and_the_context() {
+    assert_eq!(check_max_tokens("gpt-4o-mini",1000,16_384),None);
+    assert_eq!(check_max_tokens("gpt-4o-2024-05-13",0,4_097),Some("gpt-4o-2024-05-13 generates at most 4096 tokens".to_string()));
+    assert_eq!(check_max_tokens("gpt-4",4_096,4_096),None);
+    assert_eq!(
+      check_max_tokens("gpt-4-0613",4_097,4_096),
+      Some("4097 prompt tokens + 4096 max tokens is more than the 8192 token context of gpt-4-0613".to_string()),
+    );
+    assert_eq!(check_max_tokens("mistral-large",1_000_000,1_000_000),None);
+  }
+
+  #[test]
+  fn reasoning_and_non_chat_models_are_left_out() {
+    assert!(is_chat_model("gpt-4.1-mini"));
+    assert!(is_chat_model("chatgpt-4o-latest"));
+    assert!(!is_chat_model("gpt-5-mini"));
+    assert!(!is_chat_model("o3-mini"));
+    assert!(!is_chat_model("gpt-4o-realtime-preview"));
+  }
+}
@@ -0,0 +1,165 @@
+use super::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModerationProvider{
+    /// `/v1/moderations`, needs the OpenAI key
+    OpenAi,
+    Blocklist,
+}
+
+/// What happens to a payload that gets flagged.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlaggedAction{
+    /// Recorded as blocked and never sent
+    Block,
+    /// Held until someone approves or rejects it
+    Review,
+}
+
+/// Checks every payload before it's posted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModerationSettings{
+    pub enabled:bool,
+    pub provider:ModerationProvider,
+    /// One entry per line, `/pattern/` is a regex, anything else a case insensitive keyword
+    pub blocklist:String,
+    /// Check the row the payload was built from as well
+    pub include_input:bool,
+    pub action:FlaggedAction,
+}
+impl Default for ModerationSettings{
+  fn default() -> Self {
+    Self{
+      enabled:false,
+      provider:ModerationProvider::OpenAi,
+      blocklist:"".to_string(),
+      include_input:false,
+      action:FlaggedAction::Review,
+    }
+  }
+}
+
+/// Outcome of moderating a payload, kept in the run log.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ModerationResult{
+    pub flagged:bool,
+    /// The categories or blocklist entries that were hit
+    pub reasons:Vec<String>,
+}
+
+/// The text of a payload worth moderating, every string in it except those that are just an asset reference, data URI or link.
+pub fn moderation_texts(payload:&serde_json::Value) -> Vec<String> {
+  let mut texts = vec![];
+  collect_texts(payload,&mut texts);
+  texts
+}
+
+fn collect_texts(value:&serde_json::Value, texts:&mut Vec<String>) {
+  match value {
+    serde_json::Value::String(s) => {
+      let trimmed = s.trim();
+      // Text that merely starts with a link, like "https://... is down", still gets checked.
+      let skip = ["asset:","data:","http://","https://"].iter().any(|prefix| trimmed.starts_with(prefix))
+        && !trimmed.contains(char::is_whitespace);
+      if !skip && !trimmed.is_empty() {
+        texts.push(s.clone());
+      }
+    },
+    serde_json::Value::Array(values) => values.iter().for_each(|v| collect_texts(v,texts)),
+    serde_json::Value::Object(map) => map.values().for_each(|v| collect_texts(v,texts)),
+    _ => (),
+  }
+}
+
+/// Blocklist entries found in any of the texts.
+pub fn blocklist_check(blocklist:&str, texts:&[String]) -> Result<Vec<String>,String> {
+  let mut hits = vec![];
+  for entry in blocklist.lines().map(str::trim).filter(|e| !e.is_empty()) {
+    let hit = match entry.strip_prefix('/').and_then(|e| e.strip_suffix('/')) {
+      Some(pattern) => {
+        let pattern = regex::Regex::new(pattern).map_err(|e| format!("blocklist entry {}: {}",entry,e))?;
+        texts.iter().any(|t| pattern.is_match(t))
+      },
+      None => {
+        let keyword = entry.to_lowercase();
+        texts.iter().any(|t| t.to_lowercase().contains(&keyword))
+      },
+    };
+    if hit {
+      hits.push(entry.to_string());
+    }
+  }
+  Ok(hits)
+}
+
+/// The flagged categories of each moderated text, deduplicated.
+pub fn flagged_categories(resp:&ModerationResponse) -> Vec<String> {
+  let mut categories : Vec<String> = resp.results.iter()
+    .filter(|r| r.flagged)
+    .flat_map(|r| r.categories.iter().filter(|(_,hit)| **hit).map(|(category,_)| category.clone()))
+    .collect();
+  categories.sort();
+  categories.dedup();
+  if categories.is_empty() && resp.results.iter().any(|r| r.flagged) {
+    categories.push("flagged".to_string());
+  }
+  categories
+}
+
+/// A payload on its way to the endpoint. When moderation holds it for review it's posted
+/// as it was when it was flagged once approved.
+#[derive(Clone)]
+pub struct PendingDelivery{
+    pub recorder:RunRecorder,
+    /// Index of the row in the uploaded file, for display
+    pub row:Option<usize>,
+    pub key:String,
+    pub endpoint:String,
+    pub json:String,
+    pub attachments:Vec<PayloadAttachment>,
+    /// Why moderation flagged it, empty until it's held
+    pub reasons:Vec<String>,
+}
+
+/// Held payloads by id, so approving or rejecting one can't hit another that took its place.
+#[derive(Clone, Default)]
+pub struct ReviewQueue{
+    pub pending:std::collections::BTreeMap<u64,PendingDelivery>,
+    next_id:u64,
+}
+impl ReviewQueue{
+  pub fn hold(&mut self, delivery:PendingDelivery) {
+    self.pending.insert(self.next_id,delivery);
+    self.next_id += 1;
+  }
+  /// `None` once the payload was approved or rejected.
+  pub fn take(&mut self, id:u64) -> Option<PendingDelivery> {
+    self.pending.remove(&id)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn only_bare_references_and_links_are_left_unmoderated() {
+    let payload = serde_json::json!({
+      "image":"asset:3f2a.png",
+      "link":" https://example.com/a ",
+      "thumbnail":"data:image/png;base64,iVBOR",
+      "body":"https://example.com is down, again",
+      "tags":["politics",""],
+    });
+    assert_eq!(moderation_texts(&payload),["https://example.com is down, again","politics"]);
+  }
+
+  #[test]
+  fn blocklist_matches_keywords_and_regexes() {
+    let texts = vec!["The Senate VOTE failed".to_string(),"call 555-0100".to_string()];
+    let blocklist = "vote\n\n /\\d{3}-\\d{4}/ \nrecall";
+    assert_eq!(blocklist_check(blocklist,&texts).unwrap(),["vote","/\\d{3}-\\d{4}/"]);
+    assert!(blocklist_check("/(/",&texts).is_err());
+    assert!(blocklist_check("",&texts).unwrap().is_empty());
+  }
+}
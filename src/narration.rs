@@ -0,0 +1,254 @@
+use super::*;
+
+/// Where one chunk of a narration sits in the concatenated audio.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Chapter{
+    pub index:usize,
+    /// Seconds from the start of the clip
+    pub start:f64,
+    pub end:f64,
+    pub text:String,
+}
+
+/// Words ending in a period that don't end a sentence.
+const ABBREVIATIONS : [&str;14] = ["mr","mrs","ms","dr","prof","st","jr","sr","vs","etc","e.g","i.e","no","inc"];
+
+/// Splits text after `.`, `!`, `?` and `…` followed by whitespace, and at blank lines.
+/// Abbreviations and initials don't end a sentence.
+pub fn split_sentences(text:&str) -> Vec<String> {
+  let mut sentences = vec![];
+  for paragraph in text.split("\n\n") {
+    let mut sentence = String::new();
+    let mut chars = paragraph.chars().peekable();
+    while let Some(c) = chars.next() {
+      sentence.push(c);
+      let ends = matches!(c,'.' | '!' | '?' | '…');
+      // Closing quotes and brackets belong to the sentence they close
+      while ends && matches!(chars.peek(),Some('"' | '\'' | '”' | '’' | ')' | ']')) {
+        sentence.push(chars.next().unwrap());
+      }
+      if ends && chars.peek().is_some_and(|c| c.is_whitespace()) && !(c == '.' && is_abbreviation(&sentence)) {
+        sentences.push(sentence.trim().to_string());
+        sentence.clear();
+      }
+    }
+    if !sentence.trim().is_empty() {
+      sentences.push(sentence.trim().to_string());
+    }
+  }
+  sentences.retain(|s| !s.is_empty());
+  sentences
+}
+
+fn is_abbreviation(sentence:&str) -> bool {
+  let word = sentence.trim_end_matches('.').rsplit(char::is_whitespace).next().unwrap_or_default().to_lowercase();
+  let single_letter = word.chars().count() == 1 && word.chars().all(char::is_alphabetic);
+  single_letter || ABBREVIATIONS.contains(&word.as_str())
+}
+
+/// The most characters ElevenLabs takes in one request.
+pub const MAX_CHUNK_CHARS : usize = 5000;
+
+/// Characters per chunk as typed into the form.
+pub fn parse_chunk_chars(text:&str) -> Result<usize,String> {
+  match text.trim().parse::<usize>() {
+    Ok(n) if (1..=MAX_CHUNK_CHARS).contains(&n) => Ok(n),
+    _ => Err(format!("characters per chunk must be a whole number from 1 to {}",MAX_CHUNK_CHARS)),
+  }
+}
+
+/// Packs whole sentences into chunks of at most `max_chars`, sentences longer than that are split between words.
+pub fn chunk_text(text:&str, max_chars:usize) -> Vec<String> {
+  let max_chars = max_chars.max(1);
+  let mut chunks = vec![];
+  let mut chunk = String::new();
+  let push = |piece:&str, chunks:&mut Vec<String>, chunk:&mut String| {
+    let separator = if chunk.is_empty() { 0 } else { 1 };
+    if chunk.chars().count() + separator + piece.chars().count() > max_chars && !chunk.is_empty() {
+      chunks.push(std::mem::take(chunk));
+    }
+    if !chunk.is_empty() {
+      chunk.push(' ');
+    }
+    chunk.push_str(piece);
+  };
+  for sentence in split_sentences(text) {
+    if sentence.chars().count() <= max_chars {
+      push(&sentence,&mut chunks,&mut chunk);
+      continue;
+    }
+    for word in sentence.split_whitespace() {
+      // A single word longer than a chunk can only be cut
+      let letters : Vec<char> = word.chars().collect();
+      for piece in letters.chunks(max_chars) {
+        push(&piece.iter().collect::<String>(),&mut chunks,&mut chunk);
+      }
+    }
+  }
+  if !chunk.is_empty() {
+    chunks.push(chunk);
+  }
+  chunks
+}
+
+/// The MPEG audio of an MP3 without its ID3 tags, so segments can be played back to back.
+pub fn mp3_audio(bytes:&[u8]) -> &[u8] {
+  let mut start = 0;
+  if bytes.len() >= 10 && bytes.starts_with(b"ID3") {
+    // The size is synchsafe, 7 bits per byte, and doesn't include the header or footer
+    let size = bytes[6..10].iter().fold(0usize,|size,b| (size << 7) | (*b & 0x7f) as usize);
+    let footer = if bytes[5] & 0x10 != 0 { 10 } else { 0 };
+    start = (10 + size + footer).min(bytes.len());
+  }
+  let mut end = bytes.len();
+  if end - start >= 128 && &bytes[end - 128..end - 125] == b"TAG" {
+    end -= 128;
+  }
+  &bytes[start..end]
+}
+
+/// The audio without its leading Xing, Info or VBRI frame. Encoders put one at the start of a file
+/// to describe the whole of it, in the middle of a clip it's wrong and some players stop there.
+pub fn without_info_frame(bytes:&[u8]) -> &[u8] {
+  let Some((len,_,_)) = bytes.get(..4).and_then(frame_header) else {
+    return bytes;
+  };
+  // The tag follows the side information, which is shorter for mono and MPEG 2
+  let mono = bytes[3] >> 6 == 0b11;
+  let mpeg1 = (bytes[1] >> 3) & 0b11 == 3;
+  let side_info = match (mpeg1,mono) {
+    (true,false) => 32,
+    (true,true) | (false,false) => 17,
+    (false,true) => 9,
+  };
+  let tag_at = |offset:usize,tags:&[&[u8]]| bytes.get(offset..offset + 4).is_some_and(|tag| tags.contains(&tag));
+  if len <= bytes.len() && (tag_at(4 + side_info,&[b"Xing",b"Info"]) || tag_at(36,&[b"VBRI"])) {
+    &bytes[len..]
+  } else {
+    bytes
+  }
+}
+
+/// Seconds of audio in MPEG layer III frames, skipping anything between them that isn't a frame
+/// and the Xing or Info frame at the start, which players don't play.
+pub fn mp3_duration(bytes:&[u8]) -> f64 {
+  let bytes = without_info_frame(bytes);
+  let mut seconds = 0.;
+  let mut i = 0;
+  while i + 4 <= bytes.len() {
+    match frame_header(&bytes[i..i + 4]) {
+      Some((len,samples,rate)) => {
+        seconds += samples as f64 / rate as f64;
+        i += len;
+      },
+      None => i += 1,
+    }
+  }
+  seconds
+}
+
+/// Length in bytes, samples and sample rate of the frame starting with `header`.
+fn frame_header(header:&[u8]) -> Option<(usize,u32,u32)> {
+  const MPEG1_BITRATES : [u32;15] = [0,32,40,48,56,64,80,96,112,128,160,192,224,256,320];
+  const MPEG2_BITRATES : [u32;15] = [0,8,16,24,32,40,48,56,64,80,96,112,128,144,160];
+  const SAMPLE_RATES : [u32;3] = [44100,48000,32000];
+  if header[0] != 0xff || header[1] & 0xe0 != 0xe0 {
+    return None;
+  }
+  // 3 is MPEG 1, 2 MPEG 2 and 0 MPEG 2.5, layer 1 is layer III
+  let version = (header[1] >> 3) & 0b11;
+  let layer = (header[1] >> 1) & 0b11;
+  let bitrate = (header[2] >> 4) as usize;
+  let rate = ((header[2] >> 2) & 0b11) as usize;
+  let padding = ((header[2] >> 1) & 1) as u32;
+  if version == 1 || layer != 1 || bitrate == 0 || bitrate == 15 || rate == 3 {
+    return None;
+  }
+  let (bitrate,rate,samples) = match version {
+    3 => (MPEG1_BITRATES[bitrate],SAMPLE_RATES[rate],1152),
+    2 => (MPEG2_BITRATES[bitrate],SAMPLE_RATES[rate] / 2,576),
+    _ => (MPEG2_BITRATES[bitrate],SAMPLE_RATES[rate] / 4,576),
+  };
+  let len = samples / 8 * bitrate * 1000 / rate + padding;
+  Some((len as usize,samples,rate))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// An MPEG 1 layer III frame at 128 kbps and 44.1 kHz, 417 bytes and 1152 samples long.
+  fn frame(mono:bool, tag:Option<&[u8]>) -> Vec<u8> {
+    let mut frame = vec![0;417];
+    frame[..4].copy_from_slice(&[0xff,0xfb,0x90,if mono { 0xc4 } else { 0x44 }]);
+    if let Some(tag) = tag {
+      let offset = if mono { 21 } else { 36 };
+      frame[offset..offset + 4].copy_from_slice(tag);
+    }
+    frame
+  }
+
+  #[test]
+  fn sentences_end_at_punctuation_but_not_abbreviations() {
+    let text = "Dr. Smith met J. R. Doe (at St. Mary's). \"Why?\" she asked… Then left!\n\nNew paragraph\nwithout a stop";
+    assert_eq!(split_sentences(text),[
+      "Dr. Smith met J. R. Doe (at St. Mary's).",
+      "\"Why?\"",
+      "she asked…",
+      "Then left!",
+      "New paragraph\nwithout a stop",
+    ]);
+    assert!(split_sentences(" \n\n ").is_empty());
+  }
+
+  #[test]
+  fn chunks_pack_whole_sentences_and_cut_what_doesnt_fit() {
+    assert_eq!(chunk_text("One two. Three four. Five.",20),["One two. Three four.","Five."]);
+    assert_eq!(chunk_text("Short. A sentence far too long.",10),["Short. A","sentence","far too","long."]);
+    assert_eq!(chunk_text("Abcdefghij.",4),["Abcd","efgh","ij."]);
+    assert!(chunk_text("",10).is_empty());
+  }
+
+  #[test]
+  fn chunk_size_is_checked_as_typed() {
+    assert_eq!(parse_chunk_chars(" 250 "),Ok(250));
+    assert!(parse_chunk_chars("").is_err());
+    assert!(parse_chunk_chars("0").is_err());
+    assert!(parse_chunk_chars("5001").is_err());
+  }
+
+  #[test]
+  fn duration_counts_audio_frames_only() {
+    let seconds_per_frame = 1152. / 44100.;
+    let mut bytes = frame(false,Some(b"Info"));
+    bytes.extend([0;7]);
+    for _ in 0..3 {
+      bytes.extend(frame(false,None));
+    }
+    assert!((mp3_duration(&bytes) - 3. * seconds_per_frame).abs() < 1e-9);
+    assert_eq!(mp3_duration(b"not audio"),0.);
+  }
+
+  #[test]
+  fn only_a_leading_info_frame_is_dropped() {
+    for (mono,tag) in [(false,b"Xing"),(true,b"Info")] {
+      let mut bytes = frame(mono,Some(tag));
+      bytes.extend(frame(mono,None));
+      assert_eq!(without_info_frame(&bytes),frame(mono,None));
+    }
+    let audio = [frame(false,None),frame(false,Some(b"Info"))].concat();
+    assert_eq!(without_info_frame(&audio),audio);
+    assert_eq!(without_info_frame(&frame(true,Some(b"Xing"))[..100]).len(),100);
+  }
+
+  #[test]
+  fn id3_tags_are_left_out() {
+    let audio = frame(false,None);
+    let mut bytes = b"ID3\x04\x00\x00\x00\x00\x01\x00".to_vec();
+    bytes.extend([0;128]);
+    bytes.extend(&audio);
+    bytes.extend(b"TAG");
+    bytes.extend([0;125]);
+    assert_eq!(mp3_audio(&bytes),audio);
+  }
+}
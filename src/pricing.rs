@@ -0,0 +1,298 @@
+use super::*;
+
+/// USD per million prompt and completion tokens, by model prefix.
+/// More specific prefixes come first, `gpt-4o-mini` and `gpt-4.1` have to match before `gpt-4o` and `gpt-4`.
+const CHAT_PRICES : [(&str,f64,f64);15] = [
+  ("gpt-4.1-nano",0.1,0.4),
+  ("gpt-4.1-mini",0.4,1.6),
+  ("gpt-4.1",2.,8.),
+  ("gpt-4.5",75.,150.),
+  ("gpt-4o-mini",0.15,0.6),
+  ("gpt-4o",2.5,10.),
+  ("chatgpt-4o",5.,15.),
+  ("gpt-4-turbo",10.,30.),
+  ("gpt-4-vision",10.,30.),
+  ("gpt-4-1106",10.,30.),
+  ("gpt-4-0125",10.,30.),
+  ("gpt-4-32k",60.,120.),
+  ("gpt-4",30.,60.),
+  ("gpt-3.5-turbo-16k",3.,4.),
+  ("gpt-3.5-turbo",0.5,1.5),
+];
+
+/// USD per image by model, size and quality.
+const IMAGE_PRICES : [(&str,&str,&str,f64);9] = [
+  ("dall-e-3","1024x1024","standard",0.04),
+  ("dall-e-3","1024x1792","standard",0.08),
+  ("dall-e-3","1792x1024","standard",0.08),
+  ("dall-e-3","1024x1024","hd",0.08),
+  ("dall-e-3","1024x1792","hd",0.12),
+  ("dall-e-3","1792x1024","hd",0.12),
+  ("dall-e-2","1024x1024","standard",0.02),
+  ("dall-e-2","512x512","standard",0.018),
+  ("dall-e-2","256x256","standard",0.016),
+];
+
+/// USD per million characters of OpenAI speech.
+const SPEECH_PRICES : [(&str,f64);2] = [
+  ("tts-1-hd",30.),
+  ("tts-1",15.),
+];
+
+/// USD per thousand characters, the overage rate of the Creator plan.
+pub const ELEVEN_LABS_PRICE_PER_1K_CHARS : f64 = 0.3;
+
+/// USD per minute of Whisper transcription, billed by the second.
+pub const WHISPER_PRICE_PER_MINUTE : f64 = 0.006;
+
+pub fn chat_cost(model:&str, prompt_tokens:u64, completion_tokens:u64) -> Option<f64> {
+  CHAT_PRICES.iter()
+    .find(|(prefix,_,_)| model.starts_with(prefix))
+    .map(|(_,prompt,completion)| (prompt_tokens as f64 * prompt + completion_tokens as f64 * completion) / 1_000_000.)
+}
+
+/// Sizes missing from the table are priced as 1024x1024.
+pub fn image_cost(model:&str, size:&str, quality:Option<&str>, n:u32) -> Option<f64> {
+  let quality = quality.unwrap_or("standard");
+  IMAGE_PRICES.iter()
+    .find(|(m,s,q,_)| *m == model && *s == size && *q == quality)
+    .or_else(|| IMAGE_PRICES.iter().find(|(m,s,q,_)| *m == model && *s == "1024x1024" && *q == quality))
+    .map(|(_,_,_,price)| price * n as f64)
+}
+
+pub fn speech_cost(model:&str, characters:usize) -> Option<f64> {
+  SPEECH_PRICES.iter()
+    .find(|(prefix,_)| model.starts_with(prefix))
+    .map(|(_,price)| characters as f64 * price / 1_000_000.)
+}
+
+pub fn eleven_labs_cost(characters:usize) -> f64 {
+  characters as f64 * ELEVEN_LABS_PRICE_PER_1K_CHARS / 1000.
+}
+
+pub fn transcription_cost(seconds:f64) -> f64 {
+  seconds.ceil() * WHISPER_PRICE_PER_MINUTE / 60.
+}
+
+/// Models tokenized with o200k, the gpt-3.5 and older gpt-4 models use cl100k.
+const O200K_MODELS : [&str;4] = ["gpt-4o","chatgpt-4o","gpt-4.1","gpt-4.5"];
+
+/// Token ids of `text` in the encoding `model` uses, e.g. to bias them.
+pub fn token_ids(model:&str, text:&str) -> Vec<usize> {
+  if O200K_MODELS.iter().any(|prefix| model.starts_with(prefix)) {
+    tiktoken_rs::o200k_base_singleton().lock().encode_with_special_tokens(text)
+  } else {
+    tiktoken_rs::cl100k_base_singleton().lock().encode_with_special_tokens(text)
+  }
+}
+
+pub fn count_tokens(model:&str, text:&str) -> usize {
+  token_ids(model,text).len()
+}
+
+/// Tokens billed for a chat prompt, each message costs 3 tokens on top of its content
+/// and the reply is primed with another 3.
+pub fn chat_prompt_tokens(model:&str, messages:&[&str]) -> usize {
+  messages.iter().map(|m| count_tokens(model,m) + 3).sum::<usize>() + 3
+}
+
+pub fn format_usd(usd:f64) -> String {
+  if usd < 0.01 && usd > 0. {
+    format!("${:.4}",usd)
+  } else {
+    format!("${:.2}",usd)
+  }
+}
+
+/// Estimated cost of running the ChatGPT step over a set of rows.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CostEstimate{
+    pub rows:usize,
+    pub prompt_tokens:u64,
+    /// The most that can be generated, `max_tokens` for each choice of each row
+    pub completion_tokens:u64,
+    /// `None` when the model isn't in the price table
+    pub cost:Option<f64>,
+    /// Hash of the settings and rows the estimate was made for, it's stale once they change
+    pub fingerprint:u64,
+    /// Prompt tokens of the longest single request, to check it fits the model's context
+    pub largest_prompt:u64,
+    /// What judging the choices of every row adds, already included in `cost`
+    pub judge_prompt_tokens:u64,
+    pub judge_completion_tokens:u64,
+}
+
+/// Rows tokenized between giving the browser a chance to render.
+const ROWS_PER_CHUNK : usize = 200;
+
+/// Prompt tokens of each request of the conversation for a row. Each follow-up turn resends the
+/// conversation so far, previous answers are counted at `max_tokens`.
+pub fn conversation_prompt_tokens(
+  app_state:&AppState,
+  model:&str,
+  examples:&[FewShotExample],
+  follow_ups:&[String],
+  max_tokens:u32,
+  row:Option<(&Dataset,usize)>,
+) -> Vec<u64> {
+  let messages = app_state.chat_messages(examples,row);
+  let contents : Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+  let mut context = chat_prompt_tokens(model,&contents) as u64;
+  let mut requests = vec![context];
+  for follow_up in follow_ups {
+    let follow_up = app_state.render_for(follow_up,row);
+    context += max_tokens as u64 + 3 + count_tokens(model,&follow_up) as u64 + 3;
+    requests.push(context);
+  }
+  requests
+}
+
+/// Prompt tokens of the judge's request for a row, the choices it reads are counted at `max_tokens`.
+pub fn judge_prompt_tokens(
+  app_state:&AppState,
+  settings:&SelectionSettings,
+  follow_ups:&[String],
+  max_tokens:u32,
+  n:u8,
+  row:Option<(&Dataset,usize)>,
+) -> u64 {
+  // The judge is shown the last user turn of the conversation.
+  let task = app_state.render_for(follow_ups.last().unwrap_or(&app_state.chat_gpt_prompt_raw),row);
+  let rubric = app_state.render_for(&settings.rubric,row);
+  let messages = judge_messages(&rubric,&task,&[]);
+  let contents : Vec<&str> = messages.iter().map(|m| m.content.as_str()).collect();
+  let candidates = (1..=n).map(|i| count_tokens(&settings.judge_model,&format!("\n\nCandidate {}:\n",i)) as u64 + max_tokens as u64).sum::<u64>();
+  chat_prompt_tokens(&settings.judge_model,&contents) as u64 + candidates
+}
+
+/// The ChatGPT settings a batch is estimated for.
+#[derive(Debug, Clone)]
+pub struct ChatBatchSettings{
+    pub model:String,
+    pub examples:Vec<FewShotExample>,
+    pub follow_ups:Vec<String>,
+    pub max_tokens:u32,
+    /// Completions per request
+    pub n:u8,
+    /// With a judge its call for every row is priced in too
+    pub judge:Option<SelectionSettings>,
+}
+
+/// Counts the prompt tokens of the conversation rendered for every row and prices them along with
+/// the most the completions can cost.
+pub async fn estimate_chat_batch(
+  app_state:&AppState,
+  dataset:&Dataset,
+  rows:&[usize],
+  settings:&ChatBatchSettings,
+  fingerprint:u64,
+) -> CostEstimate {
+  let ChatBatchSettings{ model, examples, follow_ups, max_tokens, n, judge } = settings;
+  let (max_tokens,n,judge) = (*max_tokens,*n,judge.as_ref());
+  let mut prompt_tokens = 0;
+  let mut largest_prompt = 0;
+  let mut judge_prompt = 0;
+  for (chunk,i) in rows.iter().enumerate() {
+    let requests = conversation_prompt_tokens(app_state,model,examples,follow_ups,max_tokens,Some((dataset,*i)));
+    prompt_tokens += requests.iter().sum::<u64>();
+    largest_prompt = requests.into_iter().fold(largest_prompt,u64::max);
+    if let Some(judge) = judge {
+      judge_prompt += judge_prompt_tokens(app_state,judge,follow_ups,max_tokens,n,Some((dataset,*i)));
+    }
+    if chunk % ROWS_PER_CHUNK == ROWS_PER_CHUNK - 1 {
+      gloo::timers::future::TimeoutFuture::new(0).await;
+    }
+  }
+  let requests = rows.len() as u64 * (follow_ups.len() as u64 + 1);
+  let completion_tokens = requests * max_tokens as u64 * n as u64;
+  let judge_completion = if judge.is_some() { rows.len() as u64 * JUDGE_MAX_TOKENS as u64 } else { 0 };
+  let judge_cost = match judge {
+    Some(judge) => chat_cost(&judge.judge_model,judge_prompt,judge_completion),
+    None => Some(0.),
+  };
+  CostEstimate{
+    rows:rows.len(),
+    prompt_tokens,
+    completion_tokens,
+    cost:chat_cost(model,prompt_tokens,completion_tokens).zip(judge_cost).map(|(chat,judge)| chat + judge),
+    fingerprint,
+    largest_prompt,
+    judge_prompt_tokens:judge_prompt,
+    judge_completion_tokens:judge_completion,
+  }
+}
+
+/// Runs costing more than the threshold need a budget owner's sign-off before they start.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Budget{
+    /// USD
+    pub sign_off_threshold:f64,
+}
+impl Default for Budget{
+  fn default() -> Self {
+    Self{
+      sign_off_threshold:10.,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn chat_cost_matches_the_most_specific_prefix() {
+    assert_eq!(chat_cost("gpt-4o-mini-2024-07-18",1_000_000,1_000_000),Some(0.75));
+    assert_eq!(chat_cost("gpt-4o",1_000_000,0),Some(2.5));
+    assert_eq!(chat_cost("gpt-4.1-2025-04-14",1_000_000,1_000_000),Some(10.));
+    assert_eq!(chat_cost("gpt-4.1-mini",1_000_000,1_000_000),Some(2.));
+    assert_eq!(chat_cost("gpt-4.1-nano",1_000_000,1_000_000),Some(0.5));
+    assert_eq!(chat_cost("gpt-4.5-preview",1_000_000,0),Some(75.));
+    assert_eq!(chat_cost("chatgpt-4o-latest",0,1_000_000),Some(15.));
+    assert_eq!(chat_cost("gpt-4-vision-preview",1_000_000,0),Some(10.));
+    assert_eq!(chat_cost("gpt-4-0613",1000,500),Some(0.06));
+    assert_eq!(chat_cost("gpt-3.5-turbo-16k-0613",0,1_000_000),Some(4.));
+    assert_eq!(chat_cost("llama-3",1000,1000),None);
+  }
+
+  #[test]
+  fn gpt_4o_and_later_use_o200k() {
+    let text = "Résumé of the quarterly earnings";
+    assert_eq!(token_ids("gpt-4o-mini",text),token_ids("gpt-4.1",text));
+    assert_ne!(token_ids("gpt-4o-mini",text),token_ids("gpt-4-turbo",text));
+    assert_eq!(token_ids("gpt-4-turbo",text),token_ids("gpt-3.5-turbo",text));
+  }
+
+  #[test]
+  fn the_judge_reads_every_choice_at_max_tokens() {
+    let app_state = AppState{ chat_gpt_prompt_raw:"Write a title".to_string(), ..Default::default() };
+    let settings = SelectionSettings{ rubric:"Catchy".to_string(), ..Default::default() };
+    let one = judge_prompt_tokens(&app_state,&settings,&[],100,1,None);
+    let three = judge_prompt_tokens(&app_state,&settings,&[],100,3,None);
+    assert!(one > 100);
+    assert_eq!(three - one,2 * (100 + count_tokens("gpt-4o-mini","\n\nCandidate 2:\n") as u64));
+  }
+
+  #[test]
+  fn transcriptions_are_billed_by_the_started_second() {
+    assert_eq!(transcription_cost(60.),0.006);
+    assert_eq!(transcription_cost(0.5),transcription_cost(1.));
+  }
+
+  #[test]
+  fn follow_ups_resend_the_conversation() {
+    let app_state = AppState{
+      chat_gpt_system_raw:"You write headlines".to_string(),
+      chat_gpt_prompt_raw:"Summarize {0}".to_string(),
+      ..Default::default()
+    };
+    let dataset = Dataset{ rows:vec![["hello world"].iter().collect()], ..Default::default() };
+    assert_eq!(count_tokens("gpt-4","hello world"),2);
+    let requests = conversation_prompt_tokens(&app_state,"gpt-4",&[],&["Shorter, about {0}".to_string()],100,Some((&dataset,0)));
+    let first = chat_prompt_tokens("gpt-4",&["You write headlines","Summarize hello world"]) as u64;
+    assert_eq!(requests,[
+      first,
+      first + 100 + 3 + count_tokens("gpt-4","Shorter, about hello world") as u64 + 3,
+    ]);
+  }
+}
@@ -0,0 +1,238 @@
+use super::*;
+use std::collections::BTreeMap;
+
+/// Everything generated and sent for the rows processed in this session.
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct RunLog{
+    pub rows:BTreeMap<usize,RowRun>,
+    /// Calls made before any row of the dataset was loaded
+    pub ad_hoc:Option<RowRun>,
+    pub batches:Vec<BatchRun>,
+}
+impl RunLog{
+  pub fn row_mut(&mut self, row:Option<usize>, record:&[String]) -> &mut RowRun {
+    let run = || RowRun{
+      row,
+      record:record.to_vec(),
+      ..Default::default()
+    };
+    match row {
+      Some(i) => self.rows.entry(i).or_insert_with(run),
+      None => self.ad_hoc.get_or_insert_with(run),
+    }
+  }
+}
+
+/// A ChatGPT batch as it was started or resumed, and who signed off on its cost.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BatchRun{
+    pub model:String,
+    pub rows:usize,
+    /// Rows already done when a stopped or paused batch was resumed
+    pub resumed_at:Option<usize>,
+    /// USD, `None` when the model isn't in the price table
+    pub estimated_cost:Option<f64>,
+    pub sign_off_threshold:f64,
+    /// `None` when the estimate was under the threshold
+    pub approved_by:Option<String>,
+    /// RFC 3339
+    pub timestamp:String,
+}
+
+/// One row of the dataset and what happened to it.
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct RowRun{
+    /// Index of the row in the uploaded file, `None` for the ad hoc calls
+    pub row:Option<usize>,
+    pub record:Vec<String>,
+    pub generations:Vec<Generation>,
+    /// Choices picked automatically and how they scored
+    pub selections:Vec<Selection>,
+    /// The last payload built for this row
+    pub payload:Option<serde_json::Value>,
+    /// How the last payload fared in moderation, when it was on
+    pub moderation:Option<ModerationResult>,
+    pub delivery:Option<Delivery>,
+}
+
+/// A single provider call.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Generation{
+    /// e.g. `chat_gpt`, `dall_e`, `eleven_labs`
+    pub provider:String,
+    pub model:String,
+    pub prompt:String,
+    pub usage:Option<TokenUsage>,
+    /// What the call is billed for, booked into the usage ledger when recorded
+    pub consumption:Option<Consumption>,
+    /// The output came from the response cache rather than the provider
+    pub cached:bool,
+    /// Provider specific output worth keeping, e.g. the message choices
+    pub output:serde_json::Value,
+    /// Why the call failed, `output` is empty then
+    pub error:Option<String>,
+    /// Seed the call was made with, together with `system_fingerprint` what's needed to reproduce the output
+    pub seed:Option<i64>,
+    pub system_fingerprint:Option<String>,
+    pub assets:Vec<AssetRef>,
+    /// RFC 3339
+    pub timestamp:String,
+}
+impl Generation{
+  pub fn new(provider:&str, model:&str, prompt:&str) -> Self {
+    Self{
+      provider:provider.to_string(),
+      model:model.to_string(),
+      prompt:prompt.to_string(),
+      usage:None,
+      consumption:None,
+      cached:false,
+      output:serde_json::Value::Null,
+      error:None,
+      seed:None,
+      system_fingerprint:None,
+      assets:vec![],
+      timestamp:chrono::Utc::now().to_rfc3339(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Delivery{
+    pub endpoint:String,
+    /// The HTTP status, or the error when the request didn't go through
+    pub status:String,
+    /// RFC 3339
+    pub timestamp:String,
+}
+
+/// The payload a row starts from, the objects of the one being edited without any of its values,
+/// so nothing generated for another row is carried over, and what was recorded for the row laid over it.
+pub fn row_payload(map:&serde_json::Map<String,serde_json::Value>, recorded:Option<&serde_json::Value>) -> serde_json::Map<String,serde_json::Value> {
+  let mut payload = payload_template(map);
+  if let Some(serde_json::Value::Object(recorded)) = recorded {
+    merge_payload(&mut payload,recorded.clone());
+  }
+  payload
+}
+
+fn payload_template(map:&serde_json::Map<String,serde_json::Value>) -> serde_json::Map<String,serde_json::Value> {
+  map.iter()
+    .filter_map(|(key,value)| value.as_object().map(|object| (key.clone(),serde_json::Value::Object(payload_template(object)))))
+    .collect()
+}
+
+/// Lays a recorded payload over another one, fields it doesn't set are kept.
+fn merge_payload(map:&mut serde_json::Map<String,serde_json::Value>, recorded:serde_json::Map<String,serde_json::Value>) {
+  for (key,value) in recorded {
+    match (map.get_mut(&key),value) {
+      (Some(serde_json::Value::Object(current)),serde_json::Value::Object(value)) => merge_payload(current,value),
+      (_,value) => {
+        map.insert(key,value);
+      },
+    }
+  }
+}
+
+/// Records calls against the row that was current when the call was made,
+/// the user may have moved on by the time the response arrives.
+#[derive(Clone)]
+pub struct RunRecorder{
+    run_log:UseSharedState<RunLog>,
+    ledger:UseSharedState<UsageLedger>,
+    row:Option<usize>,
+    record:Vec<String>,
+}
+impl RunRecorder{
+  pub fn new(run_log:&UseSharedState<RunLog>, ledger:&UseSharedState<UsageLedger>, app_state:&AppState) -> Self {
+    Self{
+      run_log:run_log.clone(),
+      ledger:ledger.clone(),
+      row:app_state.current_row,
+      record:app_state.current_record.as_ref().map(|r| r.iter().map(|s| s.to_string()).collect()).unwrap_or_default(),
+    }
+  }
+  /// Records against any row of the dataset, for batches that don't go through the current one.
+  pub fn for_row(run_log:&UseSharedState<RunLog>, ledger:&UseSharedState<UsageLedger>, dataset:&Dataset, row:usize) -> Self {
+    Self{
+      run_log:run_log.clone(),
+      ledger:ledger.clone(),
+      row:Some(row),
+      record:dataset.rows.get(row).map(|r| r.iter().map(|s| s.to_string()).collect()).unwrap_or_default(),
+    }
+  }
+  /// Why no more may be spent, checked before every call that isn't answered from the cache.
+  pub fn cap_exceeded(&self) -> Option<String> {
+    self.ledger.read().cap_exceeded().map(|reason| format!("spending cap reached, {}",reason))
+  }
+  /// Records the call and books what it consumed under the current project.
+  pub fn generation(&self, generation:Generation) {
+    if let Some(consumption) = &generation.consumption {
+      let mut ledger = self.ledger.write();
+      ledger.record(&generation.model,consumption);
+      if let Err(err) = ledger.save() {
+        log::error!("couldn't save usage: {}",err);
+      }
+    }
+    self.run_log.write().row_mut(self.row,&self.record).generations.push(generation);
+  }
+  /// Records a call that failed, with its error, and hands the error back.
+  pub fn failed(&self, mut generation:Generation, err:String) -> String {
+    generation.error = Some(err.clone());
+    self.generation(generation);
+    err
+  }
+  pub fn payload(&self, payload:serde_json::Value) {
+    self.run_log.write().row_mut(self.row,&self.record).payload = Some(payload);
+  }
+  /// Sets one field of the recorded payload, for rows that aren't the current one.
+  pub fn payload_field(&self, path:&str, value:serde_json::Value) {
+    let mut run_log = self.run_log.write();
+    let row = run_log.row_mut(self.row,&self.record);
+    let mut map = match row.payload.take() {
+      Some(serde_json::Value::Object(map)) => map,
+      _ => serde_json::Map::default(),
+    };
+    map.entry("").or_insert_with(|| serde_json::Value::Object(serde_json::Map::default()));
+    set_payload_field(&mut map,path,value);
+    row.payload = Some(serde_json::Value::Object(map));
+  }
+  pub fn moderation(&self, result:ModerationResult) {
+    self.run_log.write().row_mut(self.row,&self.record).moderation = Some(result);
+  }
+  pub fn selection(&self, selection:Selection) {
+    self.run_log.write().row_mut(self.row,&self.record).selections.push(selection);
+  }
+  pub fn delivery(&self, endpoint:&str, status:String) {
+    self.run_log.write().row_mut(self.row,&self.record).delivery = Some(Delivery{
+      endpoint:endpoint.to_string(),
+      status,
+      timestamp:chrono::Utc::now().to_rfc3339(),
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn rows_never_inherit_the_values_of_the_row_left() {
+    let row_a = json!({"":{"title":"a","meta":{"lang":"en"}}}).as_object().unwrap().clone();
+    assert_eq!(serde_json::Value::Object(row_payload(&row_a,None)),json!({"":{"meta":{}}}));
+    let recorded = json!({"":{"summary":"b"}});
+    assert_eq!(serde_json::Value::Object(row_payload(&row_a,Some(&recorded))),json!({"":{"meta":{},"summary":"b"}}));
+  }
+
+  #[test]
+  fn recorded_payloads_are_merged_field_by_field() {
+    let mut map = json!({"":{"title":"old","meta":{"lang":"en","slug":"a"}}}).as_object().unwrap().clone();
+    let recorded = json!({"":{"meta":{"slug":"b"},"summary":"picked"}}).as_object().unwrap().clone();
+    merge_payload(&mut map,recorded);
+    assert_eq!(
+      serde_json::Value::Object(map),
+      json!({"":{"title":"old","meta":{"lang":"en","slug":"b"},"summary":"picked"}}),
+    );
+  }
+}
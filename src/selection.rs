@@ -0,0 +1,237 @@
+use super::*;
+
+/// How one of several message choices is picked without a human.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum SelectionStrategy{
+    /// Every choice is listed and added to the payload by hand
+    Manual,
+    Heuristics,
+    /// A second chat call scores the choices against a rubric
+    Judge,
+}
+
+/// Checks a choice has to pass to be picked by the heuristics.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct HeuristicRules{
+    pub min_chars:Option<usize>,
+    pub max_chars:Option<usize>,
+    /// Comma separated, matched case insensitively
+    pub keywords:String,
+    /// Regex the choice has to match, ignored when empty
+    pub pattern:String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectionSettings{
+    pub strategy:SelectionStrategy,
+    /// Payload field the top choice is written to
+    pub field:String,
+    pub rules:HeuristicRules,
+    /// Template like the prompt, what the judge scores the choices on
+    pub rubric:String,
+    pub judge_model:String,
+}
+impl Default for SelectionSettings{
+  fn default() -> Self {
+    Self{
+      strategy:SelectionStrategy::Manual,
+      field:"".to_string(),
+      rules:HeuristicRules::default(),
+      rubric:"".to_string(),
+      judge_model:"gpt-4o-mini".to_string(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChoiceScore{
+    /// `index` of the `MessageChoice`
+    pub index:u32,
+    /// 0 to 10 from the judge, the share of checks passed from the heuristics
+    pub score:f64,
+    /// Disqualified choices are never picked, whatever their score
+    pub qualified:bool,
+    pub reason:String,
+}
+
+/// The scores of a set of choices and the one that was picked, kept in the run log for review.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Selection{
+    pub strategy:SelectionStrategy,
+    pub field:String,
+    pub scores:Vec<ChoiceScore>,
+    /// `None` when no choice qualified
+    pub chosen:Option<u32>,
+}
+impl Selection{
+  /// Picks the best qualified choice, ties go to the more confident one and then the first.
+  pub fn new(strategy:SelectionStrategy, field:&str, scores:Vec<ChoiceScore>, choices:&[MessageChoice]) -> Self {
+    let confidence = |index:u32| choices.iter()
+      .find(|c| c.index == index)
+      .and_then(|c| c.confidence())
+      .unwrap_or_default();
+    let chosen = scores.iter()
+      .filter(|s| s.qualified)
+      .fold(None::<&ChoiceScore>,|best,s| match best {
+        Some(best) if (best.score,confidence(best.index)) >= (s.score,confidence(s.index)) => Some(best),
+        _ => Some(s),
+      })
+      .map(|s| s.index);
+    Self{
+      strategy,
+      field:field.to_string(),
+      scores,
+      chosen,
+    }
+  }
+  pub fn chosen_content<'a>(&self, choices:&'a [MessageChoice]) -> Option<&'a str> {
+    let chosen = self.chosen?;
+    choices.iter().find(|c| c.index == chosen).map(|c| c.message.content.as_str())
+  }
+}
+
+/// Scores each choice by the share of checks it passes, only choices passing all of them qualify.
+pub fn heuristic_scores(rules:&HeuristicRules, choices:&[MessageChoice]) -> Result<Vec<ChoiceScore>,String> {
+  let pattern = if rules.pattern.is_empty() {
+    None
+  } else {
+    Some(regex::Regex::new(&rules.pattern).map_err(|e| e.to_string())?)
+  };
+  let keywords : Vec<String> = rules.keywords.split(',')
+    .map(|k| k.trim().to_lowercase())
+    .filter(|k| !k.is_empty())
+    .collect();
+  Ok(choices.iter().map(|choice| {
+    let content = &choice.message.content;
+    let chars = content.chars().count();
+    let lowercase = content.to_lowercase();
+    let mut failed = vec![];
+    let mut checks = 0;
+    if let Some(min) = rules.min_chars {
+      checks += 1;
+      if chars < min {
+        failed.push(format!("{} chars, fewer than {}",chars,min));
+      }
+    }
+    if let Some(max) = rules.max_chars {
+      checks += 1;
+      if chars > max {
+        failed.push(format!("{} chars, more than {}",chars,max));
+      }
+    }
+    for keyword in &keywords {
+      checks += 1;
+      if !lowercase.contains(keyword.as_str()) {
+        failed.push(format!("missing {:?}",keyword));
+      }
+    }
+    if let Some(pattern) = &pattern {
+      checks += 1;
+      if !pattern.is_match(content) {
+        failed.push(format!("doesn't match {}",pattern));
+      }
+    }
+    ChoiceScore{
+      index:choice.index,
+      score:if checks == 0 { 1. } else { (checks - failed.len()) as f64 / checks as f64 },
+      qualified:failed.is_empty(),
+      reason:failed.join(", "),
+    }
+  }).collect())
+}
+
+/// Most tokens the judge may reply with.
+pub const JUDGE_MAX_TOKENS : u32 = 1024;
+
+/// Asks the judge to score every choice against the rubric, as JSON.
+pub fn judge_messages(rubric:&str, task:&str, choices:&[MessageChoice]) -> Vec<ChatMessage> {
+  let mut prompt = format!("Rubric:\n{}\n\nTask:\n{}",rubric,task);
+  for (i,choice) in choices.iter().enumerate() {
+    prompt.push_str(&format!("\n\nCandidate {}:\n{}",i + 1,choice.message.content));
+  }
+  vec![
+    ChatMessage{
+      role:Role::System,
+      content:concat!(
+        "You judge candidate answers to a task. Score every candidate from 0 to 10 against the rubric. ",
+        "Reply with JSON like {\"scores\":[{\"candidate\":1,\"score\":7,\"reason\":\"...\"}]}."
+      ).to_string(),
+    },
+    ChatMessage{ role:Role::User, content:prompt },
+  ]
+}
+
+#[derive(Deserialize)]
+struct JudgeReply{
+    scores:Vec<JudgeScore>,
+}
+#[derive(Deserialize)]
+struct JudgeScore{
+    /// 1 based position in the prompt
+    candidate:usize,
+    score:f64,
+    #[serde(default)]
+    reason:String,
+}
+
+/// Reads the judge's reply, choices it didn't score don't qualify.
+pub fn parse_judge_scores(reply:&str, choices:&[MessageChoice]) -> Result<Vec<ChoiceScore>,String> {
+  let reply = serde_json::from_str::<JudgeReply>(reply).map_err(|e| format!("couldn't read the judge's scores: {}",e))?;
+  Ok(choices.iter().enumerate().map(|(i,choice)| {
+    match reply.scores.iter().find(|s| s.candidate == i + 1) {
+      Some(s) => ChoiceScore{ index:choice.index, score:s.score, qualified:true, reason:s.reason.clone() },
+      None => ChoiceScore{ index:choice.index, score:0., qualified:false, reason:"not scored by the judge".to_string() },
+    }
+  }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn choices(contents:&[&str]) -> Vec<MessageChoice> {
+    contents.iter().enumerate().map(|(i,content)| MessageChoice{
+      message:ChatMessage{ role:Role::Assistant, content:content.to_string() },
+      finish_reason:"stop".to_string(),
+      index:i as u32,
+      logprobs:None,
+    }).collect()
+  }
+
+  #[test]
+  fn heuristics_score_the_share_of_checks_passed() {
+    let rules = HeuristicRules{
+      min_chars:Some(10),
+      max_chars:Some(40),
+      keywords:"Budget, vote".to_string(),
+      pattern:r"^[A-Z]".to_string(),
+    };
+    let choices = choices(&["Budget vote passes", "the budget vote passes narrowly", "short"]);
+    let scores = heuristic_scores(&rules,&choices).unwrap();
+    assert_eq!(scores[0].score,1.);
+    assert!(scores[0].qualified);
+    assert_eq!(scores[1].score,0.8);
+    assert!(!scores[1].qualified);
+    assert_eq!(scores[2].reason,"5 chars, fewer than 10, missing \"budget\", missing \"vote\", doesn't match ^[A-Z]");
+    assert_eq!(Selection::new(SelectionStrategy::Heuristics,"title",scores,&choices).chosen,Some(0));
+  }
+
+  #[test]
+  fn heuristics_without_rules_qualify_everything_and_reject_bad_patterns() {
+    let scores = heuristic_scores(&HeuristicRules::default(),&choices(&["a","b"])).unwrap();
+    assert!(scores.iter().all(|s| s.qualified && s.score == 1.));
+    let rules = HeuristicRules{ pattern:"(".to_string(), ..Default::default() };
+    assert!(heuristic_scores(&rules,&choices(&["a"])).is_err());
+  }
+
+  #[test]
+  fn judge_scores_are_matched_to_candidates() {
+    let choices = choices(&["a","b","c"]);
+    let reply = r#"{"scores":[{"candidate":2,"score":9,"reason":"sharp"},{"candidate":1,"score":4}]}"#;
+    let scores = parse_judge_scores(reply,&choices).unwrap();
+    assert_eq!(scores.iter().map(|s| (s.index,s.score,s.qualified)).collect::<Vec<_>>(),[(0,4.,true),(1,9.,true),(2,0.,false)]);
+    assert_eq!(scores[1].reason,"sharp");
+    assert_eq!(Selection::new(SelectionStrategy::Judge,"title",scores,&choices).chosen,Some(1));
+    assert!(parse_judge_scores("Candidate 2 is best",&choices).is_err());
+  }
+}
@@ -0,0 +1,194 @@
+use regex::Regex;
+
+/// One step between a generated message and the payload field it goes into.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transform{
+    Trim,
+    /// Headings, emphasis, links, quotes, list markers and code fences, keeping the text
+    StripMarkdown,
+    /// Contents of the first fenced code block, of any language when it's empty
+    ExtractCodeBlock{ language:String },
+    /// The first JSON object or array in the text, parsed
+    ExtractJson,
+    /// `replacement` can refer to groups as `$1`
+    RegexReplace{ pattern:String, replacement:String },
+    /// The first group of the first match, or the whole match without groups
+    RegexCapture{ pattern:String },
+    /// An array of the non-empty lines
+    SplitLines,
+    /// An array of the list items, without their markers
+    SplitBullets,
+    TruncateWords(usize),
+    Slugify,
+}
+impl Transform{
+  pub fn label(&self) -> String {
+    match self {
+      Transform::Trim => "trim".to_string(),
+      Transform::StripMarkdown => "strip markdown".to_string(),
+      Transform::ExtractCodeBlock{ language } if language.is_empty() => "code block".to_string(),
+      Transform::ExtractCodeBlock{ language } => format!("{} code block",language),
+      Transform::ExtractJson => "JSON block".to_string(),
+      Transform::RegexReplace{ pattern, replacement } => format!("replace /{}/ with {:?}",pattern,replacement),
+      Transform::RegexCapture{ pattern } => format!("capture /{}/",pattern),
+      Transform::SplitLines => "split lines".to_string(),
+      Transform::SplitBullets => "split bullets".to_string(),
+      Transform::TruncateWords(n) => format!("first {} words",n),
+      Transform::Slugify => "slugify".to_string(),
+    }
+  }
+  /// Transforms a string, or each string of an array.
+  pub fn apply(&self, value:serde_json::Value) -> Result<serde_json::Value,String> {
+    use serde_json::Value;
+    match value {
+      Value::String(text) => self.apply_text(&text),
+      Value::Array(values) => values.into_iter().map(|v| self.apply(v)).collect::<Result<Vec<_>,_>>().map(Value::Array),
+      _ => Err(format!("{} needs text",self.label())),
+    }
+  }
+  fn apply_text(&self, text:&str) -> Result<serde_json::Value,String> {
+    use serde_json::Value;
+    Ok(match self {
+      Transform::Trim => Value::String(text.trim().to_string()),
+      Transform::StripMarkdown => Value::String(strip_markdown(text)),
+      Transform::ExtractCodeBlock{ language } => Value::String(
+        code_blocks(text)
+          .into_iter()
+          .find(|(lang,_)| language.is_empty() || lang.eq_ignore_ascii_case(language))
+          .map(|(_,code)| code)
+          .ok_or_else(|| format!("no {}",self.label()))?
+      ),
+      Transform::ExtractJson => extract_json(text).ok_or_else(|| "no JSON object or array".to_string())?,
+      Transform::RegexReplace{ pattern, replacement } => Value::String(
+        regex(pattern)?.replace_all(text,replacement.as_str()).to_string()
+      ),
+      Transform::RegexCapture{ pattern } => {
+        let captures = regex(pattern)?.captures(text).ok_or_else(|| format!("nothing matches /{}/",pattern))?;
+        let capture = captures.get(1).or_else(|| captures.get(0)).map(|m| m.as_str()).unwrap_or_default();
+        Value::String(capture.to_string())
+      },
+      Transform::SplitLines => Value::Array(
+        text.lines().map(str::trim).filter(|l| !l.is_empty()).map(|l| Value::String(l.to_string())).collect()
+      ),
+      Transform::SplitBullets => {
+        let marker = regex(r"^\s*(?:[-*+•]|\d+[.)])\s+(.*)$")?;
+        Value::Array(
+          text.lines()
+            .filter_map(|l| marker.captures(l))
+            .map(|c| Value::String(c[1].trim().to_string()))
+            .collect()
+        )
+      },
+      Transform::TruncateWords(n) => Value::String(text.split_whitespace().take(*n).collect::<Vec<&str>>().join(" ")),
+      Transform::Slugify => Value::String(slugify(text)),
+    })
+  }
+}
+
+/// Runs the chain in order, starting from the message text.
+pub fn apply_transforms(transforms:&[Transform], content:&str) -> Result<serde_json::Value,String> {
+  transforms.iter().try_fold(serde_json::Value::String(content.to_string()),|value,transform| transform.apply(value))
+}
+
+fn regex(pattern:&str) -> Result<Regex,String> {
+  Regex::new(pattern).map_err(|e| e.to_string())
+}
+
+/// Language and contents of every fenced code block.
+fn code_blocks(text:&str) -> Vec<(String,String)> {
+  let fence = Regex::new(r"(?s)```[ \t]*([\w+#.-]*)[^\n]*\n(.*?)```").unwrap();
+  fence.captures_iter(text)
+    .map(|c| (c[1].to_string(),c[2].trim_end_matches('\n').to_string()))
+    .collect()
+}
+
+/// A JSON code block, or the longest run from the first `{` or `[` that parses.
+fn extract_json(text:&str) -> Option<serde_json::Value> {
+  let from_block = code_blocks(text).into_iter()
+    .filter(|(lang,_)| lang.is_empty() || lang.eq_ignore_ascii_case("json"))
+    .find_map(|(_,code)| serde_json::from_str::<serde_json::Value>(&code).ok());
+  if from_block.is_some() {
+    return from_block;
+  }
+  let start = text.find(['{','['])?;
+  let candidate = &text[start..];
+  candidate.char_indices()
+    .filter(|(_,c)| *c == '}' || *c == ']')
+    .map(|(i,c)| i + c.len_utf8())
+    .rev()
+    .find_map(|end| serde_json::from_str::<serde_json::Value>(&candidate[..end]).ok())
+    .filter(|v| v.is_object() || v.is_array())
+}
+
+/// Emphasis has to hug its text, so `2 * 3 * 4` is left alone.
+fn strip_markdown(text:&str) -> String {
+  let rules : [(&str,&str);12] = [
+    (r"(?m)^[ \t]*```.*\n?",""),
+    (r"(?m)^ {0,3}#{1,6}[ \t]+",""),
+    (r"(?m)^ {0,3}>[ \t]?",""),
+    (r"(?m)^[ \t]*([-*_][ \t]*){3,}$",""),
+    (r"(?m)^[ \t]*(?:[-*+]|\d+[.)])[ \t]+",""),
+    (r"!\[([^\]]*)\]\([^)]*\)","$1"),
+    (r"\[([^\]]+)\]\([^)]*\)","$1"),
+    (r"\*\*([^*\s](?:[^*]*[^*\s])?)\*\*","$1"),
+    (r"__([^_]+)__","$1"),
+    (r"\*([^*\s](?:[^*\n]*[^*\s])?)\*","$1"),
+    (r"\b_([^_\n]+)_\b","$1"),
+    (r"`([^`\n]+)`","$1"),
+  ];
+  rules.iter().fold(text.to_string(),|text,(pattern,replacement)| {
+    Regex::new(pattern).unwrap().replace_all(&text,*replacement).to_string()
+  })
+}
+
+/// Lowercase letters and digits separated by single dashes.
+fn slugify(text:&str) -> String {
+  let mut slug = String::new();
+  for c in text.chars().flat_map(char::to_lowercase) {
+    if c.is_alphanumeric() {
+      slug.push(c);
+    } else if !slug.is_empty() && !slug.ends_with('-') {
+      slug.push('-');
+    }
+  }
+  slug.trim_end_matches('-').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn transforms_run_in_order_over_arrays() {
+    let content = "Ideas:\n- **Fast** boats\n- Slow *trains*\n";
+    let transforms = [Transform::SplitBullets,Transform::StripMarkdown,Transform::Slugify];
+    assert_eq!(apply_transforms(&transforms,content).unwrap(),json!(["fast-boats","slow-trains"]));
+    assert_eq!(apply_transforms(&[],content).unwrap(),json!(content));
+    assert_eq!(apply_transforms(&[Transform::Trim,Transform::TruncateWords(1)],"  Ideas: boats ").unwrap(),json!("Ideas:"));
+  }
+
+  #[test]
+  fn transforms_report_what_they_couldnt_do() {
+    assert_eq!(apply_transforms(&[Transform::ExtractJson,Transform::Trim],"{\"a\":1}").unwrap_err(),"trim needs text");
+    assert_eq!(apply_transforms(&[Transform::ExtractJson],"no JSON here").unwrap_err(),"no JSON object or array");
+    assert!(apply_transforms(&[Transform::RegexCapture{ pattern:"(".to_string() }],"text").is_err());
+  }
+
+  #[test]
+  fn json_is_taken_from_a_block_or_the_text_around_it() {
+    let block = "Here it is:\n```json\n{\"a\":[1,2]}\n```\nand {\"b\":2}";
+    assert_eq!(extract_json(block),Some(json!({"a":[1,2]})));
+    assert_eq!(extract_json("Sure! {\"a\":{\"b\":true}} Anything else?"),Some(json!({"a":{"b":true}})));
+    assert_eq!(extract_json("The list: [1, 2] (that's all)"),Some(json!([1,2])));
+    assert_eq!(extract_json("```python\n{'a': 1}\n```"),None);
+    assert_eq!(extract_json("plain text"),None);
+  }
+
+  #[test]
+  fn markdown_is_stripped_to_its_text() {
+    let markdown = "# Title\n> quoted **bold** and _it_\n1. see [the docs](https://example.com)\n---\n```rust\nlet `x` = 1;\n```";
+    assert_eq!(strip_markdown(markdown),"Title\nquoted bold and it\nsee the docs\n\nlet x = 1;\n");
+    assert_eq!(strip_markdown("snake_case_name and 2 * 3 * 4"),"snake_case_name and 2 * 3 * 4");
+  }
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use super::*;
 
 
@@ -7,23 +7,67 @@ use super::*;
 pub struct MessageChoicesProps{
   pub choices:Vec<MessageChoice>,
 }
+#[derive(Props,PartialEq)]
+pub struct AddToFieldProps{
+  pub value:serde_json::Value,
+}
+#[derive(Props,PartialEq)]
+pub struct AssetFieldProps{
+  pub asset:AssetRef,
+}
 #[derive(Debug,Clone,PartialEq,Default)]
 pub struct AppState{
+  pub headers:Option<StringRecord>,
+  /// Index of `current_record` in the uploaded file
+  pub current_row:Option<usize>,
   pub current_record:Option<StringRecord>,
+  pub current_source:Option<RowSource>,
   pub chat_gpt_system_raw:String,
   pub chat_gpt_system_edited:String,
   pub chat_gpt_prompt_raw:String,
   pub chat_gpt_prompt_edited:String,
+  /// Also followed by the preview, which checks prompts against the model's context
+  pub chat_gpt_model:String,
+  pub chat_gpt_max_tokens:u32,
   pub dall_e_raw:String,
   pub dall_e_edited:String,
+  /// Kept here rather than in the DALL-E panel so the preview checks prompts against its limit
+  pub dall_e_model:String,
   pub eleven_labs_raw:String,
   pub eleven_labs_edited:String,
+  pub stable_diffusion_raw:String,
+  pub stable_diffusion_edited:String,
+  pub open_ai_speech_raw:String,
+  pub open_ai_speech_edited:String,
+  pub whisper_file_raw:String,
+  pub whisper_file_edited:String,
+  pub transcript:String,
+}
+/// A piece of a rendered template.
+#[derive(Debug,Clone,PartialEq)]
+pub enum TemplateSegment{
+  Text(String),
+  /// What a placeholder was substituted with
+  Value(String),
+  /// A placeholder nothing matched, left as is
+  Missing(String),
+}
+pub fn join_segments(segments:Vec<TemplateSegment>) -> String {
+  segments.into_iter()
+    .map(|segment| match segment {
+      TemplateSegment::Text(s) | TemplateSegment::Value(s) => s,
+      TemplateSegment::Missing(key) => format!("{{{}}}",key),
+    })
+    .collect()
 }
 pub enum AppStateFieldUpdate{
   ChatGPTSystem(String),
   ChatGPTPrompt(String),
   DallE(String),
   ElevenLabs(String),
+  StableDiffusion(String),
+  OpenAISpeech(String),
+  WhisperFile(String),
 }
 impl AppState{
   pub fn update_field(&mut self, update:AppStateFieldUpdate) {
@@ -32,35 +76,111 @@ impl AppState{
         AppStateFieldUpdate::ChatGPTPrompt(s) => self.chat_gpt_prompt_raw=s,
         AppStateFieldUpdate::DallE(s) => self.dall_e_raw=s,
         AppStateFieldUpdate::ElevenLabs(s) => self.eleven_labs_raw=s,
+        AppStateFieldUpdate::StableDiffusion(s) => self.stable_diffusion_raw=s,
+        AppStateFieldUpdate::OpenAISpeech(s) => self.open_ai_speech_raw=s,
+        AppStateFieldUpdate::WhisperFile(s) => self.whisper_file_raw=s,
     }
-    if let Some(current_record) = &self.current_record {
-      self.update_current_record(current_record.clone());
-    } else {
-      self.chat_gpt_system_edited = self.chat_gpt_system_raw.clone();
-      self.chat_gpt_prompt_edited = self.chat_gpt_prompt_raw.clone();
-      self.eleven_labs_edited = self.eleven_labs_raw.clone();
-      self.dall_e_edited = self.dall_e_raw.clone();
+    self.render_all();
+  }
+  /// Switches to a newly uploaded dataset, none of its rows is current yet.
+  pub fn update_dataset(&mut self, headers:Option<StringRecord>) {
+    self.headers=headers;
+    self.current_row=None;
+    self.current_record=None;
+    self.current_source=None;
+    self.render_all();
+  }
+  pub fn update_current_record(&mut self, record:StringRecord, source:Option<RowSource>) {
+    self.current_record=Some(record);
+    self.current_source=source;
+    self.render_all();
+  }
+  /// Makes the latest transcription available to templates as `{transcript}`.
+  pub fn update_transcript(&mut self, transcript:String) {
+    self.transcript=transcript;
+    self.render_all();
+  }
+  /// Substitutes `{0}`, `{1}`, ... and `{<header>}` with the columns of the current record,
+  /// `{_source_file}` and `{_row_number}` with where it came from and `{transcript}` with the latest transcription.
+  pub fn render(&self, raw:&str) -> String {
+    join_segments(self.render_segments(raw,self.current_record.as_ref(),self.current_source.as_ref()))
+  }
+  /// Renders `raw` against row `i` of the dataset rather than the current one.
+  pub fn render_row(&self, raw:&str, dataset:&Dataset, i:usize) -> String {
+    join_segments(self.render_segments(raw,dataset.rows.get(i),dataset.sources.get(i)))
+  }
+  /// Renders against the current row, or the given row of a dataset.
+  pub fn render_for(&self, raw:&str, row:Option<(&Dataset,usize)>) -> String {
+    match row {
+      Some((dataset,i)) => self.render_row(raw,dataset,i),
+      None => self.render(raw),
     }
   }
-  pub fn update_current_record(&mut self, record:StringRecord) {
-    let mut i = 0;
-    let mut chat_gpt_system_edited = self.chat_gpt_system_raw.clone();
-    let mut chat_gpt_prompt_edited = self.chat_gpt_prompt_raw.clone();
-    let mut dall_e_edited = self.dall_e_raw.clone();
-    let mut eleven_labs_edited = self.eleven_labs_raw.clone();
-    while record.get(i).is_some() {
-      let s = record.get(i).unwrap();
-      chat_gpt_system_edited = chat_gpt_system_edited.replace(&format!("{{{}}}",i),s);
-      chat_gpt_prompt_edited = chat_gpt_prompt_edited.replace(&format!("{{{}}}",i),s);
-      eleven_labs_edited = eleven_labs_edited.replace(&format!("{{{}}}",i),s);
-      dall_e_edited = dall_e_edited.replace(&format!("{{{}}}",i),s);
-      i+=1;
+  /// The messages opening a ChatGPT conversation: the system message, the few-shot examples, then the prompt.
+  pub fn chat_messages(&self, examples:&[FewShotExample], row:Option<(&Dataset,usize)>) -> Vec<ChatMessage> {
+    let mut messages = vec![];
+    let system = self.render_for(&self.chat_gpt_system_raw,row);
+    if !system.is_empty() {
+      messages.push(ChatMessage{ role:Role::System, content:system });
     }
-    self.chat_gpt_system_edited = chat_gpt_system_edited;
-    self.chat_gpt_prompt_edited = chat_gpt_prompt_edited;
-    self.eleven_labs_edited = eleven_labs_edited;
-    self.dall_e_edited = dall_e_edited;
-    self.current_record=Some(record);
+    for example in examples {
+      messages.push(ChatMessage{ role:Role::User, content:self.render_for(&example.user,row) });
+      messages.push(ChatMessage{ role:Role::Assistant, content:self.render_for(&example.assistant,row) });
+    }
+    messages.push(ChatMessage{ role:Role::User, content:self.render_for(&self.chat_gpt_prompt_raw,row) });
+    messages
+  }
+  /// Renders `raw` against any row, keeping track of which parts were substituted.
+  pub fn render_segments(&self, raw:&str, record:Option<&StringRecord>, source:Option<&RowSource>) -> Vec<TemplateSegment> {
+    let mut segments = vec![];
+    let mut rest = raw;
+    while let Some(start) = rest.find('{') {
+      let Some(len) = rest[start+1..].find('}') else {
+        break;
+      };
+      let key = &rest[start+1..start+1+len];
+      if key.contains('{') {
+        // Not a placeholder, e.g. `{{0}`, the inner brace may start one.
+        segments.push(TemplateSegment::Text(rest[..start+1].to_string()));
+        rest = &rest[start+1..];
+        continue;
+      }
+      segments.push(TemplateSegment::Text(rest[..start].to_string()));
+      segments.push(match self.lookup(key,record,source) {
+        Some(value) => TemplateSegment::Value(value),
+        None => TemplateSegment::Missing(key.to_string()),
+      });
+      rest = &rest[start+2+len..];
+    }
+    segments.push(TemplateSegment::Text(rest.to_string()));
+    segments.retain(|segment| segment != &TemplateSegment::Text(String::new()));
+    segments
+  }
+  fn lookup(&self, key:&str, record:Option<&StringRecord>, source:Option<&RowSource>) -> Option<String> {
+    if let Some(record) = record {
+      if let Some(value) = key.parse::<usize>().ok().and_then(|i| record.get(i)) {
+        return Some(value.to_string());
+      }
+      let column = self.headers.as_ref().and_then(|headers| headers.iter().position(|h| h == key));
+      if let Some(value) = column.and_then(|i| record.get(i)) {
+        return Some(value.to_string());
+      }
+    }
+    match (key,source) {
+      ("_source_file",Some(source)) => Some(source.file.clone()),
+      ("_row_number",Some(source)) => Some(source.row.to_string()),
+      ("transcript",_) => Some(self.transcript.clone()),
+      _ => None,
+    }
+  }
+  fn render_all(&mut self) {
+    self.chat_gpt_system_edited = self.render(&self.chat_gpt_system_raw);
+    self.chat_gpt_prompt_edited = self.render(&self.chat_gpt_prompt_raw);
+    self.dall_e_edited = self.render(&self.dall_e_raw);
+    self.eleven_labs_edited = self.render(&self.eleven_labs_raw);
+    self.stable_diffusion_edited = self.render(&self.stable_diffusion_raw);
+    self.open_ai_speech_edited = self.render(&self.open_ai_speech_raw);
+    self.whisper_file_edited = self.render(&self.whisper_file_raw);
   }
 }
 
@@ -73,6 +193,10 @@ pub struct ApiKeys{
 pub struct ApiKeyProps{
     pub model:GenModel,
 }
+#[derive(Props,PartialEq)]
+pub struct AudioClipsProps{
+    pub model:GenModel,
+}
 #[derive(Clone,Debug,Copy,PartialEq)]
 pub enum GenModel{
     OpenAI,
@@ -102,6 +226,9 @@ pub struct CompletionResponse {
     /// Message choices for this response, guaranteed to contain at least one message response
     #[serde(rename = "choices")]
     pub message_choices: Vec<MessageChoice>,
+    /// Backend configuration the completion was generated with, outputs for the same seed only match while it stays the same
+    #[serde(default)]
+    pub system_fingerprint: Option<String>,
 }
 /// A message completion choice struct
 #[derive(Debug, Clone, PartialEq, PartialOrd, Deserialize)]
@@ -112,6 +239,43 @@ pub struct MessageChoice {
     pub finish_reason: String,
     /// The index of this message in the outer `message_choices` array
     pub index: u32,
+    /// Log probabilities of the generated tokens, when they were requested
+    #[serde(default)]
+    pub logprobs: Option<ChoiceLogprobs>,
+}
+impl MessageChoice{
+  /// Geometric mean of the token probabilities, between 0 and 1. `None` without logprobs.
+  pub fn confidence(&self) -> Option<f64> {
+    let tokens = self.logprobs.as_ref()?.content.as_ref()?;
+    if tokens.is_empty() {
+      return None;
+    }
+    let mean = tokens.iter().map(|t| t.logprob).sum::<f64>() / tokens.len() as f64;
+    Some(mean.exp())
+  }
+}
+#[derive(Debug, Clone, PartialEq, PartialOrd, Deserialize)]
+pub struct ChoiceLogprobs {
+    #[serde(default)]
+    pub content: Option<Vec<TokenLogprob>>,
+}
+#[derive(Debug, Clone, PartialEq, PartialOrd, Deserialize)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f64,
+    /// The most likely tokens at this position, with `top_logprobs`
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+impl TokenLogprob{
+  pub fn probability(&self) -> f64 {
+    self.logprob.exp()
+  }
+}
+#[derive(Debug, Clone, PartialEq, PartialOrd, Deserialize)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f64,
 }
 /// A role of a message sender, can be:
 /// - `System`, for starting system message, that sets the tone of model
@@ -130,6 +294,69 @@ pub enum Role {
     Function,
 }
 
+/// A user message and the answer it should get, sent ahead of the prompt to show the model what's expected.
+/// Both are templates like the prompt.
+#[derive(Debug, Clone, PartialEq, Default, Hash)]
+pub struct FewShotExample{
+    pub user:String,
+    pub assistant:String,
+}
+impl FewShotExample{
+  /// Reads examples from the `user` and `assistant` columns of a dataset, or its first two columns.
+  pub fn from_dataset(dataset:&Dataset) -> Vec<Self> {
+    let column = |name:&str, fallback:usize| dataset.headers.as_ref()
+      .and_then(|headers| headers.iter().position(|h| h.trim().eq_ignore_ascii_case(name)))
+      .unwrap_or(fallback);
+    let (user,assistant) = (column("user",0),column("assistant",1));
+    dataset.rows.iter()
+      .map(|row| FewShotExample{
+        user:row.get(user).unwrap_or_default().to_string(),
+        assistant:row.get(assistant).unwrap_or_default().to_string(),
+      })
+      .collect()
+  }
+}
+
+/// Body of `/v1/chat/completions`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChatCompletionRequest{
+    pub model:String,
+    pub messages:Vec<ChatMessage>,
+    pub frequency_penalty:f32,
+    pub max_tokens:u32,
+    pub n:u8,
+    pub presence_penalty:f32,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stop:Vec<String>,
+    pub temperature:f32,
+    pub top_p:f32,
+    /// Repeating a request with the same seed should give the same output, as long as `system_fingerprint` doesn't change
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed:Option<i64>,
+    /// Token id to a bias from -100 to 100 added to its logit
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub logit_bias:BTreeMap<String,i32>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub logprobs:bool,
+    /// Alternatives returned for every token, 0 to 20, needs `logprobs`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_logprobs:Option<u8>,
+    /// End user the request is made for, passed on to OpenAI for abuse monitoring
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub user:String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format:Option<ResponseFormat>,
+}
+
+/// What the model is constrained to output
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat{
+    Text,
+    /// Valid JSON, the prompt still has to ask for JSON
+    JsonObject,
+}
+
 /// Container for the sent/received ChatGPT messages
 #[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct ChatMessage {
@@ -145,7 +372,7 @@ pub struct ChatMessage {
 }
 
 /// The token usage of a specific response
-#[derive(Debug, Clone, PartialEq, PartialOrd, Deserialize,Default)]
+#[derive(Debug, Clone, PartialEq, PartialOrd, Deserialize, Serialize,Default)]
 pub struct TokenUsage {
     /// Tokens spent on the prompt message (including previous messages)
     pub prompt_tokens: u32,
@@ -163,10 +390,430 @@ pub struct DallEResponse{
     /// The list of image urls.
     pub data: Vec<ImageObject>,
 }   
+/// A generated image as kept in the response cache.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedImage{
+    pub asset:AssetRef,
+    pub revised_prompt:Option<String>,
+}
 #[derive(Debug, Clone, PartialEq, PartialOrd, Deserialize,Default)]
 pub struct ImageObject{
-    /// The Url of the Image generated by Dall-E
+    /// The Url of the Image generated by Dall-E, empty when `b64_json` was requested
+    #[serde(default)]
     pub url:String,
+    /// The base64 encoded image, when `response_format` was `b64_json`
+    #[serde(default)]
+    pub b64_json:Option<String>,
+    /// The prompt DALL-E 3 actually used after rewriting ours
+    #[serde(default)]
+    pub revised_prompt:Option<String>,
+    /// Where the image was kept in the asset store
+    #[serde(skip)]
+    pub asset:Option<AssetRef>,
+}
+impl ImageObject{
+  /// Something an `img` tag can display, whichever response format was used.
+  pub fn src(&self) -> String {
+    match &self.b64_json {
+      Some(b64) => format!("data:image/png;base64,{}",b64),
+      None => self.url.clone(),
+    }
+  }
+}
+
+/// Body of OpenAI's `/v1/images/generations` endpoint.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DallERequest{
+    /// `dall-e-2` or `dall-e-3`
+    pub model:String,
+    pub prompt:String,
+    pub n:u8,
+    pub size:String,
+    /// `standard` or `hd`, DALL-E 3 only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quality:Option<String>,
+    /// `vivid` or `natural`, DALL-E 3 only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub style:Option<String>,
+    /// `url` or `b64_json`
+    pub response_format:String,
+}
+/// The form of OpenAI's `/v1/images/edits` endpoint, or `/v1/images/variations` without a prompt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DallERefineRequest{
+    /// PNG bytes of the source image
+    pub image:Vec<u8>,
+    /// PNG bytes whose transparent areas are edited, edits only
+    pub mask:Option<Vec<u8>>,
+    pub prompt:Option<String>,
+    pub n:u8,
+    pub size:String,
+    /// `url` or `b64_json`
+    pub response_format:String,
+}
+
+impl DallERequest{
+  /// The sizes each model accepts.
+  pub fn sizes(model:&str) -> &'static [&'static str] {
+    match model {
+      "dall-e-3" => &["1024x1024","1792x1024","1024x1792"],
+      _ => &["256x256","512x512","1024x1024"],
+    }
+  }
+  /// The longest prompt each model accepts, in characters.
+  pub fn max_prompt_chars(model:&str) -> usize {
+    match model {
+      "dall-e-3" => 4000,
+      _ => 1000,
+    }
+  }
+  /// Checks the per model constraints before we spend any credits.
+  pub fn validate(&self) -> Result<(),String> {
+    if self.prompt.trim().is_empty() {
+      return Err("the prompt is empty".to_string());
+    }
+    if self.prompt.chars().count() > Self::max_prompt_chars(&self.model) {
+      return Err(format!("{} prompts are limited to {} characters",self.model,Self::max_prompt_chars(&self.model)));
+    }
+    if !Self::sizes(&self.model).contains(&self.size.as_str()) {
+      return Err(format!("{} does not support the size {}",self.model,self.size));
+    }
+    match self.model.as_str() {
+      "dall-e-3" => {
+        if self.n != 1 {
+          return Err("dall-e-3 only generates one image per request, set the batch size to 1".to_string());
+        }
+      },
+      _ => {
+        if self.n < 1 || self.n > 10 {
+          return Err("dall-e-2 generates between 1 and 10 images per request".to_string());
+        }
+        if self.quality.is_some() || self.style.is_some() {
+          return Err("quality and style are only supported by dall-e-3".to_string());
+        }
+      },
+    }
+    Ok(())
+  }
+}
+
+/// Width and height from the IHDR chunk of a PNG.
+pub fn png_dimensions(bytes:&[u8]) -> Option<(u32,u32)> {
+  if bytes.len() < 24 || !bytes.starts_with(b"\x89PNG\r\n\x1a\n") || &bytes[12..16] != b"IHDR" {
+    return None;
+  }
+  let width = u32::from_be_bytes(bytes[16..20].try_into().unwrap());
+  let height = u32::from_be_bytes(bytes[20..24].try_into().unwrap());
+  Some((width,height))
+}
+
+/// The edit and variation endpoints are dall-e-2 only, they take square PNGs under 4 MB
+/// and a mask has to match the image.
+pub fn check_refine_images(image:&[u8], mask:Option<&[u8]>) -> Result<(),String> {
+  const MAX_BYTES : usize = 4 * 1024 * 1024;
+  let (width,height) = png_dimensions(image).ok_or_else(|| "the source image has to be a PNG".to_string())?;
+  if width != height {
+    return Err(format!("the source image is {}x{}, edits and variations need a square image",width,height));
+  }
+  if image.len() >= MAX_BYTES {
+    return Err("the source image has to be smaller than 4 MB".to_string());
+  }
+  if let Some(mask) = mask {
+    if png_dimensions(mask) != Some((width,height)) {
+      return Err(format!("the mask has to be a {}x{} PNG like the source image",width,height));
+    }
+    if mask.len() >= MAX_BYTES {
+      return Err("the mask has to be smaller than 4 MB".to_string());
+    }
+  }
+  Ok(())
+}
+
+/// Which self-hosted Stable Diffusion HTTP API to talk to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StableDiffusionBackend{
+    /// AUTOMATIC1111 web UI started with `--api`, uses `/sdapi/v1/txt2img`.
+    Automatic1111,
+    /// ComfyUI, queues a txt2img workflow on `/prompt` and polls `/history`.
+    ComfyUI,
+}
+
+/// The txt2img parameters shared by both Stable Diffusion backends.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StableDiffusionRequest{
+    pub prompt:String,
+    pub negative_prompt:String,
+    /// `-1` lets the server pick a random seed.
+    pub seed:i64,
+    pub steps:u32,
+    pub sampler_name:String,
+    pub cfg_scale:f32,
+    pub width:u32,
+    pub height:u32,
+    pub batch_size:u8,
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd, Deserialize,Default)]
+pub struct StableDiffusionResponse{
+    /// Base64 encoded PNGs.
+    pub images:Vec<String>,
+    /// The seed used for each image, in the same order as `images`.
+    #[serde(skip)]
+    pub seeds:Vec<i64>,
+    /// AUTOMATIC1111 returns its generation info as a JSON encoded string.
+    #[serde(default)]
+    pub info:String,
+    /// Where each image was kept in the asset store, in the same order as `images`
+    /// and `None` where storing it failed.
+    #[serde(skip)]
+    pub assets:Vec<Option<AssetRef>>,
+}
+
+/// Generated Stable Diffusion images as kept in the response cache.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedStableDiffusion{
+    pub seeds:Vec<i64>,
+    pub assets:Vec<AssetRef>,
+}
+
+/// The part of AUTOMATIC1111's `info` string we care about.
+#[derive(Debug, Clone, PartialEq, Deserialize,Default)]
+pub struct StableDiffusionInfo{
+    #[serde(default)]
+    pub all_seeds:Vec<i64>,
+}
+
+/// Response of ComfyUI's `/prompt` endpoint.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ComfyPromptResponse{
+    pub prompt_id:String,
+}
+
+/// An image written by a ComfyUI `SaveImage` node, fetched through `/view`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ComfyImage{
+    pub filename:String,
+    pub subfolder:String,
+    #[serde(rename = "type")]
+    pub kind:String,
+}
+
+/// An asset sent as its own form-data part next to the JSON payload,
+/// for receivers that can't fetch urls.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PayloadAttachment{
+    /// The payload field referencing the attachment, also the name of its part.
+    pub path:String,
+    pub asset:AssetRef,
+}
+impl PayloadAttachment{
+  /// Whether writing the payload field at `path` replaces the field referencing this attachment.
+  pub fn replaced_by(&self, path:&str) -> bool {
+    let nested = |outer:&str, inner:&str| inner.strip_prefix(outer).is_some_and(|rest| rest.is_empty() || rest.starts_with('.'));
+    nested(path,&self.path) || nested(&self.path,path)
+  }
+}
+
+/// A piece of generated speech, kept as bytes so later steps can reuse it.
+#[derive(Clone)]
+pub struct AudioClip{
+    /// The provider that produced the clip.
+    pub provider:GenModel,
+    /// The text that was narrated.
+    pub text:String,
+    pub mime:String,
+    pub bytes:Bytes,
+    pub url:ObjectUrl,
+    /// Where the clip was kept in the asset store
+    pub asset:Option<AssetRef>,
+    /// One per chunk of an ElevenLabs narration, empty for other clips
+    pub chapters:Vec<Chapter>,
+}
+impl AudioClip{
+  pub fn new(provider:GenModel, text:String, bytes:Bytes, mime:&str) -> Self {
+    let blob = gloo::file::Blob::new_with_options(&*bytes,Some(mime));
+    Self{
+      provider,
+      text,
+      mime:mime.to_string(),
+      bytes,
+      url:ObjectUrl::from(blob),
+      asset:None,
+      chapters:vec![],
+    }
+  }
+  /// A file name Whisper will accept, it infers the format from the extension.
+  pub fn file_name(&self) -> &'static str {
+    match self.mime.as_str() {
+      "audio/ogg" => "speech.ogg",
+      "audio/aac" => "speech.aac",
+      "audio/flac" => "speech.flac",
+      _ => "speech.mp3",
+    }
+  }
+}
+
+/// Body of OpenAI's `/v1/audio/speech` endpoint.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OpenAISpeechRequest{
+    /// `tts-1` or `tts-1-hd`
+    pub model:String,
+    pub input:String,
+    pub voice:String,
+    /// Between 0.25 and 4.0
+    pub speed:f32,
+    /// `mp3`, `opus`, `aac` or `flac`
+    pub response_format:String,
+}
+impl OpenAISpeechRequest{
+  /// The mime type of the audio returned for `response_format`.
+  pub fn mime(&self) -> &'static str {
+    match self.response_format.as_str() {
+      "opus" => "audio/ogg",
+      "aac" => "audio/aac",
+      "flac" => "audio/flac",
+      _ => "audio/mpeg",
+    }
+  }
+}
+
+/// The audio sent to OpenAI's `/v1/audio/transcriptions` endpoint and what's sent with it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TranscriptionRequest{
+    pub file_name:String,
+    pub mime:String,
+    pub bytes:Vec<u8>,
+    /// ISO-639-1 code, empty to let Whisper detect it
+    pub language:String,
+    /// Text to guide the transcription's style or spelling, may be empty
+    pub prompt:String,
+}
+
+/// A `verbose_json` response of OpenAI's `/v1/audio/transcriptions` endpoint.
+#[derive(Debug, Clone, PartialEq, PartialOrd, Deserialize, Serialize, Default)]
+pub struct TranscriptionResponse{
+    pub text:String,
+    #[serde(default)]
+    pub language:String,
+    /// Length of the audio in seconds
+    #[serde(default)]
+    pub duration:f64,
+    #[serde(default)]
+    pub segments:Vec<TranscriptionSegment>,
+    #[serde(default)]
+    pub words:Vec<TranscriptionWord>,
+}
+#[derive(Debug, Clone, PartialEq, PartialOrd, Deserialize, Serialize)]
+pub struct TranscriptionSegment{
+    pub id:u32,
+    pub start:f64,
+    pub end:f64,
+    pub text:String,
+}
+#[derive(Debug, Clone, PartialEq, PartialOrd, Deserialize, Serialize)]
+pub struct TranscriptionWord{
+    pub word:String,
+    pub start:f64,
+    pub end:f64,
+}
+impl TranscriptionResponse{
+  /// Renders the segments as a SubRip subtitle file.
+  pub fn to_srt(&self) -> String {
+    self.segments.iter().enumerate().map(|(i,seg)|
+      format!("{}\n{} --> {}\n{}\n",i+1,subtitle_timestamp(seg.start,','),subtitle_timestamp(seg.end,','),seg.text.trim())
+    ).collect::<Vec<String>>().join("\n")
+  }
+  /// Renders the segments as a WebVTT subtitle file.
+  pub fn to_vtt(&self) -> String {
+    let mut vtt = "WEBVTT\n\n".to_string();
+    for seg in &self.segments {
+      vtt.push_str(&format!("{} --> {}\n{}\n\n",subtitle_timestamp(seg.start,'.'),subtitle_timestamp(seg.end,'.'),seg.text.trim()));
+    }
+    vtt
+  }
+  /// Words of `source` that don't show up, in order, in the transcription.
+  /// Running narration back through Whisper and diffing it against the text
+  /// it was generated from surfaces mispronounced or skipped words.
+  pub fn mismatches(&self, source:&str) -> Vec<String> {
+    let expected : Vec<String> = normalized_words(source);
+    let heard : Vec<String> = normalized_words(&self.text);
+    // Longest common subsequence over words, anything in `expected` outside it was not heard.
+    let mut heard_words = vec![false;expected.len()];
+    mark_common_words(&expected,&heard,&mut heard_words);
+    expected.into_iter().zip(heard_words).filter(|(_,heard)| !heard).map(|(word,_)| word).collect()
+  }
+}
+/// Marks the words of `expected` that are part of a longest common subsequence with `heard`.
+/// Hirschberg's split keeps this to two rows of memory however long the narration is.
+fn mark_common_words(expected:&[String], heard:&[String], marks:&mut [bool]) {
+  // Narrations mostly match, so peel off the agreeing ends before doing any real work.
+  let prefix = expected.iter().zip(heard).take_while(|(a,b)| a == b).count();
+  marks[..prefix].iter_mut().for_each(|m| *m = true);
+  let (expected,heard,marks) = (&expected[prefix..],&heard[prefix..],&mut marks[prefix..]);
+  let suffix = expected.iter().rev().zip(heard.iter().rev()).take_while(|(a,b)| a == b).count();
+  let expected = &expected[..expected.len() - suffix];
+  let heard = &heard[..heard.len() - suffix];
+  let (marks,suffix_marks) = marks.split_at_mut(expected.len());
+  suffix_marks.iter_mut().for_each(|m| *m = true);
+  if expected.is_empty() || heard.is_empty() {
+    return;
+  }
+  if expected.len() == 1 {
+    marks[0] = heard.contains(&expected[0]);
+    return;
+  }
+  let mid = expected.len() / 2;
+  let forward = lcs_lengths(expected[..mid].iter(),heard.iter());
+  let backward = lcs_lengths(expected[mid..].iter().rev(),heard.iter().rev());
+  let split = (0..=heard.len()).max_by_key(|&k| forward[k] + backward[heard.len() - k]).unwrap_or_default();
+  let (left,right) = marks.split_at_mut(mid);
+  mark_common_words(&expected[..mid],&heard[..split],left);
+  mark_common_words(&expected[mid..],&heard[split..],right);
+}
+/// Length of the longest common subsequence of `a` with every prefix of `b`.
+fn lcs_lengths<'a>(a:impl Iterator<Item=&'a String>, b:impl Iterator<Item=&'a String> + Clone) -> Vec<usize> {
+  let mut row = vec![0usize;b.clone().count()+1];
+  for x in a {
+    let mut diagonal = 0;
+    for (j,y) in b.clone().enumerate() {
+      let above = row[j+1];
+      row[j+1] = if x == y { diagonal + 1 } else { above.max(row[j]) };
+      diagonal = above;
+    }
+  }
+  row
+}
+fn subtitle_timestamp(seconds:f64, separator:char) -> String {
+  let millis = (seconds * 1000.).round() as u64;
+  format!("{:02}:{:02}:{:02}{}{:03}",millis/3_600_000,(millis/60_000)%60,(millis/1000)%60,separator,millis%1000)
+}
+fn normalized_words(text:&str) -> Vec<String> {
+  text.split_whitespace()
+    .map(|w| w.chars().filter(|c| c.is_alphanumeric()).collect::<String>().to_lowercase())
+    .filter(|w| !w.is_empty())
+    .collect()
+}
+
+/// Response of `/v1/moderations`, one result per input
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Default)]
+pub struct ModerationResponse{
+    pub results:Vec<ModerationOutcome>,
+}
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Default)]
+pub struct ModerationOutcome{
+    pub flagged:bool,
+    #[serde(default)]
+    pub categories:BTreeMap<String,bool>,
+}
+
+/// Response of `/v1/models`
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Default)]
+pub struct ModelsResponse{
+    pub data:Vec<ModelObject>,
+}
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct ModelObject{
+    pub id:String,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Default)]
@@ -313,4 +960,109 @@ Controls Accept header.
       ]
     }
   ]
-}*/
\ No newline at end of file
+}*/
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn transcription(text:&str, segments:&[(f64,f64,&str)]) -> TranscriptionResponse {
+    TranscriptionResponse{
+      text:text.to_string(),
+      segments:segments.iter().enumerate().map(|(i,(start,end,text))| TranscriptionSegment{
+        id:i as u32,
+        start:*start,
+        end:*end,
+        text:text.to_string(),
+      }).collect(),
+      ..Default::default()
+    }
+  }
+
+  fn png(width:u32, height:u32) -> Vec<u8> {
+    let mut bytes = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+    bytes.extend_from_slice(&width.to_be_bytes());
+    bytes.extend_from_slice(&height.to_be_bytes());
+    bytes
+  }
+
+  #[test]
+  fn dall_e_prompts_are_limited_per_model() {
+    let request = |model:&str, chars:usize| DallERequest{
+      model:model.to_string(),
+      prompt:"a".repeat(chars),
+      n:1,
+      size:"1024x1024".to_string(),
+      quality:None,
+      style:None,
+      response_format:"url".to_string(),
+    };
+    assert!(request("dall-e-2",1000).validate().is_ok());
+    assert!(request("dall-e-2",1001).validate().is_err());
+    assert!(request("dall-e-3",4000).validate().is_ok());
+    assert!(request("dall-e-3",4001).validate().is_err());
+  }
+
+  #[test]
+  fn refine_images_have_to_be_square_pngs() {
+    assert_eq!(png_dimensions(&png(1792,1024)),Some((1792,1024)));
+    assert!(check_refine_images(&png(1024,1024),None).is_ok());
+    assert!(check_refine_images(&png(1792,1024),None).is_err());
+    assert!(check_refine_images(b"GIF89a not a png at all",None).is_err());
+    assert!(check_refine_images(&png(512,512),Some(&png(512,512))).is_ok());
+    assert!(check_refine_images(&png(512,512),Some(&png(256,256))).is_err());
+  }
+
+  #[test]
+  fn attachments_go_with_the_field_they_were_put_in() {
+    let attachment = PayloadAttachment{
+      path:"media.hero".to_string(),
+      asset:AssetRef{ id:"abc".to_string(), mime:"image/png".to_string(), size:3 },
+    };
+    assert!(attachment.replaced_by("media.hero"));
+    assert!(attachment.replaced_by("media"));
+    assert!(attachment.replaced_by("media.hero.alt"));
+    assert!(!attachment.replaced_by("media.hero_alt"));
+    assert!(!attachment.replaced_by("med"));
+  }
+
+  #[test]
+  fn srt_numbers_cues_from_one_with_comma_millis() {
+    let resp = transcription("",&[(0.,1.5," Hello"),(1.5,62.25,"World ")]);
+    assert_eq!(resp.to_srt(),"1\n00:00:00,000 --> 00:00:01,500\nHello\n\n2\n00:00:01,500 --> 00:01:02,250\nWorld\n");
+  }
+
+  #[test]
+  fn vtt_has_header_and_dot_millis() {
+    let resp = transcription("",&[(3723.004,3724.,"Late")]);
+    assert_eq!(resp.to_vtt(),"WEBVTT\n\n01:02:03.004 --> 01:02:04.000\nLate\n\n");
+  }
+
+  #[test]
+  fn mismatches_lists_skipped_words_in_order() {
+    let resp = transcription("the brown fox jumped over dog",&[]);
+    assert_eq!(resp.mismatches("The quick, brown fox jumps over the lazy dog."),vec!["quick","jumps","the","lazy"]);
+  }
+
+  #[test]
+  fn mismatches_is_empty_for_a_faithful_narration() {
+    let resp = transcription("Hello, world! It's fine.",&[]);
+    assert!(resp.mismatches("hello world its fine").is_empty());
+  }
+
+  #[test]
+  fn mismatches_keeps_repeated_words_apart() {
+    let resp = transcription("no",&[]);
+    assert_eq!(resp.mismatches("no no no"),vec!["no","no"]);
+    assert_eq!(transcription("",&[]).mismatches("a b"),vec!["a","b"]);
+  }
+
+  #[test]
+  fn mismatches_handles_article_length_text() {
+    let source : Vec<String> = (0..4_000).map(|i| format!("w{}",i % 97)).collect();
+    let mut heard = source.clone();
+    heard.remove(2_345);
+    heard.remove(3);
+    let resp = transcription(&heard.join(" "),&[]);
+    assert_eq!(resp.mismatches(&source.join(" ")).len(),2);
+  }
+}
@@ -0,0 +1,145 @@
+use super::*;
+
+/// What a provider call consumed, as it's billed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Consumption{
+    Tokens{ prompt:u64, completion:u64 },
+    Images{ count:u32, size:String, quality:Option<String> },
+    /// Characters of text turned into speech
+    Characters(usize),
+    /// Seconds of audio transcribed
+    AudioSeconds(f64),
+}
+impl Consumption{
+  pub fn cost(&self, model:&str) -> Option<f64> {
+    match self {
+      Consumption::Tokens{ prompt, completion } => chat_cost(model,*prompt,*completion),
+      Consumption::Images{ count, size, quality } => image_cost(model,size,quality.as_deref(),*count),
+      Consumption::Characters(characters) if model.starts_with("tts") => speech_cost(model,*characters),
+      Consumption::Characters(characters) => Some(eleven_labs_cost(*characters)),
+      Consumption::AudioSeconds(seconds) => Some(transcription_cost(*seconds)),
+    }
+  }
+}
+
+/// Consumption summed per day, project and model.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct UsageEntry{
+    /// `YYYY-MM-DD`, local time
+    pub day:String,
+    pub project:String,
+    pub model:String,
+    pub prompt_tokens:u64,
+    pub completion_tokens:u64,
+    pub images:u64,
+    pub characters:u64,
+    #[serde(default)]
+    pub audio_seconds:f64,
+    /// USD, calls to models missing from the price table count as free
+    pub cost:f64,
+}
+
+/// Everything spent across sessions, kept in local storage.
+/// Loaded once and held in shared state, it's saved whenever usage is booked or a setting committed.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct UsageLedger{
+    /// What new usage is booked under
+    pub project:String,
+    /// USD the current project may spend per day
+    pub daily_cap:Option<f64>,
+    /// USD the current project may spend in total
+    pub project_cap:Option<f64>,
+    pub entries:Vec<UsageEntry>,
+}
+impl UsageLedger{
+  pub fn load() -> Self {
+    backend::load().unwrap_or_default()
+  }
+  pub fn save(&self) -> Result<(),String> {
+    backend::save(self)
+  }
+  pub fn record(&mut self, model:&str, consumption:&Consumption) {
+    let day = today();
+    let index = match self.entries.iter().position(|e| e.day == day && e.project == self.project && e.model == model) {
+      Some(index) => index,
+      None => {
+        self.entries.push(UsageEntry{
+          day,
+          project:self.project.clone(),
+          model:model.to_string(),
+          ..Default::default()
+        });
+        self.entries.len() - 1
+      },
+    };
+    let entry = &mut self.entries[index];
+    match consumption {
+      Consumption::Tokens{ prompt, completion } => {
+        entry.prompt_tokens += prompt;
+        entry.completion_tokens += completion;
+      },
+      Consumption::Images{ count, .. } => entry.images += *count as u64,
+      Consumption::Characters(characters) => entry.characters += *characters as u64,
+      Consumption::AudioSeconds(seconds) => entry.audio_seconds += seconds,
+    }
+    entry.cost += consumption.cost(model).unwrap_or_default();
+  }
+  /// USD the current project spent, today only when `day` is given.
+  pub fn spent(&self, day:Option<&str>) -> f64 {
+    self.entries.iter()
+      .filter(|e| e.project == self.project && day.is_none_or(|day| e.day == day))
+      .map(|e| e.cost)
+      .sum()
+  }
+  /// Why the current project can't spend any more, if it can't.
+  pub fn cap_exceeded(&self) -> Option<String> {
+    let today = self.spent(Some(&today()));
+    if let Some(cap) = self.daily_cap.filter(|cap| today >= *cap) {
+      return Some(format!("{} spent today, the daily cap is {}",format_usd(today),format_usd(cap)));
+    }
+    let total = self.spent(None);
+    if let Some(cap) = self.project_cap.filter(|cap| total >= *cap) {
+      return Some(format!("{} spent on the project, the cap is {}",format_usd(total),format_usd(cap)));
+    }
+    None
+  }
+}
+
+fn today() -> String {
+  chrono::Local::now().format("%Y-%m-%d").to_string()
+}
+
+/// In the browser the ledger lives in local storage.
+#[cfg(target_arch = "wasm32")]
+mod backend {
+  use super::UsageLedger;
+  use gloo::storage::{LocalStorage, Storage};
+
+  const KEY:&str = "craptent.usage";
+
+  pub fn load() -> Option<UsageLedger> {
+    LocalStorage::get(KEY).ok()
+  }
+
+  pub fn save(ledger:&UsageLedger) -> Result<(),String> {
+    LocalStorage::set(KEY,ledger).map_err(|e| e.to_string())
+  }
+}
+
+/// Natively it's written to `./usage.json`.
+#[cfg(not(target_arch = "wasm32"))]
+mod backend {
+  use super::UsageLedger;
+
+  const PATH:&str = "usage.json";
+
+  pub fn load() -> Option<UsageLedger> {
+    let json = std::fs::read(PATH).ok()?;
+    serde_json::from_slice(&json).ok()
+  }
+
+  pub fn save(ledger:&UsageLedger) -> Result<(),String> {
+    let json = serde_json::to_vec_pretty(ledger).map_err(|e| e.to_string())?;
+    std::fs::write(PATH,json).map_err(|e| e.to_string())
+  }
+}